@@ -4,16 +4,25 @@ use std::path::Path;
 use std::fs::File;
 use std::io::Write;
 
-type InstructionSpec = (&'static str, &'static [Arg], &'static str);
+// Name, operands, encoding, and the `Feature` a core needs to have the instruction
+type InstructionSpec = (&'static str, &'static [Arg], &'static str, Option<&'static str>);
 
 #[derive(Copy, Clone, Debug)]
 enum Arg {
     Register(char),
     RegisterPair(char),
+    UpperRegister(char),    // R16-R31
+    MultiplyRegister(char), // R16-R23
+    WordPair(char),         // R25:R24, X, Y or Z
+    Flag(char),             // a bit of SREG
+    Bit(char),              // a bit number within a byte
     Unsigned(char),
+    IoAddress(char), // an address in I/O space, given as anything `Into<IoAddress>`
     RelativeOffset(char),
     AbsoluteOffset(char),
     AbsoluteOffsetDoubles(char), // measured by 16-bit intervals
+    AddressByte(char, &'static str), // one byte of an address, picked by the named OffsetKind
+    ReducedData(char), // data address in the reduced core's scrambled 7-bit field
     ImplicitZ,
     
 }
@@ -27,10 +36,15 @@ impl Arg {
         match self { 
             &Arg::Register(c) => c,
             &Arg::RegisterPair(c) => c,
+            &Arg::UpperRegister(c) | &Arg::MultiplyRegister(c) | &Arg::WordPair(c) => c,
+            &Arg::Flag(c) | &Arg::Bit(c) => c,
             &Arg::Unsigned(c) => c,
+            &Arg::IoAddress(c) => c,
             &Arg::RelativeOffset(c) => c,
             &Arg::AbsoluteOffset(c) => c,
             &Arg::AbsoluteOffsetDoubles(c) => c,
+            &Arg::AddressByte(c, _) => c,
+            &Arg::ReducedData(c) => c,
             &Arg::ImplicitZ => 'z',
         }
     }
@@ -39,194 +53,414 @@ impl Arg {
         match self {
             &Arg::Register(_) => "Register".to_string(),
             &Arg::RegisterPair(_) => "RegisterPair".to_string(),
+            &Arg::UpperRegister(c) | &Arg::MultiplyRegister(c) | &Arg::WordPair(c) => format!("R{}", c),
+            &Arg::Flag(_) => "Flag".to_string(),
+            &Arg::Bit(_) => "Bit".to_string(),
             &Arg::Unsigned(_) => "u32".to_string(),
-            &Arg::RelativeOffset(_) => "Offset".to_string(),
-            &Arg::AbsoluteOffset(_) => "Offset".to_string(),
-            &Arg::AbsoluteOffsetDoubles(_) => "Offset".to_string(),
+            &Arg::IoAddress(_) => "I".to_string(),
+            &Arg::RelativeOffset(_) | &Arg::AbsoluteOffsetDoubles(_) => "P".to_string(),
+            &Arg::AbsoluteOffset(_) | &Arg::ReducedData(_) => "D".to_string(),
+            &Arg::AddressByte(..) if self.is_program() => "P".to_string(),
+            &Arg::AddressByte(..) => "D".to_string(),
             &Arg::ImplicitZ => "RegisterPair".to_string(),
         }
     }
+    
+    // How the decoder should read this argument back out of an instruction
+    fn operand_kind(&self) -> String {
+        match *self {
+            Arg::Register(c) | Arg::UpperRegister(c) | Arg::MultiplyRegister(c) => format!("OperandKind::Register(b'{}')", c),
+            Arg::RegisterPair(c) | Arg::WordPair(c) => format!("OperandKind::RegisterPair(b'{}')", c),
+            Arg::Unsigned(c) | Arg::IoAddress(c) | Arg::Flag(c) | Arg::Bit(c) => format!("OperandKind::Unsigned(b'{}')", c),
+            Arg::RelativeOffset(c) => format!("OperandKind::Relative(b'{}')", c),
+            Arg::AbsoluteOffset(c) => format!("OperandKind::Absolute(b'{}')", c),
+            Arg::AbsoluteOffsetDoubles(c) => format!("OperandKind::AbsoluteDoubles(b'{}')", c),
+            Arg::AddressByte(c, _) => format!("OperandKind::Unsigned(b'{}')", c),
+            Arg::ReducedData(c) => format!("OperandKind::ReducedData(b'{}')", c),
+            Arg::ImplicitZ => "OperandKind::ImplicitZ".to_string(),
+        }
+    }
+    
+    // How a textual front end should read this argument
+    fn operand_type(&self) -> &'static str {
+        match *self {
+            Arg::Register(_) | Arg::UpperRegister(_) | Arg::MultiplyRegister(_) => "OperandType::Register",
+            Arg::RegisterPair(_) | Arg::WordPair(_) => "OperandType::RegisterPair",
+            Arg::Unsigned(_) | Arg::IoAddress(_) | Arg::Flag(_) | Arg::Bit(_) => "OperandType::Unsigned",
+            Arg::RelativeOffset(_) => "OperandType::Relative",
+            Arg::AbsoluteOffset(_) | Arg::ReducedData(_) => "OperandType::Absolute",
+            Arg::AbsoluteOffsetDoubles(_) => "OperandType::AbsoluteDoubles",
+            Arg::AddressByte(..) => "OperandType::AddressByte",
+            Arg::ImplicitZ => "OperandType::ImplicitZ",
+        }
+    }
+    
+    // Pulls this argument out of `values[index]` in `call_form`. A register
+    // that a front end gave as a plain `Register` has its class checked by the
+    // method it is passed to.
+    fn value_getter(&self, index: usize, name: &str) -> String {
+        let class = |class: &str, getter: &str| format!(
+            "{}::try_from(values[{}].{}()).map_err(|constraint| AsmError::invalid_operand({:?}, b'{}', constraint))?",
+            class, index, getter, mnemonic(name), self.format_char());
+        match *self {
+            Arg::Register(_) => format!("values[{}].register()", index),
+            Arg::RegisterPair(_) | Arg::ImplicitZ => format!("values[{}].pair()", index),
+            Arg::UpperRegister(_) | Arg::MultiplyRegister(_) => format!("values[{}].register()", index),
+            Arg::WordPair(_) => format!("values[{}].pair()", index),
+            Arg::Flag(_) => class("Flag", "unsigned"),
+            Arg::Bit(_) => class("Bit", "unsigned"),
+            Arg::Unsigned(_) | Arg::IoAddress(_) => format!("values[{}].unsigned()", index),
+            _ if self.is_program() => format!("values[{}].program()", index),
+            _ => format!("values[{}].data()", index),
+        }
+    }
+    
+    // Whether the argument is a `ProgramAddress`, in words, rather than a `DataReference`, in bytes
+    fn is_program(&self) -> bool {
+        match *self {
+            Arg::RelativeOffset(_) | Arg::AbsoluteOffsetDoubles(_) => true,
+            Arg::AddressByte(_, kind) => kind.starts_with("Pm"),
+            _ => false,
+        }
+    }
+    
+    // The type parameter of a register class argument, which takes the class
+    // itself or a plain register that is checked when the instruction is emitted
+    fn class_parameter(&self) -> Option<String> {
+        let class = match *self {
+            Arg::UpperRegister(_) => "UpperRegister",
+            Arg::MultiplyRegister(_) => "MultiplyRegister",
+            Arg::WordPair(_) => "WordPair",
+            _ => return None,
+        };
+        Some(format!("R{}: IntoClass<{}>", self.format_char(), class))
+    }
+    
+    fn address_type(&self) -> &'static str {
+        if self.is_program() { "ProgramAddress" } else { "DataReference" }
+    }
+    
+    fn is_offset(&self) -> bool {
+        matches!(*self, Arg::RelativeOffset(_) | Arg::AbsoluteOffset(_) | Arg::AbsoluteOffsetDoubles(_) | Arg::AddressByte(..) | Arg::ReducedData(_))
+    }
 }
 
 const RD: Arg = Arg::Register('d');
 const RR: Arg = Arg::Register('r');
 const RD_PAIR: Arg = Arg::RegisterPair('d');
 const RR_PAIR: Arg = Arg::RegisterPair('r');
+const RD_UPPER: Arg = Arg::UpperRegister('d');
+const RR_UPPER: Arg = Arg::UpperRegister('r');
+const RD_MUL: Arg = Arg::MultiplyRegister('d');
+const RR_MUL: Arg = Arg::MultiplyRegister('r');
+const RD_WORD: Arg = Arg::WordPair('d');
 const K: Arg = Arg::Unsigned('K');
-const S: Arg = Arg::Unsigned('s');
-const B: Arg = Arg::Unsigned('b');
+const S: Arg = Arg::Flag('s');
+const B: Arg = Arg::Bit('b');
 const OFFSET: Arg = Arg::RelativeOffset('k');
 const ABSOLUTE_OFFSET: Arg = Arg::AbsoluteOffset('k');
 const ABSOLUTE_OFFSET_DOUBLES: Arg = Arg::AbsoluteOffsetDoubles('k');
-const A: Arg = Arg::Unsigned('A');
+const A: Arg = Arg::IoAddress('A');
+const LO8: Arg = Arg::AddressByte('K', "Lo8");
+const HI8: Arg = Arg::AddressByte('K', "Hi8");
+const PM_LO8: Arg = Arg::AddressByte('K', "PmLo8");
+const PM_HI8: Arg = Arg::AddressByte('K', "PmHi8");
+const REDUCED_DATA: Arg = Arg::ReducedData('k');
+
+// Instructions every core has
+const ANY: Option<&str> = None;
+const SRAM: Option<&str> = Some("Sram");
+const ADIW: Option<&str> = Some("Adiw");
+const LPM: Option<&str> = Some("Lpm");
+const MOVW: Option<&str> = Some("Movw");
+const MUL: Option<&str> = Some("Mul");
+const JMP_CALL: Option<&str> = Some("JmpCall");
+const ELPM: Option<&str> = Some("Elpm");
+const EIJMP: Option<&str> = Some("Eijmp");
+const SPM: Option<&str> = Some("Spm");
+const SPM_X: Option<&str> = Some("SpmX");
+const DES: Option<&str> = Some("Des");
+const RMW: Option<&str> = Some("Rmw");
+const REDUCED: Option<&str> = Some("Reduced");
 
-static INSTRUCTIONS: [InstructionSpec; 117] = [
-    ("adc", &[RD, RR], "0001 11rd dddd rrrr"),
-    ("add", &[RD, RR], "0000 11rd dddd rrrr"),
-    ("adiw", &[RD_PAIR, K], "1001 0110 KKdd KKKK"),
-    ("and", &[RD, RR], "0010 00rd dddd rrrr"),
-    ("andi", &[RD, K], "0111 KKKK dddd KKKK"),
-    ("asr", &[RD], "1001 010d dddd 0101"),
-    ("bclr", &[S], "1001 0100 1sss 1000"), // TODO: Tests?
-    ("bld", &[RD, B], "1111 100d dddd 0bbb"),
+static INSTRUCTIONS: [InstructionSpec; 122] = [
+    ("adc", &[RD, RR], "0001 11rd dddd rrrr", ANY),
+    ("add", &[RD, RR], "0000 11rd dddd rrrr", ANY),
+    ("adiw", &[RD_WORD, K], "1001 0110 KKdd KKKK", ADIW),
+    ("and", &[RD, RR], "0010 00rd dddd rrrr", ANY),
+    ("andi", &[RD_UPPER, K], "0111 KKKK dddd KKKK", ANY),
+    ("asr", &[RD], "1001 010d dddd 0101", ANY),
+    ("bclr", &[S], "1001 0100 1sss 1000", ANY), // TODO: Tests?
+    ("bld", &[RD, B], "1111 100d dddd 0bbb", ANY),
 
-    ("brbc", &[S, OFFSET], "1111 01kk kkkk ksss"),
-    ("brbs", &[S, OFFSET], "1111 00kk kkkk ksss"),
-    ("brcc", &[OFFSET], "1111 01kk kkkk k000"),
-    ("brcs", &[OFFSET], "1111 00kk kkkk k000"),
-    ("break_", &[], "1001 0101 1001 1000"),
-    ("breq", &[OFFSET], "1111 00kk kkkk k001"),
-    ("brge", &[OFFSET], "1111 01kk kkkk k100"),
-    ("brhc", &[OFFSET], "1111 01kk kkkk k101"),
-    ("brhs", &[OFFSET], "1111 00kk kkkk k101"),
-    ("brid", &[OFFSET], "1111 01kk kkkk k111"),
-    ("brie", &[OFFSET], "1111 00kk kkkk k111"),
-    ("brlo", &[OFFSET], "1111 00kk kkkk k000"),
-    ("brlt", &[OFFSET], "1111 00kk kkkk k100"),
-    ("brmi", &[OFFSET], "1111 00kk kkkk k010"),
-    ("brne", &[OFFSET], "1111 01kk kkkk k001"),
-    ("brpl", &[OFFSET], "1111 01kk kkkk k010"),
-    ("brsh", &[OFFSET], "1111 01kk kkkk k000"),
-    ("brtc", &[OFFSET], "1111 01kk kkkk k110"),
-    ("brts", &[OFFSET], "1111 00kk kkkk k110"),
-    ("brvc", &[OFFSET], "1111 01kk kkkk k011"),
-    ("brvs", &[OFFSET], "1111 00kk kkkk k011"),
+    ("brbc", &[S, OFFSET], "1111 01kk kkkk ksss", ANY),
+    ("brbs", &[S, OFFSET], "1111 00kk kkkk ksss", ANY),
+    ("brcc", &[OFFSET], "1111 01kk kkkk k000", ANY),
+    ("brcs", &[OFFSET], "1111 00kk kkkk k000", ANY),
+    ("break_", &[], "1001 0101 1001 1000", ANY),
+    ("breq", &[OFFSET], "1111 00kk kkkk k001", ANY),
+    ("brge", &[OFFSET], "1111 01kk kkkk k100", ANY),
+    ("brhc", &[OFFSET], "1111 01kk kkkk k101", ANY),
+    ("brhs", &[OFFSET], "1111 00kk kkkk k101", ANY),
+    ("brid", &[OFFSET], "1111 01kk kkkk k111", ANY),
+    ("brie", &[OFFSET], "1111 00kk kkkk k111", ANY),
+    ("brlo", &[OFFSET], "1111 00kk kkkk k000", ANY),
+    ("brlt", &[OFFSET], "1111 00kk kkkk k100", ANY),
+    ("brmi", &[OFFSET], "1111 00kk kkkk k010", ANY),
+    ("brne", &[OFFSET], "1111 01kk kkkk k001", ANY),
+    ("brpl", &[OFFSET], "1111 01kk kkkk k010", ANY),
+    ("brsh", &[OFFSET], "1111 01kk kkkk k000", ANY),
+    ("brtc", &[OFFSET], "1111 01kk kkkk k110", ANY),
+    ("brts", &[OFFSET], "1111 00kk kkkk k110", ANY),
+    ("brvc", &[OFFSET], "1111 01kk kkkk k011", ANY),
+    ("brvs", &[OFFSET], "1111 00kk kkkk k011", ANY),
 
     
-    ("bset", &[S], "1001 0100 0sss 1000"),
-    ("bst", &[RD, B], "1111 101d dddd 0bbb"),
-    ("call", &[ABSOLUTE_OFFSET_DOUBLES], "1001 010k kkkk 111k kkkk kkkk kkkk kkkk"),
-    ("cbi", &[A, B], "1001 1000 AAAA Abbb"),
-    ("cbr", &[RD, K], "0111 KKKK dddd KKKK"), // TODO: test this
-    ("clc", &[], "1001 0100 1000 1000"),
-    ("clh", &[], "1001 0100 1101 1000"),
-    ("cli", &[], "1001 0100 1111 1000"),
-    ("cln", &[], "1001 0100 1010 1000"),
-    ("clr", &[RD], "0010 01dd dddd dddd"), // EOR RD,RD
-    ("cls", &[], "1001 0100 1100 1000"),
-    ("clt", &[], "1001 0100 1110 1000"),
-    ("clv", &[], "1001 0100 1011 1000"),
-    ("clz", &[], "1001 0100 1001 1000"),
-    ("com", &[RD], "1001 010d dddd 0000"),
-    ("cp", &[RD, RR], "0001 01rd dddd rrrr"),
-    ("cpc", &[RD, RR], "0000 01rd dddd rrrr"),
-    ("cpi", &[RD, K], "0011 KKKK dddd KKKK"),
-    ("cpse", &[RD, RR], "0001 00rd dddd rrrr"),
-    ("dec", &[RD], "1001 010d dddd 1010"),
-    ("des", &[K], "1001 0100 KKKK 1011"),
-    ("eicall", &[], "1001 0101 0001 1001"),
-    ("eijmp", &[], "1001 0100 0001 1001"),
-    ("elpm_r0", &[], "1001 0101 1101 1000"),
-    ("eor", &[RD, RR], "0010 01rd dddd rrrr"),
-    ("fmul", &[RD, RR], "0000 0011 0ddd 1rrr"),
-    ("fmuls", &[RD, RR], "0000 0011 1ddd 0rrr"),
-    ("fmulsu", &[RD, RR], "0000 0011 1ddd 1rrr"),
-    ("icall", &[], "1001 0101 0000 1001"),
-    ("ijmp", &[], "1001 0100 0000 1001"),
-    ("in_", &[RD, A], "1011 0AAd dddd AAAA"),
-    ("inc", &[RD], "1001 010d dddd 0011"),
-    ("jmp", &[ABSOLUTE_OFFSET_DOUBLES], "1001 010k kkkk 110k kkkk kkkk kkkk kkkk"),
-    ("lac", &[Arg::ImplicitZ, RD], "1001 001d dddd 0110"),
-    ("las", &[Arg::ImplicitZ, RD], "1001 001d dddd 0101"),
-    ("lat", &[Arg::ImplicitZ, RD], "1001 001d dddd 0111"),
-    ("ldi", &[RD,K], "1110 KKKK dddd KKKK"),
-    ("lds_16", &[RD,ABSOLUTE_OFFSET], "1001 000d dddd 0000 kkkk kkkk kkkk kkkk"),
-    ("lds_7", &[RD,ABSOLUTE_OFFSET], "1010 0kkk dddd kkkk"),
-    ("lpm_r0", &[], "1001 0101 1100 1000"),
-    ("lsl", &[RD], "0000 11dd dddd dddd"),
-    ("lsr", &[RD], "1001 010d dddd 0110"),
-    ("mov", &[RD, RR], "0010 11rd dddd rrrr"),
-    ("movw", &[RD_PAIR,RR_PAIR], "0000 0001 dddd rrrr"),
-    ("mul", &[RD, RR], "1001 11rd dddd rrrr"),
-    ("muls", &[RD, RR], "0000 0010 dddd rrrr"),
-    ("mulsu", &[RD, RR], "0000 0011 0ddd 0rrr"),
-    ("neg", &[RD], "1001 010d dddd 0001"),
-    ("nop", &[], "0000 0000 0000 0000"),
-    ("or", &[RD, RR], "0010 10rd dddd rrrr"),
-    ("ori", &[RD, K], "0110 KKKK dddd KKKK"),
-    ("out", &[A, RR], "1011 1AAr rrrr AAAA"),
-    ("pop", &[RD], "1001 000d dddd 1111"),
-    ("push", &[RR], "1001 001r rrrr 1111"),
-    ("rcall", &[OFFSET], "1101 kkkk kkkk kkkk"),
-    ("ret", &[], "1001 0101 0000 1000"),
-    ("reti", &[], "1001 0101 0001 1000"),
-    ("rjmp", &[OFFSET], "1100 kkkk kkkk kkkk"),
-    ("rol", &[RD], "0001 11dd dddd dddd"),
-    ("ror", &[RD], "1001 010d dddd 0111"),
-    ("sbc", &[RD, RR], "0000 10rd dddd rrrr"),
-    ("sbci", &[RD, K], "0100 KKKK dddd KKKK"),
-    ("sbi", &[A, B], "1001 1010 AAAA Abbb"),
-    ("sbic", &[A, B], "1001 1001 AAAA Abbb"),
-    ("sbis", &[A, B], "1001 1011 AAAA Abbb"),
-    ("sbiw", &[RD_PAIR, K], "1001 0111 KKdd KKKK"),
-    ("sbr", &[RD, K], "0110 KKKK dddd KKKK"),
-    ("sbrc", &[RR, B], "1111 110r rrrr 0bbb"),
-    ("sbrs", &[RR, B], "1111 111r rrrr 0bbb"),
-    ("sec", &[], "1001 0100 0000 1000"),
-    ("seh", &[], "1001 0100 0101 1000"),
-    ("sei", &[], "1001 0100 0111 1000"),
-    ("sen", &[], "1001 0100 0010 1000"),
-    ("ser", &[RD], "1110 1111 dddd 1111"),
-    ("ses", &[], "1001 0100 0100 1000"),
-    ("set", &[], "1001 0100 0110 1000"),
-    ("sev", &[], "1001 0100 0011 1000"),
-    ("sez", &[], "1001 0100 0001 1000"),
-    ("sleep", &[], "1001 0101 1000 1000"),
-    ("spm", &[], "1001 0101 1110 1000"),
-    ("spm_z_plus", &[], "1001 0101 1111 1000"),
-    ("sts", &[ABSOLUTE_OFFSET, RD], "1001 001d dddd 0000 kkkk kkkk kkkk kkkk"),
-    ("sub", &[RD, RR], "0001 10rd dddd rrrr"),
-    ("subi", &[RD, K], "0101 KKKK dddd KKKK"),
-    ("swap", &[RD], "1001 010d dddd 0010"),
-    ("tst", &[RD], "0010 00dd dddd dddd"),
-    ("wdr", &[], "1001 0101 1010 1000"),
-    ("xch", &[Arg::ImplicitZ, RD], "1001 001d dddd 0100"),
+    ("bset", &[S], "1001 0100 0sss 1000", ANY),
+    ("bst", &[RD, B], "1111 101d dddd 0bbb", ANY),
+    ("call", &[ABSOLUTE_OFFSET_DOUBLES], "1001 010k kkkk 111k kkkk kkkk kkkk kkkk", JMP_CALL),
+    ("cbi", &[A, B], "1001 1000 AAAA Abbb", ANY),
+    ("cbr", &[RD_UPPER, K], "0111 KKKK dddd KKKK", ANY), // TODO: test this
+    ("clc", &[], "1001 0100 1000 1000", ANY),
+    ("clh", &[], "1001 0100 1101 1000", ANY),
+    ("cli", &[], "1001 0100 1111 1000", ANY),
+    ("cln", &[], "1001 0100 1010 1000", ANY),
+    ("clr", &[RD], "0010 01dd dddd dddd", ANY), // EOR RD,RD
+    ("cls", &[], "1001 0100 1100 1000", ANY),
+    ("clt", &[], "1001 0100 1110 1000", ANY),
+    ("clv", &[], "1001 0100 1011 1000", ANY),
+    ("clz", &[], "1001 0100 1001 1000", ANY),
+    ("com", &[RD], "1001 010d dddd 0000", ANY),
+    ("cp", &[RD, RR], "0001 01rd dddd rrrr", ANY),
+    ("cpc", &[RD, RR], "0000 01rd dddd rrrr", ANY),
+    ("cpi", &[RD_UPPER, K], "0011 KKKK dddd KKKK", ANY),
+    ("cpse", &[RD, RR], "0001 00rd dddd rrrr", ANY),
+    ("dec", &[RD], "1001 010d dddd 1010", ANY),
+    ("des", &[K], "1001 0100 KKKK 1011", DES),
+    ("eicall", &[], "1001 0101 0001 1001", EIJMP),
+    ("eijmp", &[], "1001 0100 0001 1001", EIJMP),
+    ("elpm_r0", &[], "1001 0101 1101 1000", ELPM),
+    ("eor", &[RD, RR], "0010 01rd dddd rrrr", ANY),
+    ("fmul", &[RD_MUL, RR_MUL], "0000 0011 0ddd 1rrr", MUL),
+    ("fmuls", &[RD_MUL, RR_MUL], "0000 0011 1ddd 0rrr", MUL),
+    ("fmulsu", &[RD_MUL, RR_MUL], "0000 0011 1ddd 1rrr", MUL),
+    ("icall", &[], "1001 0101 0000 1001", SRAM),
+    ("ijmp", &[], "1001 0100 0000 1001", SRAM),
+    ("in_", &[RD, A], "1011 0AAd dddd AAAA", ANY),
+    ("inc", &[RD], "1001 010d dddd 0011", ANY),
+    ("jmp", &[ABSOLUTE_OFFSET_DOUBLES], "1001 010k kkkk 110k kkkk kkkk kkkk kkkk", JMP_CALL),
+    ("lac", &[Arg::ImplicitZ, RD], "1001 001d dddd 0110", RMW),
+    ("las", &[Arg::ImplicitZ, RD], "1001 001d dddd 0101", RMW),
+    ("lat", &[Arg::ImplicitZ, RD], "1001 001d dddd 0111", RMW),
+    ("ldi", &[RD_UPPER, K], "1110 KKKK dddd KKKK", ANY),
+    ("ldi_lo8", &[RD_UPPER, LO8], "1110 KKKK dddd KKKK", ANY),
+    ("ldi_hi8", &[RD_UPPER, HI8], "1110 KKKK dddd KKKK", ANY),
+    ("ldi_pm_lo8", &[RD_UPPER, PM_LO8], "1110 KKKK dddd KKKK", ANY),
+    ("ldi_pm_hi8", &[RD_UPPER, PM_HI8], "1110 KKKK dddd KKKK", ANY),
+    ("lds_16", &[RD,ABSOLUTE_OFFSET], "1001 000d dddd 0000 kkkk kkkk kkkk kkkk", ADIW),
+    ("lds_7", &[RD,REDUCED_DATA], "1010 0kkk dddd kkkk", REDUCED),
+    ("lpm_r0", &[], "1001 0101 1100 1000", LPM),
+    ("lsl", &[RD], "0000 11dd dddd dddd", ANY),
+    ("lsr", &[RD], "1001 010d dddd 0110", ANY),
+    ("mov", &[RD, RR], "0010 11rd dddd rrrr", ANY),
+    ("movw", &[RD_PAIR,RR_PAIR], "0000 0001 dddd rrrr", MOVW),
+    ("mul", &[RD, RR], "1001 11rd dddd rrrr", MUL),
+    ("muls", &[RD_UPPER, RR_UPPER], "0000 0010 dddd rrrr", MUL),
+    ("mulsu", &[RD_MUL, RR_MUL], "0000 0011 0ddd 0rrr", MUL),
+    ("neg", &[RD], "1001 010d dddd 0001", ANY),
+    ("nop", &[], "0000 0000 0000 0000", ANY),
+    ("or", &[RD, RR], "0010 10rd dddd rrrr", ANY),
+    ("ori", &[RD_UPPER, K], "0110 KKKK dddd KKKK", ANY),
+    ("out", &[A, RR], "1011 1AAr rrrr AAAA", ANY),
+    ("pop", &[RD], "1001 000d dddd 1111", SRAM),
+    ("push", &[RR], "1001 001r rrrr 1111", SRAM),
+    ("rcall", &[OFFSET], "1101 kkkk kkkk kkkk", ANY),
+    ("ret", &[], "1001 0101 0000 1000", ANY),
+    ("reti", &[], "1001 0101 0001 1000", ANY),
+    ("rjmp", &[OFFSET], "1100 kkkk kkkk kkkk", ANY),
+    ("rol", &[RD], "0001 11dd dddd dddd", ANY),
+    ("ror", &[RD], "1001 010d dddd 0111", ANY),
+    ("sbc", &[RD, RR], "0000 10rd dddd rrrr", ANY),
+    ("sbci", &[RD_UPPER, K], "0100 KKKK dddd KKKK", ANY),
+    ("sbi", &[A, B], "1001 1010 AAAA Abbb", ANY),
+    ("sbic", &[A, B], "1001 1001 AAAA Abbb", ANY),
+    ("sbis", &[A, B], "1001 1011 AAAA Abbb", ANY),
+    ("sbiw", &[RD_WORD, K], "1001 0111 KKdd KKKK", ADIW),
+    ("sbr", &[RD_UPPER, K], "0110 KKKK dddd KKKK", ANY),
+    ("sbrc", &[RR, B], "1111 110r rrrr 0bbb", ANY),
+    ("sbrs", &[RR, B], "1111 111r rrrr 0bbb", ANY),
+    ("sec", &[], "1001 0100 0000 1000", ANY),
+    ("seh", &[], "1001 0100 0101 1000", ANY),
+    ("sei", &[], "1001 0100 0111 1000", ANY),
+    ("sen", &[], "1001 0100 0010 1000", ANY),
+    ("ser", &[RD_UPPER], "1110 1111 dddd 1111", ANY),
+    ("ses", &[], "1001 0100 0100 1000", ANY),
+    ("set", &[], "1001 0100 0110 1000", ANY),
+    ("sev", &[], "1001 0100 0011 1000", ANY),
+    ("sez", &[], "1001 0100 0001 1000", ANY),
+    ("sleep", &[], "1001 0101 1000 1000", ANY),
+    ("spm", &[], "1001 0101 1110 1000", SPM),
+    ("spm_z_plus", &[], "1001 0101 1111 1000", SPM_X),
+    ("sts_16", &[ABSOLUTE_OFFSET, RD], "1001 001d dddd 0000 kkkk kkkk kkkk kkkk", ADIW),
+    ("sts_7", &[REDUCED_DATA, RD], "1010 1kkk dddd kkkk", REDUCED),
+    ("sub", &[RD, RR], "0001 10rd dddd rrrr", ANY),
+    ("subi", &[RD_UPPER, K], "0101 KKKK dddd KKKK", ANY),
+    ("swap", &[RD], "1001 010d dddd 0010", ANY),
+    ("tst", &[RD], "0010 00dd dddd dddd", ANY),
+    ("wdr", &[], "1001 0101 1010 1000", ANY),
+    ("xch", &[Arg::ImplicitZ, RD], "1001 001d dddd 0100", RMW),
 ];
 
+// Instructions that are another one in the table with an operand repeated or
+// fixed by the name, which the decoder reports as that other one, as
+// avr-objdump does: `clr r1` comes out as `eor r1, r1` and `ser r16` as
+// `ldi r16, 0xFF`. The flag and condition names of `bset`, `bclr`, `brbs`
+// and `brbc`, such as `sec` and `breq`, are not among them, since avr-objdump
+// prints those.
+static DECODE_ALIASES: [&str; 13] = [
+    "brlo", // brcs
+    "brsh", // brcc
+    "cbr", // andi
+    "clr", // eor
+    "lsl", // add
+    "rol", // adc
+    "sbr", // ori
+    "ser", // ldi
+    "tst", // and
+    "ldi_lo8", // ldi
+    "ldi_hi8", // ldi
+    "ldi_pm_lo8", // ldi
+    "ldi_pm_hi8", // ldi
+];
+
+// The name the datasheet uses for an instruction, without the suffix that
+// tells apart different forms of it or avoids a Rust keyword
+fn mnemonic(name: &str) -> &str {
+    name.split('_').next().unwrap()
+}
+
 fn main() {
     let mut lines: Vec<String> = Vec::new();
     
     lines.push("impl Assembler {".to_string());
     
-    for &(name, args, template) in INSTRUCTIONS.iter() {
+    for &(name, args, template, feature) in INSTRUCTIONS.iter() {
         let arg_strs: Vec<String> = args.iter().map(
             |arg| format!("{}: {}", arg.name(), arg.type_str())
         ).collect();
     
-        let arg_intos: Vec<_> = args.iter().map(
-            |arg| format!("({}.into(), b'{}')", arg.name(), arg.format_char())
+        let arg_intos: Vec<_> = args.iter().filter_map(
+            |arg| match *arg {
+                Arg::ImplicitZ => None,
+                Arg::RelativeOffset(_) => Some(format!("(Arg::Offset({}::offset({}.into()), OffsetKind::Relative), b'{}')", arg.address_type(), arg.name(), arg.format_char())),
+                Arg::AbsoluteOffset(_) => Some(format!("(Arg::Offset({}::offset({}.into()), OffsetKind::Absolute), b'{}')", arg.address_type(), arg.name(), arg.format_char())),
+                Arg::AbsoluteOffsetDoubles(_) => Some(format!("(Arg::Offset({}::offset({}.into()), OffsetKind::AbsoluteDoubles), b'{}')", arg.address_type(), arg.name(), arg.format_char())),
+                Arg::AddressByte(_, kind) => Some(format!("(Arg::Offset({}::offset({}.into()), OffsetKind::{}), b'{}')", arg.address_type(), arg.name(), kind, arg.format_char())),
+                Arg::ReducedData(_) => Some(format!("(Arg::Offset({}::offset({}.into()), OffsetKind::ReducedData), b'{}')", arg.address_type(), arg.name(), arg.format_char())),
+                Arg::IoAddress(_) => Some(format!("(Arg::U32({}.into().0), b'{}')", arg.name(), arg.format_char())),
+                _ => Some(format!("({}.into(), b'{}')", arg.name(), arg.format_char())),
+            }
         ).collect();
+        
+        let arg_names: Vec<_> = args.iter().map(|arg| arg.name()).collect();
     
-        lines.push(format!("    pub fn {}(&mut self, {}) {{", name, arg_strs.join(", ")));
+        let mut parameters: Vec<String> = args.iter().filter_map(|arg| arg.class_parameter()).collect();
+        if args.iter().any(|arg| arg.is_program()) {
+            parameters.push("P: Into<ProgramAddress>".to_string());
+        } else if args.iter().any(|arg| arg.is_offset()) {
+            parameters.push("D: Into<DataReference>".to_string());
+        } else if args.iter().any(|arg| matches!(*arg, Arg::IoAddress(_))) {
+            parameters.push("I: Into<IoAddress>".to_string());
+        }
+        let generics = if parameters.is_empty() { String::new() } else { format!("<{}>", parameters.join(", ")) };
+        
+        lines.push(format!("    pub fn {}{}(&mut self, {}) {{", name, generics, arg_strs.join(", ")));
+        lines.push(format!("        self.try_{}({}).unwrap_or_else(|err| panic!(\"{{}}\", err))", name, arg_names.join(", ")));
+        lines.push("    }".to_string());
+        lines.push("".to_string());
+        
+        lines.push(format!("    pub fn try_{}{}(&mut self, {}) -> Result<(), AsmError> {{", name, generics, arg_strs.join(", ")));
+        if let Some(feature) = feature {
+            lines.push(format!("        self.require({:?}, Feature::{})?;", mnemonic(name), feature));
+        }
         for arg in args.iter() {
-            match *arg {
-                Arg::ImplicitZ => {
-                    lines.push("        assert!(z == Z, \"Z (R30,30) is the only accepted argument\");".to_string());
-                }
-                Arg::RelativeOffset(_) => {
-                    lines.push(format!("        let {} = self.resolve_relative_offset({});", arg.name(), arg.name()));
-                }
-                Arg::AbsoluteOffset(_) => {
-                    lines.push(format!("        let {} = self.resolve_absolute_offset({});", arg.name(), arg.name()));
-                }
-                Arg::AbsoluteOffsetDoubles(_) => {
-                    lines.push(format!("        let {} = self.resolve_absolute_offset_doubles({});", arg.name(), arg.name()));
-                }
-                _ => {}
+            if arg.class_parameter().is_some() {
+                lines.push(format!("        let {} = {}.into_class().map_err(|constraint| AsmError::invalid_operand({:?}, b'{}', constraint))?;",
+                    arg.name(), arg.name(), mnemonic(name), arg.format_char()));
+            }
+            if let Arg::ImplicitZ = *arg {
+                lines.push("        if z != Z {".to_string());
+                lines.push(format!("            return Err(AsmError::invalid_operand({:?}, b'z', Constraint::OnlyZ));", mnemonic(name)));
+                lines.push("        }".to_string());
             }
         }
         
-        lines.push(format!("        self.encode(&[{}][..], b{:?})", arg_intos.join(", "), template));
+        lines.push(format!("        self.encode({:?}, &[{}][..], b{:?})", mnemonic(name), arg_intos.join(", "), template));
         lines.push("    }".to_string());
+        lines.push("".to_string());
     }
     
     lines.push("}".to_string());
     lines.push("".to_string());
     
-    let text = lines.join("\n");
-    
     let out_dir = env::var("OUT_DIR").expect("OUT_DIR environment variable not set");
-    let dest_path = Path::new(&out_dir).join("ops.rs");
+    write_lines(&Path::new(&out_dir).join("ops.rs"), &lines);
+    write_lines(&Path::new(&out_dir).join("decode_table.rs"), &decode_table());
+    write_lines(&Path::new(&out_dir).join("source_forms.rs"), &source_forms());
+}
+
+fn decode_table() -> Vec<String> {
+    let forms: Vec<&InstructionSpec> = INSTRUCTIONS.iter()
+        .filter(|&&(name, _, _, _)| !DECODE_ALIASES.contains(&name))
+        .collect();
+    
+    let mut lines: Vec<String> = Vec::new();
+    lines.push(format!("static INSTRUCTION_FORMS: [InstructionForm; {}] = [", forms.len()));
+    for &&(name, args, template, feature) in forms.iter() {
+        let mut operands: Vec<String> = args.iter().map(|arg| arg.operand_kind()).collect();
+        if name == "spm_z_plus" {
+            operands.push("OperandKind::Pointer(Z, Direction::PostIncrement)".to_string());
+        }
+        let feature = feature.map_or("None".to_string(), |feature| format!("Some(Feature::{})", feature));
+        lines.push(format!("    InstructionForm {{ mnemonic: {:?}, operands: &[{}], template: b{:?}, feature: {} }},", mnemonic(name), operands.join(", "), template, feature));
+    }
+    lines.push("];".to_string());
+    lines.push("".to_string());
+    lines
+}
+
+// The operands of every method, and a way to call it by name, for the textual front ends
+fn source_forms() -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    lines.push(format!("static SOURCE_FORMS: [SourceForm; {}] = [", INSTRUCTIONS.len()));
+    for &(name, args, template, _) in INSTRUCTIONS.iter() {
+        let operands: Vec<&str> = args.iter().map(|arg| arg.operand_type()).collect();
+        let bytes = template.chars().filter(|&c| c != ' ').count() / 8;
+        let fields: String = args.iter().map(|arg| arg.format_char()).collect();
+        lines.push(format!("    SourceForm {{ name: {:?}, operands: &[{}], fields: b{:?}, bytes: {} }},", name, operands.join(", "), fields, bytes));
+    }
+    lines.push("];".to_string());
+    lines.push("".to_string());
     
-    File::create(dest_path).and_then(|mut f| f.write_all(&text.as_bytes())).expect("writing ops.rs failed");
+    lines.push("impl Assembler {".to_string());
+    lines.push("    // Calls the method for `name` with operands already checked against its form".to_string());
+    lines.push("    fn call_form(&mut self, name: &str, values: &[Value]) -> Result<(), AsmError> {".to_string());
+    lines.push("        match name {".to_string());
+    for &(name, args, _, _) in INSTRUCTIONS.iter() {
+        let values: Vec<String> = args.iter().enumerate()
+            .map(|(index, arg)| arg.value_getter(index, name))
+            .collect();
+        lines.push(format!("            {:?} => self.try_{}({}),", name, name, values.join(", ")));
+    }
+    lines.push("            _ => panic!(\"no instruction form named {}\", name),".to_string());
+    lines.push("        }".to_string());
+    lines.push("    }".to_string());
+    lines.push("}".to_string());
+    lines.push("".to_string());
+    lines
+}
+
+fn write_lines(path: &Path, lines: &[String]) {
+    let text = lines.join("\n");
+    File::create(path).and_then(|mut f| f.write_all(text.as_bytes())).expect("writing generated code failed");
 }
\ No newline at end of file
@@ -0,0 +1,212 @@
+// The `avr_asm!` macro, which assembles AVR code while the crate using it is
+// compiled. The tokens are written out as GNU avr-as source, one statement per
+// line, and given to `Assembler::assemble_gnu`. Each token's line and column
+// are kept, so that an error can be reported at the token it points to.
+
+extern crate proc_macro;
+extern crate rassembler_avr;
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+use rassembler_avr::{AsmError, Assembler, Core, MemoryImage};
+
+/// Assembles AVR instructions at compile time into a `[u8; N]` array expression.
+///
+/// Statements are separated by `;`, and take the same operands as the
+/// `Assembler` methods, written as in avr-as: `ldi r16, 0xff`,
+/// `st X+, r0`, `ldd r1, Y+3`, `rjmp loop`. A statement may start with labels
+/// such as `loop:`, and the avr-as directives `.byte`, `.word` and `.align`
+/// may be used. Integers are read as in Rust, so `010` is ten.
+///
+/// The code is assembled at address 0 for the avr5 core of the ATmega328P.
+/// Attributes at the start of the macro change these: `#[origin = 0x7e00]`,
+/// and `#[core = avrxmega3]` with any name avr-gcc uses. Instructions the
+/// core lacks are errors. Every label must be defined in the macro. Errors
+/// are reported at the offending token.
+#[proc_macro]
+pub fn avr_asm(input: TokenStream) -> TokenStream {
+    match assemble(input) {
+        Ok(bytes) => {
+            let elements = bytes.iter().enumerate().flat_map(|(index, &byte)| {
+                let mut element = vec![TokenTree::Literal(Literal::u8_suffixed(byte))];
+                if index + 1 < bytes.len() {
+                    element.push(TokenTree::Punct(Punct::new(',', Spacing::Alone)));
+                }
+                element
+            });
+            TokenTree::Group(Group::new(Delimiter::Bracket, elements.collect())).into()
+        }
+        Err((span, message)) => compile_error(span, &message),
+    }
+}
+
+// `compile_error!("message")`, pointing at `span`
+fn compile_error(span: Span, message: &str) -> TokenStream {
+    let mut literal = Literal::string(message);
+    literal.set_span(span);
+    let mut arguments = Group::new(Delimiter::Parenthesis, TokenTree::Literal(literal).into());
+    arguments.set_span(span);
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+    vec![
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(bang),
+        TokenTree::Group(arguments),
+    ].into_iter().collect()
+}
+
+// The source given to the assembler, and where each token in it came from
+struct Source {
+    text: String,
+    line: usize,
+    column: usize,
+    // Line, column and text of each token, in order
+    spans: Vec<(usize, usize, String, Span)>,
+    // Whether the last token was joined to the next, as in `<<`
+    joint: bool,
+}
+
+impl Source {
+    fn new() -> Source {
+        Source { text: String::new(), line: 1, column: 1, spans: Vec::new(), joint: false }
+    }
+
+    fn push(&mut self, text: &str, span: Span) {
+        // A `.` is kept with the directive name after it
+        if self.column > 1 && !self.joint && !self.text.ends_with('.') {
+            self.text.push(' ');
+            self.column += 1;
+        }
+        self.joint = false;
+        self.spans.push((self.line, self.column, text.to_string(), span));
+        self.text.push_str(text);
+        self.column += text.chars().count();
+    }
+
+    fn end_line(&mut self) {
+        self.text.push('\n');
+        self.line += 1;
+        self.column = 1;
+    }
+
+    // The token at or before a line and column given in an error
+    fn span(&self, line: usize, column: usize) -> Span {
+        self.spans.iter().rev()
+            .find(|&&(token_line, token_column, _, _)| token_line == line && token_column <= column)
+            .or_else(|| self.spans.iter().rev().find(|&&(token_line, _, _, _)| token_line <= line))
+            .map_or_else(Span::call_site, |&(_, _, _, span)| span)
+    }
+
+    // The first use of a name
+    fn name_span(&self, name: &str) -> Span {
+        self.spans.iter()
+            .find(|(_, _, text, _)| text == name)
+            .map_or_else(Span::call_site, |&(_, _, _, span)| span)
+    }
+
+    fn tokens(&mut self, input: TokenStream) -> Result<(), (Span, String)> {
+        for token in input {
+            match token {
+                TokenTree::Punct(ref punct) if punct.as_char() == ';' => self.end_line(),
+                TokenTree::Punct(ref punct) => {
+                    self.push(&punct.as_char().to_string(), punct.span());
+                    self.joint = punct.spacing() == Spacing::Joint;
+                }
+                TokenTree::Ident(ref ident) => self.push(&ident.to_string(), ident.span()),
+                TokenTree::Literal(ref literal) => {
+                    let text = literal_text(literal)?;
+                    self.push(&text, literal.span());
+                }
+                TokenTree::Group(ref group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => ("", ""),
+                        Delimiter::Brace => return Err((group.span(), "braces are not allowed in assembly".to_string())),
+                    };
+                    self.push(open, group.span_open());
+                    self.tokens(group.stream())?;
+                    self.push(close, group.span_close());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A literal as avr-as reads it. Integers are converted to decimal, since a
+// leading 0 means octal to avr-as but not to Rust.
+fn literal_text(literal: &Literal) -> Result<String, (Span, String)> {
+    let text = literal.to_string();
+    if !text.starts_with(|c: char| c.is_ascii_digit()) {
+        return Ok(text);
+    }
+    // `1b` and `1f` refer to numeric local labels
+    if text.len() >= 2 && (text.ends_with('b') || text.ends_with('f')) && text[..text.len() - 1].bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(text);
+    }
+    let digits = text.replace('_', "");
+    let (digits, radix) = if let Some(hex) = digits.strip_prefix("0x") {
+        (hex.to_string(), 16)
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        (binary.to_string(), 2)
+    } else if let Some(octal) = digits.strip_prefix("0o") {
+        (octal.to_string(), 8)
+    } else {
+        (digits, 10)
+    };
+    match i64::from_str_radix(&digits, radix) {
+        Ok(value) => Ok(value.to_string()),
+        Err(_) => Err((literal.span(), format!("`{}` is not an integer", text))),
+    }
+}
+
+// What the attributes at the start of the input ask for
+struct Options {
+    origin: u32,
+    core: Core,
+}
+
+// Reads `#[origin = ...]` and `#[core = ...]` attributes from the start of the input
+fn options(input: TokenStream) -> Result<(Options, TokenStream), (Span, String)> {
+    let mut options = Options { origin: 0, core: Core::Avr5 };
+    let mut tokens = input.into_iter().peekable();
+    while let Some(TokenTree::Punct(hash)) = tokens.peek().cloned() {
+        if hash.as_char() != '#' {
+            break;
+        }
+        tokens.next();
+        let group = match tokens.next() {
+            Some(TokenTree::Group(ref group)) if group.delimiter() == Delimiter::Bracket => group.clone(),
+            _ => return Err((hash.span(), "expected an attribute such as `#[origin = 0x7e00]`".to_string())),
+        };
+        let attribute: Vec<TokenTree> = group.stream().into_iter().collect();
+        match &attribute[..] {
+            [TokenTree::Ident(name), TokenTree::Punct(equals), TokenTree::Literal(value)] if name.to_string() == "origin" && equals.as_char() == '=' => {
+                options.origin = literal_text(value)?.parse().map_err(|_| (value.span(), "expected an address".to_string()))?;
+            }
+            [TokenTree::Ident(name), TokenTree::Punct(equals), TokenTree::Ident(value)] if name.to_string() == "core" && equals.as_char() == '=' => {
+                options.core = Core::from_name(&value.to_string())
+                    .ok_or_else(|| (value.span(), format!("unknown core `{}`", value)))?;
+            }
+            _ => return Err((group.span(), "expected `#[origin = address]` or `#[core = name]`".to_string())),
+        }
+    }
+    Ok((options, tokens.collect()))
+}
+
+fn assemble(input: TokenStream) -> Result<Vec<u8>, (Span, String)> {
+    let (options, input) = options(input)?;
+    let mut source = Source::new();
+    source.tokens(input)?;
+
+    let mut assembler = Assembler::with_image(MemoryImage::new(), options.origin, options.core);
+    assembler.assemble_gnu("avr_asm!", &source.text)
+        .map_err(|err| (source.span(err.line, err.column), err.message))?;
+    assembler.finish().map_err(|err| {
+        let span = match err {
+            AsmError::UnboundLabel { name: Some(ref name), .. } => source.name_span(name),
+            _ => Span::call_site(),
+        };
+        (span, err.to_string())
+    })
+}
@@ -0,0 +1,22 @@
+extern crate rassembler_avr_macros;
+
+use rassembler_avr_macros::avr_asm;
+
+#[test]
+fn assembles_to_a_byte_array() {
+    const BLINK: [u8; 10] = avr_asm!(
+        ldi r16, 0xff;
+        out 0x04, r16;
+        ldi r17, 010;
+    loop:
+        sbi 0x03, 5;
+        rjmp loop;
+    );
+    assert_eq!(BLINK, [0x0f, 0xef, 0x04, 0xb9, 0x1a, 0xe0, 0x1d, 0x9a, 0xfe, 0xcf]);
+}
+
+#[test]
+fn attributes_pick_the_core() {
+    let load = avr_asm!(#[core = avrtiny] lds r16, 0x40);
+    assert_eq!(load, [0x00, 0xa1]);
+}
@@ -0,0 +1,222 @@
+use std::fmt;
+
+/// An AVR architecture, as named by avr-gcc's `-mmcu=avr5` and similar options.
+///
+/// The architecture decides which instructions exist. Most of the differences
+/// follow from flash size: `jmp` and `call` need more than 8 KiB, `elpm` more
+/// than 64 KiB, and `eijmp` and `eicall` more than 128 KiB.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Core {
+    /// Classic tinyAVR without SRAM, such as the ATtiny11. Only assembly is supported.
+    Avr1,
+    /// Classic devices with up to 8 KiB of flash, such as the AT90S8515.
+    Avr2,
+    /// Classic tinyAVR with `movw` and `lpm Rd, Z`, such as the ATtiny85 and ATtiny13A.
+    Avr25,
+    /// Devices with 16 KiB to 64 KiB of flash and no multiplier, such as the AT43USB355.
+    Avr3,
+    /// The ATmega103: 128 KiB of flash and no multiplier.
+    Avr31,
+    /// USB tinyAVR with 16 KiB of flash, such as the ATtiny167.
+    Avr35,
+    /// megaAVR with up to 8 KiB of flash, such as the ATmega8.
+    Avr4,
+    /// megaAVR with 16 KiB to 64 KiB of flash, such as the ATmega328P and ATmega32U4.
+    Avr5,
+    /// megaAVR with 128 KiB of flash, such as the ATmega1284P.
+    Avr51,
+    /// megaAVR with more than 128 KiB of flash, such as the ATmega2560.
+    Avr6,
+    /// The reduced core (AVRrc) of the ATtiny4/5/9/10/20/40.
+    AvrTiny,
+    /// XMEGA with up to 64 KiB of flash.
+    Xmega2,
+    /// The AVRxt core of tinyAVR 0/1/2-series and megaAVR 0-series parts, such as
+    /// the ATtiny1614, whose flash is mapped into data space.
+    Xmega3,
+    /// XMEGA with up to 128 KiB of flash.
+    Xmega4,
+    /// XMEGA with up to 128 KiB of flash and more than 64 KiB of RAM.
+    Xmega5,
+    /// XMEGA with more than 128 KiB of flash.
+    Xmega6,
+    /// XMEGA with more than 128 KiB of flash and more than 64 KiB of RAM.
+    Xmega7,
+    /// The AVRxt core of AVR Dx parts with more than 64 KiB of flash, such as
+    /// the AVR128DA: `elpm` as on `Xmega4`, but no `des` and none of the
+    /// read-modify-write instructions. avr-gcc builds for these parts as
+    /// avrxmega4, and their ELF files say so.
+    AvrXt,
+}
+
+/// A group of instructions that only some cores have.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Feature {
+    /// `ld` and `st` through X or Y or with an increment, `push`, `pop`, `ijmp` and `icall`.
+    Sram,
+    /// `adiw`, `sbiw`, `ldd`, `std` and the two-word `lds` and `sts`.
+    Adiw,
+    /// `lpm` into R0.
+    Lpm,
+    /// `lpm` into any register, through Z or Z+.
+    LpmX,
+    Movw,
+    /// `mul`, `muls`, `mulsu`, `fmul`, `fmuls` and `fmulsu`.
+    Mul,
+    /// `jmp` and `call`.
+    JmpCall,
+    /// `elpm` into R0.
+    Elpm,
+    /// `elpm` into any register, through Z or Z+.
+    ElpmX,
+    /// `eijmp` and `eicall`.
+    Eijmp,
+    Spm,
+    /// `spm Z+`.
+    SpmX,
+    Des,
+    /// The read-modify-write instructions `xch`, `las`, `lac` and `lat`.
+    Rmw,
+    /// The one-word `lds` and `sts` of the reduced core.
+    Reduced,
+}
+
+impl Core {
+    /// Every core, from the smallest.
+    pub const ALL: [Core; 18] = [
+        Core::Avr1, Core::Avr2, Core::Avr25, Core::Avr3, Core::Avr31, Core::Avr35, Core::Avr4, Core::Avr5,
+        Core::Avr51, Core::Avr6, Core::AvrTiny, Core::Xmega2, Core::Xmega3, Core::Xmega4, Core::Xmega5,
+        Core::Xmega6, Core::Xmega7, Core::AvrXt,
+    ];
+
+    /// The name avr-gcc uses, such as `avr5` or `avrxmega3`, or `avrxt` for
+    /// `AvrXt`, which avr-gcc does not name.
+    pub fn name(self) -> &'static str {
+        match self {
+            Core::Avr1 => "avr1",
+            Core::Avr2 => "avr2",
+            Core::Avr25 => "avr25",
+            Core::Avr3 => "avr3",
+            Core::Avr31 => "avr31",
+            Core::Avr35 => "avr35",
+            Core::Avr4 => "avr4",
+            Core::Avr5 => "avr5",
+            Core::Avr51 => "avr51",
+            Core::Avr6 => "avr6",
+            Core::AvrTiny => "avrtiny",
+            Core::Xmega2 => "avrxmega2",
+            Core::Xmega3 => "avrxmega3",
+            Core::Xmega4 => "avrxmega4",
+            Core::Xmega5 => "avrxmega5",
+            Core::Xmega6 => "avrxmega6",
+            Core::Xmega7 => "avrxmega7",
+            Core::AvrXt => "avrxt",
+        }
+    }
+
+    /// Looks up a core by the name avr-gcc uses.
+    pub fn from_name(name: &str) -> Option<Core> {
+        Core::ALL.iter().cloned().find(|core| core.name() == name)
+    }
+
+    /// The architecture number that the `e_flags` field of an ELF file holds.
+    pub fn elf_flags(self) -> u32 {
+        match self {
+            Core::Avr1 => 1,
+            Core::Avr2 => 2,
+            Core::Avr25 => 25,
+            Core::Avr3 => 3,
+            Core::Avr31 => 31,
+            Core::Avr35 => 35,
+            Core::Avr4 => 4,
+            Core::Avr5 => 5,
+            Core::Avr51 => 51,
+            Core::Avr6 => 6,
+            Core::AvrTiny => 100,
+            Core::Xmega2 => 102,
+            Core::Xmega3 => 103,
+            Core::Xmega4 | Core::AvrXt => 104,
+            Core::Xmega5 => 105,
+            Core::Xmega6 => 106,
+            Core::Xmega7 => 107,
+        }
+    }
+
+    /// The lowest register the core has: R16 on the reduced core, which only has R16 to R31.
+    pub fn first_register(self) -> u32 {
+        if self == Core::AvrTiny { 16 } else { 0 }
+    }
+
+    /// Where flash appears in data space on cores that read it with `ld`
+    /// rather than `lpm`. `lo8` and `hi8` of a label, which is in flash, give
+    /// its data address on these cores.
+    pub fn flash_in_data_space(self) -> Option<u32> {
+        if self == Core::AvrTiny { Some(0x4000) } else { None }
+    }
+
+    /// Where the 64 bytes of I/O space start in data space: after the registers
+    /// on classic cores, and at 0 on XMEGA cores and the reduced core.
+    pub fn io_in_data_space(self) -> u32 {
+        if self.is_xmega() || self == Core::AvrTiny { 0 } else { 0x20 }
+    }
+
+    fn is_xmega(self) -> bool {
+        matches!(self, Core::Xmega2 | Core::Xmega3 | Core::Xmega4 | Core::Xmega5 | Core::Xmega6 | Core::Xmega7
+            | Core::AvrXt)
+    }
+
+    /// Whether the core has the instructions of `feature`.
+    ///
+    /// `des` and the read-modify-write instructions are taken to be on every
+    /// XMEGA core but the AVRxt ones, `Xmega3` and `AvrXt`, although some
+    /// XMEGA parts lack the latter.
+    pub fn has(self, feature: Feature) -> bool {
+        use self::Core::*;
+        match feature {
+            Feature::Sram => self != Avr1,
+            Feature::Adiw => self != Avr1 && self != AvrTiny,
+            Feature::Lpm => self != AvrTiny,
+            Feature::LpmX | Feature::Movw | Feature::Spm => {
+                matches!(self, Avr25 | Avr35 | Avr4 | Avr5 | Avr51 | Avr6) || self.is_xmega()
+            }
+            Feature::Mul => matches!(self, Avr4 | Avr5 | Avr51 | Avr6) || self.is_xmega(),
+            Feature::JmpCall => matches!(self, Avr3 | Avr31 | Avr35 | Avr5 | Avr51 | Avr6) || self.is_xmega(),
+            Feature::Elpm => matches!(self, Avr31 | Avr51 | Avr6 | Xmega4 | Xmega5 | Xmega6 | Xmega7 | AvrXt),
+            Feature::ElpmX => matches!(self, Avr51 | Avr6 | Xmega4 | Xmega5 | Xmega6 | Xmega7 | AvrXt),
+            Feature::Eijmp => matches!(self, Avr6 | Xmega6 | Xmega7),
+            Feature::SpmX => self.is_xmega(),
+            Feature::Des | Feature::Rmw => self.is_xmega() && !matches!(self, Xmega3 | AvrXt),
+            Feature::Reduced => self == AvrTiny,
+        }
+    }
+}
+
+impl fmt::Display for Core {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AsmError, Assembler, ProgramAddress, R0, R1};
+
+    #[test]
+    fn cores_are_found_by_their_avr_gcc_names() {
+        for &core in Core::ALL.iter() {
+            assert_eq!(Core::from_name(core.name()), Some(core));
+        }
+        assert_eq!(Core::from_name("avr7"), None);
+    }
+
+    #[test]
+    fn instructions_the_core_lacks_are_rejected() {
+        let mut asm = Assembler::new(Core::Avr2);
+        assert_eq!(asm.try_mul(R0, R1), Err(AsmError::UnsupportedInstruction { instruction: "mul", core: Core::Avr2 }));
+        assert!(asm.try_call(ProgramAddress::Absolute(0)).is_err());
+        assert!(asm.buf.is_empty());
+        assert!(Core::Avr6.has(Feature::Eijmp) && !Core::Avr5.has(Feature::Eijmp));
+        assert!(Core::Xmega2.has(Feature::Rmw) && !Core::Xmega3.has(Feature::Rmw) && !Core::AvrXt.has(Feature::Des));
+    }
+}
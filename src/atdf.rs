@@ -0,0 +1,538 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// A device described by a Microchip `.atdf` file.
+#[derive(Clone, Debug)]
+pub struct AtdfDevice {
+    pub name: String,
+    /// `AVR8` for classic parts, `AVR8L` for the reduced core, and `AVR8X` or
+    /// `AVR8_XMEGA` for parts that put I/O space at the start of data space.
+    pub architecture: String,
+    pub memories: Vec<AtdfMemory>,
+    pub registers: Vec<AtdfRegister>,
+    pub interrupts: Vec<AtdfInterrupt>,
+}
+
+/// A memory segment, such as `FLASH`, `IRAM` or `EEPROM`.
+#[derive(Clone, Debug)]
+pub struct AtdfMemory {
+    pub name: String,
+    pub start: u32,
+    pub size: u32,
+    pub page_size: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AtdfRegister {
+    /// The name given in the datasheet, such as `PORTB`, prefixed by the
+    /// peripheral instance on parts with repeated peripherals, as in `PORTA_DIR`.
+    pub name: String,
+    /// Data address of the lowest byte.
+    pub address: u32,
+    /// Bytes, lowest first.
+    pub size: u32,
+    pub bitfields: Vec<AtdfBitfield>,
+}
+
+/// A field of one or more bits in a register.
+#[derive(Clone, Debug)]
+pub struct AtdfBitfield {
+    pub name: String,
+    pub mask: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct AtdfInterrupt {
+    pub name: String,
+    /// Position in the vector table, where reset is 0.
+    pub index: u32,
+}
+
+#[derive(Debug)]
+pub enum AtdfError {
+    Io(io::Error),
+    /// The file is not well-formed XML. Lines are numbered from 1.
+    Syntax { line: usize, message: &'static str },
+    /// An element or attribute that every device file has is missing or malformed.
+    Missing(&'static str),
+}
+
+impl fmt::Display for AtdfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AtdfError::Io(ref err) => write!(f, "{}", err),
+            AtdfError::Syntax { line, message } => write!(f, "line {}: {}", line, message),
+            AtdfError::Missing(what) => write!(f, "missing or malformed {}", what),
+        }
+    }
+}
+
+impl Error for AtdfError {}
+
+impl From<io::Error> for AtdfError {
+    fn from(err: io::Error) -> AtdfError {
+        AtdfError::Io(err)
+    }
+}
+
+/// Reads the first device in an ATDF file. Registers outside data space, such
+/// as fuses and lock bits, are left out.
+pub fn read_atdf<R: Read>(mut input: R) -> Result<AtdfDevice, AtdfError> {
+    let mut source = String::new();
+    input.read_to_string(&mut source)?;
+    let root = parse_xml(&source)?;
+
+    let device = root.child("devices").and_then(|devices| devices.child("device"))
+        .ok_or(AtdfError::Missing("<device>"))?;
+    let name = device.attribute("name").ok_or(AtdfError::Missing("device name"))?.to_string();
+    let architecture = device.attribute("architecture").ok_or(AtdfError::Missing("device architecture"))?.to_string();
+    // Repeated peripherals on these parts share register names, so the instance tells them apart
+    let prefixed = architecture != "AVR8";
+
+    let mut memories = Vec::new();
+    for space in device.children("address-spaces").flat_map(|spaces| spaces.children("address-space")) {
+        for segment in space.children("memory-segment") {
+            memories.push(AtdfMemory {
+                name: segment.attribute("name").ok_or(AtdfError::Missing("memory segment name"))?.to_string(),
+                start: segment.number("start").ok_or(AtdfError::Missing("memory segment start"))?,
+                size: segment.number("size").ok_or(AtdfError::Missing("memory segment size"))?,
+                page_size: segment.number("pagesize"),
+            });
+        }
+    }
+
+    let modules = root.child("modules").ok_or(AtdfError::Missing("<modules>"))?;
+    let mut registers = Vec::new();
+    for module in device.children("peripherals").flat_map(|peripherals| peripherals.children("module")) {
+        let module_name = module.attribute("name").ok_or(AtdfError::Missing("module name"))?;
+        let definition = modules.children("module").find(|definition| definition.attribute("name") == Some(module_name))
+            .ok_or(AtdfError::Missing("module definition"))?;
+        for instance in module.children("instance") {
+            let instance_name = instance.attribute("name").ok_or(AtdfError::Missing("instance name"))?;
+            for group in instance.children("register-group") {
+                if group.attribute("address-space").is_some_and(|space| space != "data") {
+                    continue;
+                }
+                let group_name = group.attribute("name-in-module").ok_or(AtdfError::Missing("register group name-in-module"))?;
+                let offset = group.number("offset").ok_or(AtdfError::Missing("register group offset"))?;
+                let prefix = if prefixed { format!("{}_", instance_name) } else { String::new() };
+                read_registers(definition, group_name, offset, &prefix, prefixed, &mut registers)?;
+            }
+        }
+    }
+
+    let mut interrupts = Vec::new();
+    for interrupt in device.children("interrupts").flat_map(|interrupts| interrupts.children("interrupt")) {
+        let name = interrupt.attribute("name").ok_or(AtdfError::Missing("interrupt name"))?;
+        let name = match interrupt.attribute("module-instance") {
+            Some(instance) if prefixed => format!("{}_{}", instance, name),
+            _ => name.to_string(),
+        };
+        interrupts.push(AtdfInterrupt {
+            name,
+            index: interrupt.number("index").ok_or(AtdfError::Missing("interrupt index"))?,
+        });
+    }
+
+    Ok(AtdfDevice { name, architecture, memories, registers, interrupts })
+}
+
+// Reads the registers of one register group of a module, at `offset` in data
+// space, including those of the groups it nests
+fn read_registers(module: &Element, group_name: &str, offset: u32, prefix: &str, prefixed: bool, registers: &mut Vec<AtdfRegister>) -> Result<(), AtdfError> {
+    let group = module.children("register-group").find(|group| group.attribute("name") == Some(group_name))
+        .ok_or(AtdfError::Missing("register group definition"))?;
+    for child in group.children.iter() {
+        match &child.name[..] {
+            "register" => {
+                let name = child.attribute("name").ok_or(AtdfError::Missing("register name"))?;
+                let bitfields = child.children("bitfield")
+                    .map(|bitfield| Ok(AtdfBitfield {
+                        name: match bitfield.attribute("name") {
+                            Some(name) if prefixed => format!("{}_{}", group_name, name),
+                            Some(name) => name.to_string(),
+                            None => return Err(AtdfError::Missing("bitfield name")),
+                        },
+                        mask: bitfield.number("mask").ok_or(AtdfError::Missing("bitfield mask"))?,
+                    }))
+                    .collect::<Result<Vec<_>, _>>()?;
+                registers.push(AtdfRegister {
+                    name: format!("{}{}", prefix, name),
+                    address: offset + child.number("offset").ok_or(AtdfError::Missing("register offset"))?,
+                    size: child.number("size").unwrap_or(1),
+                    bitfields,
+                });
+            }
+            "register-group" => {
+                let nested = child.attribute("name-in-module").ok_or(AtdfError::Missing("register group name-in-module"))?;
+                let name = child.attribute("name").unwrap_or(nested);
+                let nested_offset = child.number("offset").unwrap_or(0);
+                read_registers(module, nested, offset + nested_offset, &format!("{}{}_", prefix, name), prefixed, registers)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl AtdfDevice {
+    /// The I/O address of a data address, if it is in the 64 bytes of I/O space
+    /// that `in_` and `out` reach.
+    pub fn io_address(&self, data_address: u32) -> Option<u32> {
+        let io_start = if self.architecture == "AVR8" { 0x20 } else { 0 };
+        if data_address >= io_start && data_address < io_start + 0x40 {
+            Some(data_address - io_start)
+        } else {
+            None
+        }
+    }
+
+    /// The bytes taken by each interrupt vector: a `jmp` above 8 KiB of flash
+    /// and an `rjmp` otherwise.
+    pub fn vector_size(&self) -> u32 {
+        match self.memories.iter().find(|memory| memory.name == "FLASH" || memory.name == "PROGMEM") {
+            Some(flash) if flash.size > 8 * 1024 => 4,
+            _ => 2,
+        }
+    }
+
+    /// Writes Rust constants for the device, for `include!` from a build script's output.
+    ///
+    /// As in AVRASM2's include files, a register in I/O space is given its
+    /// `IoAddress`, for `in_`, `out`, `sbi`, `cbi`, `sbic` and `sbis`, and any
+    /// other register its `DataAddress`, for `lds` and `sts`; both work with
+    /// `read_io` and `write_io`. Registers of more than one byte also get a
+    /// constant per byte, `L` and `H` suffixed for two bytes and numbered from 0
+    /// otherwise. The rest are `u32`. Bitfields are given as a `Bit`, for `sbi`,
+    /// `cbi`, `sbic`, `sbis`, `bst` and `bld`, and those of more than one bit as
+    /// one numbered `Bit` per bit. Each of these, and each bitfield as a whole,
+    /// also gets a `_MASK` over the whole register, for `ori` and `andi`. A bit of a wider register is
+    /// numbered within its byte, to go with that byte's constant. Memory
+    /// segments get `_START` and `_SIZE`, and interrupts a `_VECTOR` byte address.
+    /// When two constants would share a name, the first one is kept.
+    pub fn write_rust<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut names = HashSet::new();
+        let mut constant = |out: &mut W, name: String, type_name: &str, value: u32| -> io::Result<()> {
+            let name = identifier(&name);
+            if !names.insert(name.clone()) {
+                return Ok(());
+            }
+            match type_name {
+                "u32" => writeln!(out, "pub const {}: u32 = 0x{:x};", name, value),
+                "Bit" => writeln!(out, "pub const {}: ::rassembler_avr::Bit = ::rassembler_avr::Bit::B{};", name, value % 8),
+                _ => writeln!(out, "pub const {}: ::rassembler_avr::{} = ::rassembler_avr::{}(0x{:x});", name, type_name, type_name, value),
+            }
+        };
+
+        writeln!(out, "// Generated from the ATDF file for the {}", self.name)?;
+        for memory in self.memories.iter() {
+            constant(out, format!("{}_START", memory.name), "u32", memory.start)?;
+            constant(out, format!("{}_SIZE", memory.name), "u32", memory.size)?;
+        }
+        for register in self.registers.iter() {
+            let address = |byte: u32| {
+                let data_address = register.address + byte;
+                match self.io_address(data_address) {
+                    Some(io_address) => ("IoAddress", io_address),
+                    None => ("DataAddress", data_address),
+                }
+            };
+            let (type_name, value) = address(0);
+            constant(out, register.name.clone(), type_name, value)?;
+            let bytes: Vec<(String, u32)> = match register.size {
+                1 => Vec::new(),
+                2 => vec![(format!("{}L", register.name), 0), (format!("{}H", register.name), 1)],
+                size => (0..size).map(|byte| (format!("{}{}", register.name, byte), byte)).collect(),
+            };
+            for (name, byte) in bytes {
+                let (type_name, value) = address(byte);
+                constant(out, name, type_name, value)?;
+            }
+            for bitfield in register.bitfields.iter() {
+                let bits: Vec<u32> = (0..32).filter(|bit| bitfield.mask & (1 << bit) != 0).collect();
+                if bits.len() == 1 {
+                    constant(out, bitfield.name.clone(), "Bit", bits[0])?;
+                } else {
+                    for (index, &bit) in bits.iter().enumerate() {
+                        constant(out, format!("{}{}", bitfield.name, index), "Bit", bit)?;
+                        constant(out, format!("{}{}_MASK", bitfield.name, index), "u32", 1 << bit)?;
+                    }
+                }
+                constant(out, format!("{}_MASK", bitfield.name), "u32", bitfield.mask)?;
+            }
+        }
+        let vector_size = self.vector_size();
+        for interrupt in self.interrupts.iter() {
+            constant(out, format!("{}_VECTOR", interrupt.name), "u32", interrupt.index * vector_size)?;
+        }
+        Ok(())
+    }
+}
+
+// An uppercase Rust identifier for an ATDF name, which may hold spaces or dashes
+fn identifier(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
+
+// An XML element, with the text between its children dropped, as ATDF files
+// keep everything in attributes
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+}
+
+impl Element {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|(key, _)| key == name).map(|(_, value)| &value[..])
+    }
+
+    // An attribute holding a decimal or 0x-prefixed hexadecimal number
+    fn number(&self, name: &str) -> Option<u32> {
+        let value = self.attribute(name)?;
+        if value.starts_with("0x") || value.starts_with("0X") {
+            u32::from_str_radix(&value[2..], 16).ok()
+        } else {
+            value.parse().ok()
+        }
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> + 'a {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+
+    fn child<'a>(&'a self, name: &'a str) -> Option<&'a Element> {
+        self.children(name).next()
+    }
+}
+
+// Parses the subset of XML that ATDF files use: elements, attributes, the five
+// predefined entities and numeric character references. Declarations,
+// comments, CDATA and text are skipped.
+fn parse_xml(source: &str) -> Result<Element, AtdfError> {
+    let mut parser = XmlParser { source, position: 0 };
+    let mut stack: Vec<Element> = Vec::new();
+    loop {
+        match source[parser.position..].find('<') {
+            Some(offset) => parser.position += offset,
+            None if stack.is_empty() => return Err(parser.error("no root element")),
+            None => return Err(parser.error("unclosed element")),
+        }
+        let rest = &source[parser.position..];
+        if rest.starts_with("<?") {
+            parser.skip_past("?>")?;
+        } else if rest.starts_with("<!--") {
+            parser.skip_past("-->")?;
+        } else if rest.starts_with("<![CDATA[") {
+            parser.skip_past("]]>")?;
+        } else if rest.starts_with("<!") {
+            parser.skip_past(">")?;
+        } else if rest.starts_with("</") {
+            parser.position += 2;
+            let name = parser.name()?;
+            parser.skip_whitespace();
+            parser.expect(">")?;
+            let element = stack.pop().ok_or_else(|| parser.error("closing tag without an opening tag"))?;
+            if element.name != name {
+                return Err(parser.error("closing tag does not match the opening tag"));
+            }
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(element),
+                None => return Ok(element),
+            }
+        } else {
+            parser.position += 1;
+            let name = parser.name()?;
+            let mut attributes = Vec::new();
+            loop {
+                parser.skip_whitespace();
+                if parser.source[parser.position..].starts_with("/>") {
+                    parser.position += 2;
+                    let element = Element { name, attributes, children: Vec::new() };
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None => return Ok(element),
+                    }
+                    break;
+                }
+                if parser.source[parser.position..].starts_with('>') {
+                    parser.position += 1;
+                    stack.push(Element { name, attributes, children: Vec::new() });
+                    break;
+                }
+                let key = parser.name()?;
+                parser.skip_whitespace();
+                parser.expect("=")?;
+                parser.skip_whitespace();
+                let value = parser.quoted()?;
+                attributes.push((key, value));
+            }
+        }
+    }
+}
+
+struct XmlParser<'a> {
+    source: &'a str,
+    position: usize,
+}
+
+impl<'a> XmlParser<'a> {
+    fn error(&self, message: &'static str) -> AtdfError {
+        let line = self.source[..self.position].matches('\n').count() + 1;
+        AtdfError::Syntax { line, message }
+    }
+
+    fn skip_past(&mut self, end: &str) -> Result<(), AtdfError> {
+        match self.source[self.position..].find(end) {
+            Some(offset) => {
+                self.position += offset + end.len();
+                Ok(())
+            }
+            None => Err(self.error("unterminated markup")),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.source[self.position..];
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    fn expect(&mut self, text: &'static str) -> Result<(), AtdfError> {
+        if self.source[self.position..].starts_with(text) {
+            self.position += text.len();
+            Ok(())
+        } else {
+            Err(self.error("unexpected character in tag"))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, AtdfError> {
+        let rest = &self.source[self.position..];
+        let length = rest.find(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_' || c == ':' || c == '.'))
+            .unwrap_or(rest.len());
+        if length == 0 {
+            return Err(self.error("expected a name"));
+        }
+        self.position += length;
+        Ok(rest[..length].to_string())
+    }
+
+    fn quoted(&mut self) -> Result<String, AtdfError> {
+        let quote = match self.source[self.position..].chars().next() {
+            Some(quote @ '"') | Some(quote @ '\'') => quote,
+            _ => return Err(self.error("expected a quoted attribute value")),
+        };
+        self.position += 1;
+        let rest = &self.source[self.position..];
+        let length = rest.find(quote).ok_or_else(|| self.error("unterminated attribute value"))?;
+        let value = self.unescape(&rest[..length])?;
+        self.position += length + 1;
+        Ok(value)
+    }
+
+    fn unescape(&self, text: &str) -> Result<String, AtdfError> {
+        let mut value = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find('&') {
+            value.push_str(&rest[..start]);
+            let end = rest[start..].find(';').ok_or_else(|| self.error("unterminated entity"))? + start;
+            let entity = &rest[start + 1..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16).ok().and_then(std::char::from_u32),
+                _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(std::char::from_u32),
+                _ => None,
+            };
+            value.push(c.ok_or_else(|| self.error("unknown entity"))?);
+            rest = &rest[end + 1..];
+        }
+        value.push_str(rest);
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ATDF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- A cut-down ATmega328P -->
+<avr-tools-device-file>
+  <devices>
+    <device name="ATmega328P" architecture="AVR8" family="megaAVR">
+      <address-spaces>
+        <address-space id="prog" name="prog" start="0x0000" size="0x8000">
+          <memory-segment name="FLASH" start="0x0000" size="0x8000" pagesize="0x80"/>
+        </address-space>
+      </address-spaces>
+      <peripherals>
+        <module name="PORT">
+          <instance name="PORTB">
+            <register-group name="PORTB" name-in-module="PORTB" offset="0x00" address-space="data"/>
+          </instance>
+        </module>
+      </peripherals>
+      <interrupts>
+        <interrupt index="1" name="INT0" caption="External Interrupt Request 0"/>
+      </interrupts>
+    </device>
+  </devices>
+  <modules>
+    <module name="PORT">
+      <register-group name="PORTB" caption="I/O Port &amp; Pins">
+        <register name="PORTB" offset="0x25" size="1">
+          <bitfield name="PORTB5" mask="0x20"/>
+          <bitfield name="PORTB" mask="0x06"/>
+        </register>
+        <register name="ADC" offset="0x78" size="2"/>
+      </register-group>
+    </module>
+  </modules>
+</avr-tools-device-file>
+"#;
+
+    #[test]
+    fn reads_registers_and_writes_constants() {
+        let device = read_atdf(ATDF.as_bytes()).unwrap();
+        assert_eq!((&device.name[..], &device.architecture[..]), ("ATmega328P", "AVR8"));
+        assert_eq!(device.registers.iter().map(|register| (&register.name[..], register.address)).collect::<Vec<_>>(), [("PORTB", 0x25), ("ADC", 0x78)]);
+
+        let mut out = Vec::new();
+        device.write_rust(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        for line in [
+            "pub const FLASH_SIZE: u32 = 0x8000;",
+            "pub const PORTB: ::rassembler_avr::IoAddress = ::rassembler_avr::IoAddress(0x5);",
+            "pub const PORTB5: ::rassembler_avr::Bit = ::rassembler_avr::Bit::B5;",
+            "pub const PORTB5_MASK: u32 = 0x20;",
+            "pub const PORTB1: ::rassembler_avr::Bit = ::rassembler_avr::Bit::B2;",
+            "pub const PORTB1_MASK: u32 = 0x4;",
+            "pub const PORTB_MASK: u32 = 0x6;",
+            "pub const ADCH: ::rassembler_avr::DataAddress = ::rassembler_avr::DataAddress(0x79);",
+            "pub const INT0_VECTOR: u32 = 0x4;",
+        ].iter() {
+            assert!(out.lines().any(|written| written == *line), "{} is missing from:\n{}", line, out);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_xml() {
+        match read_atdf(&b"<devices>\n<device name=\"x\">\n</devices>"[..]) {
+            Err(AtdfError::Syntax { .. }) => {}
+            result => panic!("{:?}", result.map(|device| device.name)),
+        }
+    }
+}
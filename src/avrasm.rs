@@ -0,0 +1,919 @@
+// A front end for the AVRASM2 dialect of Atmel Studio and Microchip Studio.
+//
+// Source first goes through a preprocessor, which reads included files,
+// expands `#define`s and macros, and drops lines excluded by `#if`. What is
+// left is assembled in two passes, as for the GNU dialect. AVRASM2 is
+// case insensitive, so every name is read in lower case.
+
+use std::collections::HashMap;
+use std::io;
+
+use syntax::{assemble_instruction, constant, evaluate, instruction_size, lex, operand_column, parse_expression, register_name, split_operands};
+use syntax::{strip_block_comments, AddressByte, Expr, LexOptions, Located, SourceError, Symbols, Token, TokenKind};
+use {Assembler, Core, Device, Label, MemoryImage, Register};
+
+const LEX_OPTIONS: LexOptions = LexOptions {
+    leading_zero_octal: true,
+    local_labels: false,
+    dollar_hex: true,
+};
+
+// Deep enough for any real project, and stops a file that includes itself
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// Where `.dseg` starts until moved with `.org`, on a device this crate does
+// not know the memories of
+const DATA_START: u32 = 0x60;
+
+// Register names AVRASM2 defines itself, as if by `.def`, before any source
+const POINTER_BYTES: [(&str, u32); 6] = [("xl", 26), ("xh", 27), ("yl", 28), ("yh", 29), ("zl", 30), ("zh", 31)];
+
+// One line left by the preprocessor, with identifiers in lower case
+struct Line {
+    file: String,
+    line: usize,
+    tokens: Vec<Token>,
+}
+
+struct Macro {
+    // The body, with where each line came from
+    lines: Vec<(String, usize, String)>,
+}
+
+struct Preprocessor<'i> {
+    include: &'i mut dyn FnMut(&str) -> io::Result<String>,
+    defines: HashMap<String, Vec<Token>>,
+    macros: HashMap<String, Macro>,
+    // One entry per open `#if`: whether its lines are read, and whether any
+    // branch of it has been taken
+    conditions: Vec<(bool, bool)>,
+    // The macro being defined, and its name
+    recording: Option<(String, Macro)>,
+    expansions: usize,
+    lines: Vec<Line>,
+}
+
+impl<'i> Preprocessor<'i> {
+    fn active(&self) -> bool {
+        self.conditions.iter().all(|&(active, _)| active)
+    }
+
+    // Reads a whole file, up to any `.exit`
+    fn file(&mut self, file: &str, source: &str, depth: usize) -> Result<(), SourceError> {
+        let source = strip_block_comments(source);
+        for (index, text) in source.lines().enumerate() {
+            let error = |(column, message)| SourceError { file: file.to_string(), line: index + 1, column, message };
+            if !self.line(file, index + 1, text, &HashMap::new(), depth).map_err(error)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Reads one line, returning false at `.exit`. `renames` gives the names of
+    // labels inside a macro for this expansion of it.
+    fn line(&mut self, file: &str, line: usize, text: &str, renames: &HashMap<String, String>, depth: usize) -> Located<bool> {
+        let trimmed = text.trim_start();
+        let indent = text.len() - trimmed.len();
+
+        if let Some((ref name, ref mut body)) = self.recording {
+            // The body is only read when it is expanded, since `@0` is not a token
+            let first = trimmed.split(|c: char| c.is_whitespace() || c == ';').next().unwrap_or("").to_lowercase();
+            match &first[..] {
+                ".endm" | ".endmacro" => {}
+                _ => {
+                    body.lines.push((file.to_string(), line, text.to_string()));
+                    return Ok(true);
+                }
+            }
+            let name = name.clone();
+            let (_, body) = self.recording.take().unwrap();
+            self.macros.insert(name, body);
+            return Ok(true);
+        }
+
+        if let Some(directive) = trimmed.strip_prefix('#') {
+            let tokens = lowercase(lex(directive, LEX_OPTIONS).map_err(|(column, message)| (column + indent + 1, message))?);
+            return self.directive(indent + 1, &tokens, depth);
+        }
+        if !self.active() {
+            return Ok(true);
+        }
+
+        let mut tokens = lowercase(lex(text, LEX_OPTIONS)?);
+        for token in tokens.iter_mut() {
+            if let TokenKind::Identifier(ref mut name) = token.kind {
+                if let Some(renamed) = renames.get(name) {
+                    *name = renamed.clone();
+                }
+            }
+        }
+        let tokens = self.substitute(tokens)?;
+
+        // Labels may come before a directive or macro call
+        let mut start = 0;
+        while tokens.len() >= start + 2 && tokens[start + 1].is_punct(":") {
+            start += 2;
+        }
+        let head = tokens.get(start).and_then(|token| token.identifier()).map(|name| name.to_string());
+        match head.as_ref().map(|name| &name[..]) {
+            Some(".include") => {
+                self.push(file, line, tokens[..start].to_vec());
+                let column = tokens[start].column;
+                let name = match tokens.get(start + 1).map(|token| &token.kind) {
+                    Some(TokenKind::String(bytes)) if tokens.len() == start + 2 => String::from_utf8_lossy(bytes).into_owned(),
+                    _ => return Err((column, "expected a file name in quotes".to_string())),
+                };
+                self.include_file(&name, column, depth)?;
+            }
+            Some(".macro") => {
+                let name = match tokens.get(start + 1).and_then(|token| token.identifier()) {
+                    Some(name) => name.to_string(),
+                    None => return Err((tokens[start].column, "expected a macro name".to_string())),
+                };
+                self.push(file, line, tokens[..start].to_vec());
+                self.recording = Some((name, Macro { lines: Vec::new() }));
+            }
+            Some(".exit") => return Ok(false),
+            Some(".if") | Some(".ifdef") | Some(".ifndef") | Some(".elif") | Some(".else") | Some(".endif") => {
+                self.push(file, line, tokens);
+            }
+            Some(name) if self.macros.contains_key(name) => {
+                self.push(file, line, tokens[..start].to_vec());
+                let column = tokens[start].column;
+                // Arguments are substituted as text, so take them from the line itself
+                let rest: String = text.chars().skip(column - 1 + name.chars().count()).collect();
+                let arguments = split_arguments(&rest);
+                self.expand(name, &arguments, column, depth)?;
+            }
+            _ => self.push(file, line, tokens),
+        }
+        Ok(true)
+    }
+
+    fn push(&mut self, file: &str, line: usize, tokens: Vec<Token>) {
+        if !tokens.is_empty() {
+            self.lines.push(Line { file: file.to_string(), line, tokens });
+        }
+    }
+
+    fn include_file(&mut self, name: &str, column: usize, depth: usize) -> Located<()> {
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err((column, "files are included too deeply".to_string()));
+        }
+        let source = (self.include)(name).map_err(|err| (column, format!("cannot include `{}`: {}", name, err)))?;
+        self.file(name, &source, depth + 1).map_err(|err| (column, err.to_string()))
+    }
+
+    fn expand(&mut self, name: &str, arguments: &[String], column: usize, depth: usize) -> Located<()> {
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err((column, "macros are expanded too deeply".to_string()));
+        }
+        let lines: Vec<(String, usize, String)> = self.macros[name].lines.iter()
+            .map(|&(ref file, line, ref text)| (file.clone(), line, substitute_arguments(text, arguments)))
+            .collect();
+
+        // Labels defined in the body are given a new name for each expansion
+        self.expansions += 1;
+        let mut renames = HashMap::new();
+        for (_, _, text) in lines.iter() {
+            let tokens = lowercase(lex(text, LEX_OPTIONS).unwrap_or_default());
+            let mut start = 0;
+            while tokens.len() >= start + 2 && tokens[start + 1].is_punct(":") {
+                if let Some(label) = tokens[start].identifier() {
+                    renames.insert(label.to_string(), format!("{}\u{2}{}", label, self.expansions));
+                }
+                start += 2;
+            }
+        }
+
+        for &(ref file, line, ref text) in lines.iter() {
+            let result = self.line(file, line, text, &renames, depth + 1);
+            match result {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err((body_column, message)) => {
+                    return Err((column, format!("in macro `{}` at {}:{}:{}: {}", name, file, line, body_column, message)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // `#` directives
+    fn directive(&mut self, column: usize, tokens: &[Token], depth: usize) -> Located<bool> {
+        if self.conditional_directive(tokens, column)? || !self.active() {
+            return Ok(true);
+        }
+        let name = tokens.first().and_then(|token| token.identifier()).unwrap_or("");
+        match name {
+            "define" => {
+                let name = match tokens.get(1).and_then(|token| token.identifier()) {
+                    Some(name) => name.to_string(),
+                    None => return Err((column, "expected a name to define".to_string())),
+                };
+                if tokens.get(2).is_some_and(|token| token.is_punct("(") && token.column == tokens[1].column + name.len()) {
+                    return Err((tokens[2].column, "macros with parameters are not supported".to_string()));
+                }
+                self.defines.insert(name, tokens[2..].to_vec());
+            }
+            "undef" => {
+                if let Some(name) = tokens.get(1).and_then(|token| token.identifier()) {
+                    self.defines.remove(name);
+                }
+            }
+            "include" => {
+                match tokens.get(1).map(|token| &token.kind) {
+                    Some(TokenKind::String(bytes)) => {
+                        let name = String::from_utf8_lossy(bytes).into_owned();
+                        self.include_file(&name, column, depth)?;
+                    }
+                    _ => return Err((column, "expected a file name in quotes".to_string())),
+                }
+            }
+            "error" => {
+                let message = match tokens.get(1).map(|token| &token.kind) {
+                    Some(TokenKind::String(bytes)) => String::from_utf8_lossy(bytes).into_owned(),
+                    _ => "#error".to_string(),
+                };
+                return Err((column, message));
+            }
+            "pragma" | "message" | "warning" => {}
+            _ => return Err((column, format!("unknown preprocessor directive `#{}`", name))),
+        }
+        Ok(true)
+    }
+
+    // Handles `#if` and friends, returning whether `tokens` was one of them
+    fn conditional_directive(&mut self, tokens: &[Token], column: usize) -> Located<bool> {
+        let name = tokens.first().and_then(|token| token.identifier()).unwrap_or("");
+        let outer = self.conditions.iter().rev().skip(1).all(|&(active, _)| active);
+        match name {
+            "ifdef" | "ifndef" | "if" => {
+                let parent = self.active();
+                let value = parent && match name {
+                    "if" => self.condition(&tokens[1..], column)?,
+                    _ => {
+                        let defined = tokens.get(1).and_then(|token| token.identifier()).is_some_and(|name| self.defines.contains_key(name));
+                        defined == (name == "ifdef")
+                    }
+                };
+                self.conditions.push((value, value));
+            }
+            "elif" | "else" => {
+                let top = self.conditions.len().checked_sub(1).ok_or((column, format!("#{} without #if", name)))?;
+                let (_, taken) = self.conditions[top];
+                let value = outer && !taken && (name == "else" || self.condition(&tokens[1..], column)?);
+                self.conditions[top] = (value, taken || value);
+            }
+            "endif" => {
+                self.conditions.pop().ok_or((column, "#endif without #if".to_string()))?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // Evaluates the expression of `#if`, where `defined(x)` tests for a
+    // `#define` and other names count as 0
+    fn condition(&self, tokens: &[Token], column: usize) -> Located<bool> {
+        let mut replaced = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            if tokens[i].identifier() == Some("defined") {
+                let (name, length) = if tokens.get(i + 1).is_some_and(|token| token.is_punct("(")) {
+                    (tokens.get(i + 2).and_then(|token| token.identifier()), 4)
+                } else {
+                    (tokens.get(i + 1).and_then(|token| token.identifier()), 2)
+                };
+                let defined = name.is_some_and(|name| self.defines.contains_key(name));
+                replaced.push(Token { kind: TokenKind::Number(defined as i64), column: tokens[i].column });
+                i += length;
+            } else {
+                replaced.push(tokens[i].clone());
+                i += 1;
+            }
+        }
+        let replaced = self.substitute(replaced)?;
+        let expr = parse_expression(&replaced, column)?;
+        Ok(evaluate(&expr, &|_| Some(0), &function)? != 0)
+    }
+
+    // Replaces `#define`d names with their tokens, which may use other
+    // `#define`s, keeping the column of the name
+    fn substitute(&self, tokens: Vec<Token>) -> Located<Vec<Token>> {
+        let mut tokens = tokens;
+        for _ in 0..MAX_INCLUDE_DEPTH {
+            let mut changed = false;
+            let mut result = Vec::with_capacity(tokens.len());
+            for token in tokens.into_iter() {
+                let replacement = token.identifier().and_then(|name| self.defines.get(name));
+                match replacement {
+                    Some(replacement) => {
+                        changed = true;
+                        result.extend(replacement.iter().map(|replaced| Token { kind: replaced.kind.clone(), column: token.column }));
+                    }
+                    None => result.push(token),
+                }
+            }
+            tokens = result;
+            if !changed {
+                return Ok(tokens);
+            }
+        }
+        Err((tokens.first().map_or(1, |token| token.column), "#define refers to itself".to_string()))
+    }
+}
+
+fn lowercase(tokens: Vec<Token>) -> Vec<Token> {
+    tokens.into_iter().map(|token| {
+        match token.kind {
+            TokenKind::Identifier(name) => Token { kind: TokenKind::Identifier(name.to_lowercase()), column: token.column },
+            kind => Token { kind, column: token.column },
+        }
+    }).collect()
+}
+
+// Splits the text after a macro name at commas outside quotes and
+// parentheses, stopping at a comment
+fn split_arguments(text: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if let Some(q) = quote {
+            current.push(c);
+            if c == '\\' {
+                if let Some(next) = chars.next() {
+                    current.push(next);
+                }
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            ';' => break,
+            '/' if chars.peek() == Some(&'/') => break,
+            '"' | '\'' => {
+                quote = Some(c);
+                current.push(c);
+            }
+            '(' => {
+                depth += 1;
+                current.push(c);
+            }
+            ')' => {
+                depth -= 1;
+                current.push(c);
+            }
+            ',' if depth == 0 => arguments.push(current.split_off(0)),
+            c => current.push(c),
+        }
+    }
+    if !current.trim().is_empty() || !arguments.is_empty() {
+        arguments.push(current);
+    }
+    arguments.into_iter().map(|argument| argument.trim().to_string()).collect()
+}
+
+// Replaces `@0` to `@9` in a line of a macro body
+fn substitute_arguments(text: &str, arguments: &[String]) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek().and_then(|next| next.to_digit(10))) {
+            ('@', Some(index)) => {
+                chars.next();
+                result.push_str(arguments.get(index as usize).map_or("", |argument| &argument[..]));
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+// The functions AVRASM2 accepts in expressions
+fn function(name: &str, value: i64) -> Option<i64> {
+    Some(match name {
+        "low" | "byte1" => value & 0xff,
+        "high" | "byte2" => (value >> 8) & 0xff,
+        "byte3" => (value >> 16) & 0xff,
+        "byte4" => (value >> 24) & 0xff,
+        "lwrd" => value & 0xffff,
+        "hwrd" => (value >> 16) & 0xffff,
+        "page" => (value >> 16) & 0x3f,
+        "exp2" => 1i64.checked_shl(value as u32).unwrap_or(0),
+        "log2" => if value > 0 { 63 - value.leading_zeros() as i64 } else { 0 },
+        "abs" => value.abs(),
+        _ => return None,
+    })
+}
+
+// The labels at the start of a line, with their columns, and the rest of it
+type LabelledLine<'t> = (Vec<(&'t str, usize)>, &'t [Token]);
+
+fn split_labels(tokens: &[Token]) -> Located<LabelledLine<'_>> {
+    let mut labels = Vec::new();
+    let mut start = 0;
+    while tokens.len() >= start + 2 && tokens[start + 1].is_punct(":") {
+        match tokens[start].identifier() {
+            Some(name) => labels.push((name, tokens[start].column)),
+            None => return Err((tokens[start].column, "invalid label".to_string())),
+        }
+        start += 2;
+    }
+    Ok((labels, &tokens[start..]))
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Segment {
+    Code,
+    Data,
+    Eeprom,
+}
+
+#[derive(Copy, Clone)]
+enum Symbol {
+    // Byte address of a label in the code segment
+    Label(u32),
+    // Address of a label in the data or EEPROM segment
+    Data(u32),
+    // Set by `.equ`, which cannot be redefined
+    Equ(i64),
+    // Set by `.set`
+    Set(i64),
+}
+
+struct Source {
+    core: Core,
+    segment: Segment,
+    // Next address in each segment. The code address is in bytes, like the
+    // `Assembler`'s, although the source sees word addresses.
+    code: u32,
+    data: u32,
+    eeprom: u32,
+    // The start of SRAM, where `.dseg` starts
+    data_start: u32,
+    symbols: HashMap<String, Symbol>,
+    registers: HashMap<String, Register>,
+    label_order: Vec<String>,
+    labels: HashMap<String, Label>,
+    // Whether each open `.if` is being assembled, and whether any branch of it has been
+    conditions: Vec<(bool, bool)>,
+}
+
+impl Assembler {
+    /// Assembles AVRASM2 source, as accepted by the assembler of Atmel
+    /// Studio and Microchip Studio, starting at the current address.
+    ///
+    /// `include` returns the contents of a file named by `.include` or
+    /// `#include`, such as a part definition file like `m328Pdef.inc`.
+    /// Returns what `.eseg` places in EEPROM. `.dseg` only gives values to
+    /// labels, starting at the start of SRAM of the assembler's device, or
+    /// of one named by `.device`, unless moved with `.org`. Without a device
+    /// this crate knows, it starts at 0x60.
+    ///
+    /// As in AVRASM2, code labels and `.org` in `.cseg` are word addresses.
+    /// Every symbol must be defined, since there is no linker to find it.
+    /// `XL` to `ZH` name R26 to R31 from the start, as they do in AVRASM2.
+    pub fn assemble_avrasm<F>(&mut self, file_name: &str, source: &str, mut include: F) -> Result<MemoryImage, SourceError>
+        where F: FnMut(&str) -> io::Result<String>
+    {
+        let mut preprocessor = Preprocessor {
+            include: &mut include,
+            defines: HashMap::new(),
+            macros: HashMap::new(),
+            conditions: Vec::new(),
+            recording: None,
+            expansions: 0,
+            lines: Vec::new(),
+        };
+        preprocessor.file(file_name, source, 0)?;
+        let end = SourceError { file: file_name.to_string(), line: source.lines().count(), column: 1, message: String::new() };
+        if preprocessor.recording.is_some() {
+            return Err(SourceError { message: "macro is missing `.endm`".to_string(), ..end });
+        }
+        if !preprocessor.conditions.is_empty() {
+            return Err(SourceError { message: "#if is missing #endif".to_string(), ..end });
+        }
+        let lines = preprocessor.lines;
+
+        let mut source = Source::new(self);
+        let mut included = Vec::with_capacity(lines.len());
+        for line in lines.iter() {
+            let error = |(column, message)| SourceError { file: line.file.clone(), line: line.line, column, message };
+            included.push(source.layout_line(&line.tokens).map_err(error)?);
+        }
+        if !source.conditions.is_empty() {
+            return Err(SourceError { message: "`.if` is missing `.endif`".to_string(), ..end });
+        }
+
+        for name in source.label_order.iter() {
+            let label = self.named_label(name);
+            source.labels.insert(name.clone(), label);
+        }
+
+        let mut eeprom = MemoryImage::new();
+        let origin = self.address();
+        source.reset(origin);
+        for (line, &included) in lines.iter().zip(included.iter()) {
+            if included {
+                source.emit_line(self, &mut eeprom, &line.tokens)
+                    .map_err(|(column, message)| SourceError { file: line.file.clone(), line: line.line, column, message })?;
+            }
+        }
+        Ok(eeprom)
+    }
+}
+
+impl Source {
+    fn new(assembler: &Assembler) -> Source {
+        let mut source = Source {
+            core: assembler.core(),
+            segment: Segment::Code,
+            code: 0,
+            data: 0,
+            eeprom: 0,
+            data_start: assembler.device().map_or(DATA_START, |device| device.sram_start),
+            symbols: HashMap::new(),
+            registers: HashMap::new(),
+            label_order: Vec::new(),
+            labels: HashMap::new(),
+            conditions: Vec::new(),
+        };
+        source.reset(assembler.address());
+        source
+    }
+
+    // Goes back to the start, for the second pass
+    fn reset(&mut self, code: u32) {
+        self.segment = Segment::Code;
+        self.code = code;
+        self.data = self.data_start;
+        self.eeprom = 0;
+        self.registers.clear();
+        self.registers.extend(POINTER_BYTES.iter().map(|&(name, number)| (name.to_string(), Register(number))));
+        self.symbols.retain(|_, symbol| matches!(*symbol, Symbol::Label(_) | Symbol::Data(_)));
+    }
+
+    fn address(&mut self) -> &mut u32 {
+        match self.segment {
+            Segment::Code => &mut self.code,
+            Segment::Data => &mut self.data,
+            Segment::Eeprom => &mut self.eeprom,
+        }
+    }
+
+    // Handles `.if` and friends, returning whether the line is one of them
+    fn conditional(&mut self, name: &str, tokens: &[Token], column: usize) -> Located<bool> {
+        let active = self.conditions.iter().all(|&(active, _)| active);
+        let outer = self.conditions.iter().rev().skip(1).all(|&(active, _)| active);
+        match name {
+            ".if" | ".ifdef" | ".ifndef" => {
+                let value = active && match name {
+                    ".if" => constant(self, tokens, column)? != 0,
+                    _ => {
+                        let defined = tokens.first().and_then(|token| token.identifier())
+                            .is_some_and(|name| self.symbols.contains_key(name) || self.registers.contains_key(name));
+                        defined == (name == ".ifdef")
+                    }
+                };
+                self.conditions.push((value, value));
+            }
+            ".elif" | ".else" => {
+                let top = self.conditions.len().checked_sub(1).ok_or((column, format!("`{}` without `.if`", name)))?;
+                let (_, taken) = self.conditions[top];
+                let value = outer && !taken && (name == ".else" || constant(self, tokens, column)? != 0);
+                self.conditions[top] = (value, taken || value);
+            }
+            ".endif" => {
+                self.conditions.pop().ok_or((column, "`.endif` without `.if`".to_string()))?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // The first pass over one line: defines its labels and moves the address
+    // past it. Returns whether the line is assembled, rather than left out by `.if`.
+    fn layout_line(&mut self, tokens: &[Token]) -> Located<bool> {
+        let (labels, rest) = split_labels(tokens)?;
+        let head = rest.first().and_then(|token| token.identifier()).unwrap_or("");
+        let column = rest.first().map_or(1, |token| token.column);
+        if self.conditional(head, &rest[1.min(rest.len())..], column)? {
+            return Ok(false);
+        }
+        if !self.conditions.iter().all(|&(active, _)| active) {
+            return Ok(false);
+        }
+
+        for (name, column) in labels {
+            if self.symbols.contains_key(name) {
+                return Err((column, format!("symbol `{}` is already defined", name)));
+            }
+            let symbol = match self.segment {
+                Segment::Code => {
+                    self.label_order.push(name.to_string());
+                    Symbol::Label(self.code)
+                }
+                _ => Symbol::Data(*self.address()),
+            };
+            self.symbols.insert(name.to_string(), symbol);
+        }
+        if rest.is_empty() {
+            return Ok(true);
+        }
+
+        let size = if head.starts_with('.') {
+            // Assignments that need a later label are made again in the second pass
+            self.directive(None, None, head, &rest[1..], column, true)?
+        } else {
+            if self.segment != Segment::Code {
+                return Err((column, "instructions must be in the code segment".to_string()));
+            }
+            instruction_size(head, split_operands(&rest[1..]).len(), self.core)
+                .ok_or_else(|| (column, format!("unknown instruction `{}`", head)))?
+        };
+        *self.address() += size;
+        Ok(true)
+    }
+
+    // The second pass over one line
+    fn emit_line(&mut self, assembler: &mut Assembler, eeprom: &mut MemoryImage, tokens: &[Token]) -> Located<()> {
+        let (labels, rest) = split_labels(tokens)?;
+        if self.segment == Segment::Code {
+            for (name, column) in labels {
+                assembler.try_bind(self.labels[name]).map_err(|err| (column, err.to_string()))?;
+            }
+        }
+        let head = match rest.first().and_then(|token| token.identifier()) {
+            Some(head) => head,
+            None if rest.is_empty() => return Ok(()),
+            None => return Err((rest[0].column, "expected a mnemonic or directive".to_string())),
+        };
+        let column = rest[0].column;
+        if head.starts_with('.') {
+            let size = self.directive(Some(assembler), Some(eeprom), head, &rest[1..], column, false)?;
+            *self.address() += size;
+            Ok(())
+        } else {
+            self.code = assembler.address();
+            assemble_instruction(assembler, self, head, column, &rest[1..])?;
+            self.code = assembler.address();
+            Ok(())
+        }
+    }
+
+    // Handles a directive, returning how many bytes it takes in the current
+    // segment. Output is only written in the second pass, when `assembler` is given.
+    fn directive(&mut self, assembler: Option<&mut Assembler>, eeprom: Option<&mut MemoryImage>, name: &str, tokens: &[Token], column: usize, first_pass: bool) -> Located<u32> {
+        let operands = split_operands(tokens);
+        match name {
+            ".equ" | ".set" | ".def" => {
+                let (symbol, value) = match (tokens.first().and_then(|token| token.identifier()), tokens.get(1)) {
+                    (Some(symbol), Some(equals)) if equals.is_punct("=") => (symbol.to_string(), &tokens[2..]),
+                    _ => return Err((column, format!("expected `{} name = value`", name))),
+                };
+                if name == ".def" {
+                    let register = match value.first().and_then(|token| token.identifier()) {
+                        Some(register) if value.len() == 1 => self.register(register),
+                        _ => None,
+                    };
+                    let register = register.ok_or_else(|| (operand_column(value, column), "expected a register".to_string()))?;
+                    self.registers.insert(symbol, register);
+                    return Ok(0);
+                }
+                let value = match constant(self, value, column) {
+                    Ok(value) => value,
+                    Err(_) if first_pass => return Ok(0),
+                    Err(err) => return Err(err),
+                };
+                match (name, self.symbols.get(&symbol)) {
+                    (".equ", Some(_)) | (".set", Some(&Symbol::Equ(_))) | (".set", Some(&Symbol::Label(_))) | (".set", Some(&Symbol::Data(_))) => {
+                        return Err((tokens[0].column, format!("symbol `{}` is already defined", symbol)));
+                    }
+                    _ => {}
+                }
+                self.symbols.insert(symbol, if name == ".equ" { Symbol::Equ(value) } else { Symbol::Set(value) });
+                Ok(0)
+            }
+            ".undef" => {
+                if let Some(symbol) = tokens.first().and_then(|token| token.identifier()) {
+                    self.registers.remove(symbol);
+                }
+                Ok(0)
+            }
+            ".cseg" => { self.segment = Segment::Code; Ok(0) }
+            ".dseg" => { self.segment = Segment::Data; Ok(0) }
+            ".eseg" => { self.segment = Segment::Eeprom; Ok(0) }
+            ".org" => {
+                let value = constant(self, tokens, column)?;
+                let target = if self.segment == Segment::Code { value * 2 } else { value };
+                let start = *self.address() as i64;
+                if target < start {
+                    return Err((column, "`.org` cannot move the address backwards".to_string()));
+                }
+                let length = (target - start) as u32;
+                self.output(assembler, eeprom, &vec![0xff; length as usize], column)?;
+                Ok(length)
+            }
+            ".byte" => {
+                if self.segment == Segment::Code {
+                    return Err((column, "`.byte` reserves memory in the data or EEPROM segment; use `.db` for constants".to_string()));
+                }
+                let length = constant(self, tokens, column)?;
+                if length < 0 {
+                    return Err((column, "size must not be negative".to_string()));
+                }
+                self.output(assembler, eeprom, &vec![0xff; length as usize], column)?;
+                Ok(length as u32)
+            }
+            ".db" | ".dw" | ".dd" => {
+                if self.segment == Segment::Data {
+                    return Err((column, format!("`{}` cannot be used in the data segment", name)));
+                }
+                let size = match name { ".db" => 1, ".dw" => 2, _ => 4 };
+                let mut bytes = Vec::new();
+                for tokens in operands.iter() {
+                    if let (".db", Some(TokenKind::String(string)), 1) = (name, tokens.first().map(|token| &token.kind), tokens.len()) {
+                        bytes.extend_from_slice(string);
+                        continue;
+                    }
+                    let value = match constant(self, tokens, column) {
+                        Ok(value) => value,
+                        Err(_) if first_pass => 0,
+                        Err(err) => return Err(err),
+                    };
+                    let bits = 8 * size;
+                    if value < -(1i64 << (bits - 1)) || value >= 1i64 << bits {
+                        return Err((operand_column(tokens, column), format!("{} does not fit in {} bits", value, bits)));
+                    }
+                    bytes.extend((0..size).map(|index| (value >> (8 * index)) as u8));
+                }
+                // Program memory is made of words, so AVRASM2 pads an odd number of bytes
+                if self.segment == Segment::Code && bytes.len() % 2 != 0 {
+                    bytes.push(0);
+                }
+                self.output(assembler, eeprom, &bytes, column)?;
+                Ok(bytes.len() as u32)
+            }
+            ".error" => {
+                let message = match tokens.first().map(|token| &token.kind) {
+                    Some(TokenKind::String(bytes)) => String::from_utf8_lossy(bytes).into_owned(),
+                    _ => ".error".to_string(),
+                };
+                Err((column, message))
+            }
+            ".device" => {
+                // A device this crate does not know keeps the start it had
+                let device = tokens.first().and_then(|token| token.identifier()).and_then(Device::from_name);
+                if let Some(device) = device {
+                    if self.data == self.data_start {
+                        self.data = device.sram_start;
+                    }
+                    self.data_start = device.sram_start;
+                }
+                Ok(0)
+            }
+            ".list" | ".nolist" | ".listmac" | ".overlap" | ".nooverlap" | ".message" | ".warning" | ".csegsize" => Ok(0),
+            _ => Err((column, format!("unknown directive `{}`", name))),
+        }
+    }
+
+    // Writes bytes at the address of the current segment, in the second pass
+    fn output(&self, assembler: Option<&mut Assembler>, eeprom: Option<&mut MemoryImage>, bytes: &[u8], column: usize) -> Located<()> {
+        match (self.segment, assembler, eeprom) {
+            (Segment::Code, Some(assembler), _) => assembler.try_emit_bytes(bytes).map_err(|err| (column, err.to_string()))?,
+            (Segment::Eeprom, _, Some(eeprom)) => eeprom.insert(self.eeprom, bytes),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn register(&self, name: &str) -> Option<Register> {
+        register_name(name).or_else(|| self.registers.get(name).cloned())
+    }
+}
+
+impl Symbols for Source {
+    fn value(&self, name: &str) -> Option<i64> {
+        if name == "pc" {
+            return Some(self.code as i64 / 2);
+        }
+        match self.symbols.get(name) {
+            Some(&Symbol::Label(address)) => Some(address as i64 / 2),
+            Some(&Symbol::Data(address)) => Some(address as i64),
+            Some(&Symbol::Equ(value)) | Some(&Symbol::Set(value)) => Some(value),
+            None => None,
+        }
+    }
+
+    fn label(&mut self, _assembler: &mut Assembler, name: &str) -> Option<Label> {
+        match self.symbols.get(name) {
+            Some(&Symbol::Label(_)) => Some(self.labels[name]),
+            _ => None,
+        }
+    }
+
+    fn function(&self, name: &str, value: i64) -> Option<i64> {
+        function(name, value)
+    }
+
+    // Code labels are word addresses, so `low(x)` of one is the low byte of a word address
+    fn address_byte(&self, expr: &Expr) -> Option<(AddressByte, String)> {
+        match *expr {
+            Expr::Call(ref function, ref argument, _) => {
+                let byte = match &function[..] {
+                    "low" | "byte1" => AddressByte::PmLo8,
+                    "high" | "byte2" => AddressByte::PmHi8,
+                    _ => return None,
+                };
+                match **argument {
+                    Expr::Symbol(ref symbol, _) => Some((byte, symbol.clone())),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn program_address_unit(&self) -> u32 {
+        2
+    }
+
+    fn register_alias(&self, name: &str) -> Option<Register> {
+        self.registers.get(&name.to_lowercase()).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_includes(name: &str) -> io::Result<String> {
+        Err(io::Error::new(io::ErrorKind::NotFound, name.to_string()))
+    }
+
+    #[test]
+    fn pointer_bytes_are_predefined() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.assemble_avrasm("test.asm", "ldi ZL, LOW(data*2)\nldi ZH, HIGH(data*2)\ndata: .db 1, 2\n", no_includes).unwrap();
+        assert_eq!(asm.buf, [0xe4, 0xe0, 0xf0, 0xe0, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn assembles_includes_macros_and_segments() {
+        let source = "\
+.include \"m.inc\"
+#define VALUE 3
+.macro set_port
+    ldi temp, @0
+    out PORTB, temp
+.endm
+.cseg
+.org 2
+start:
+    set_port VALUE
+    rjmp START
+.eseg
+.db 0xAA
+";
+        let include = |name: &str| match name {
+            "m.inc" => Ok(".equ PORTB = 0x05\n.def temp = r16\n".to_string()),
+            name => no_includes(name),
+        };
+        let mut asm = Assembler::new(Core::Avr5);
+        let eeprom = asm.assemble_avrasm("test.asm", source, include).unwrap();
+        assert_eq!(asm.buf, [0xff, 0xff, 0xff, 0xff, 0x03, 0xe0, 0x05, 0xb9, 0xfd, 0xcf]);
+        assert_eq!(eeprom.segments().collect::<Vec<_>>(), [(0, &[0xaa][..])]);
+    }
+
+    #[test]
+    fn errors_give_the_file_and_line() {
+        let include = |name: &str| match name {
+            "bad.inc" => Ok("nop\nldi r1, 0\n".to_string()),
+            name => no_includes(name),
+        };
+        let mut asm = Assembler::new(Core::Avr5);
+        let err = asm.assemble_avrasm("test.asm", ".include \"bad.inc\"\n", include).unwrap_err();
+        assert_eq!((&err.file[..], err.line), ("bad.inc", 2));
+    }
+
+    #[test]
+    fn data_segment_starts_at_the_device_sram() {
+        let source = ".device ATmega328P\n.dseg\nbuffer: .byte 4\nflag: .byte 1\n.cseg\nldi r16, HIGH(buffer)\nlds r17, flag\n";
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.assemble_avrasm("test.asm", source, no_includes).unwrap();
+        assert_eq!(asm.buf, [0x01, 0xe0, 0x10, 0x91, 0x04, 0x01]);
+
+        let source = ".dseg\nbuffer: .byte 4\n.cseg\nldi r16, HIGH(buffer)\n";
+        let mut asm = Assembler::for_device(&::ATMEGA328P);
+        asm.assemble_avrasm("test.asm", source, no_includes).unwrap();
+        let mut classic = Assembler::new(Core::Avr5);
+        classic.assemble_avrasm("test.asm", source, no_includes).unwrap();
+        assert_eq!((asm.buf, classic.buf), (vec![0x01, 0xe0], vec![0x00, 0xe0]));
+    }
+}
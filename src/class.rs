@@ -0,0 +1,306 @@
+// Registers that only some instructions accept, as types of their own, so that
+// passing `upper::R16` to `ldi` cannot fail. A plain `Register` or
+// `RegisterPair` converts with `TryFrom`, which checks the class when it runs,
+// and the instructions take one through `IntoClass` and check it the same way.
+
+use std::convert::{Infallible, TryFrom, TryInto};
+use std::ops::Add;
+
+use {Arg, Constraint, Direction, DirectionalRegisterPair, OffsetRegisterPair, Register, RegisterPair};
+
+/// One of R16 to R31, as `ldi`, `cpi`, `subi`, `sbci`, `andi`, `ori`, `sbr`,
+/// `cbr`, `ser` and `muls` take.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct UpperRegister(Register);
+
+/// One of R16 to R23, as `mulsu`, `fmul`, `fmuls` and `fmulsu` take.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MultiplyRegister(Register);
+
+/// R25:R24, X, Y or Z, as `adiw` and `sbiw` take.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct WordPair(RegisterPair);
+
+/// X, Y or Z, the pairs that `ld` and `st` go through.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PointerPair(RegisterPair);
+
+/// A register or pair that an instruction taking class `C` accepts: `C`
+/// itself, a class it converts into, or a plain `Register` or `RegisterPair`,
+/// whose class is checked when the instruction is emitted.
+pub trait IntoClass<C> {
+    fn into_class(self) -> Result<C, Constraint>;
+}
+
+impl<C, T> IntoClass<C> for T where T: TryInto<C>, Constraint: From<T::Error> {
+    fn into_class(self) -> Result<C, Constraint> {
+        self.try_into().map_err(Constraint::from)
+    }
+}
+
+// What converting a register to its own class, or a narrower one to a wider
+// one, fails with, which it never does
+impl From<Infallible> for Constraint {
+    fn from(never: Infallible) -> Constraint {
+        match never {}
+    }
+}
+
+impl UpperRegister {
+    pub fn register(self) -> Register {
+        self.0
+    }
+}
+
+impl MultiplyRegister {
+    pub fn register(self) -> Register {
+        self.0
+    }
+}
+
+impl WordPair {
+    pub fn pair(self) -> RegisterPair {
+        self.0
+    }
+}
+
+impl PointerPair {
+    pub fn pair(self) -> RegisterPair {
+        self.0
+    }
+
+    pub fn post_increment(self) -> DirectionalRegisterPair {
+        self.0.post_increment()
+    }
+
+    pub fn pre_decrement(self) -> DirectionalRegisterPair {
+        self.0.pre_decrement()
+    }
+}
+
+impl TryFrom<Register> for UpperRegister {
+    type Error = Constraint;
+
+    fn try_from(r: Register) -> Result<UpperRegister, Constraint> {
+        if 16 <= r.0 && r.0 < 32 {
+            Ok(UpperRegister(r))
+        } else {
+            Err(Constraint::RegisterRange { min: 16, max: 31 })
+        }
+    }
+}
+
+impl TryFrom<Register> for MultiplyRegister {
+    type Error = Constraint;
+
+    fn try_from(r: Register) -> Result<MultiplyRegister, Constraint> {
+        if 16 <= r.0 && r.0 < 24 {
+            Ok(MultiplyRegister(r))
+        } else {
+            Err(Constraint::RegisterRange { min: 16, max: 23 })
+        }
+    }
+}
+
+impl TryFrom<RegisterPair> for WordPair {
+    type Error = Constraint;
+
+    fn try_from(pair: RegisterPair) -> Result<WordPair, Constraint> {
+        let RegisterPair(high, low) = pair;
+        if high.0 != low.0 + 1 {
+            Err(Constraint::ConsecutivePair)
+        } else if low.0 % 2 == 1 {
+            Err(Constraint::EvenPair)
+        } else if low.0 < 24 {
+            Err(Constraint::PairRange { min: 24 })
+        } else {
+            Ok(WordPair(pair))
+        }
+    }
+}
+
+impl TryFrom<RegisterPair> for PointerPair {
+    type Error = Constraint;
+
+    fn try_from(pair: RegisterPair) -> Result<PointerPair, Constraint> {
+        if pair == ::X || pair == ::Y || pair == ::Z {
+            Ok(PointerPair(pair))
+        } else {
+            Err(Constraint::PointerMode)
+        }
+    }
+}
+
+impl From<UpperRegister> for Register {
+    fn from(r: UpperRegister) -> Register {
+        r.0
+    }
+}
+
+impl From<MultiplyRegister> for Register {
+    fn from(r: MultiplyRegister) -> Register {
+        r.0
+    }
+}
+
+impl From<MultiplyRegister> for UpperRegister {
+    fn from(r: MultiplyRegister) -> UpperRegister {
+        UpperRegister(r.0)
+    }
+}
+
+impl From<WordPair> for RegisterPair {
+    fn from(pair: WordPair) -> RegisterPair {
+        pair.0
+    }
+}
+
+impl From<PointerPair> for RegisterPair {
+    fn from(pair: PointerPair) -> RegisterPair {
+        pair.0
+    }
+}
+
+impl From<PointerPair> for WordPair {
+    fn from(pair: PointerPair) -> WordPair {
+        WordPair(pair.0)
+    }
+}
+
+impl From<PointerPair> for DirectionalRegisterPair {
+    fn from(pair: PointerPair) -> DirectionalRegisterPair {
+        DirectionalRegisterPair {
+            pair: pair.0,
+            direction: Direction::NoChange,
+        }
+    }
+}
+
+impl Add<u8> for PointerPair {
+    type Output = OffsetRegisterPair;
+
+    fn add(self, rhs: u8) -> OffsetRegisterPair {
+        self.0 + rhs
+    }
+}
+
+impl From<UpperRegister> for Arg {
+    fn from(r: UpperRegister) -> Arg {
+        Arg::Register(r.0)
+    }
+}
+
+impl From<MultiplyRegister> for Arg {
+    fn from(r: MultiplyRegister) -> Arg {
+        Arg::Register(r.0)
+    }
+}
+
+impl From<WordPair> for Arg {
+    fn from(pair: WordPair) -> Arg {
+        Arg::RegisterPair(pair.0)
+    }
+}
+
+/// R16 to R31 as `UpperRegister`s.
+pub mod upper {
+    use super::UpperRegister;
+
+    pub const R16: UpperRegister = UpperRegister(::R16);
+    pub const R17: UpperRegister = UpperRegister(::R17);
+    pub const R18: UpperRegister = UpperRegister(::R18);
+    pub const R19: UpperRegister = UpperRegister(::R19);
+    pub const R20: UpperRegister = UpperRegister(::R20);
+    pub const R21: UpperRegister = UpperRegister(::R21);
+    pub const R22: UpperRegister = UpperRegister(::R22);
+    pub const R23: UpperRegister = UpperRegister(::R23);
+    pub const R24: UpperRegister = UpperRegister(::R24);
+    pub const R25: UpperRegister = UpperRegister(::R25);
+    pub const R26: UpperRegister = UpperRegister(::R26);
+    pub const R27: UpperRegister = UpperRegister(::R27);
+    pub const R28: UpperRegister = UpperRegister(::R28);
+    pub const R29: UpperRegister = UpperRegister(::R29);
+    pub const R30: UpperRegister = UpperRegister(::R30);
+    pub const R31: UpperRegister = UpperRegister(::R31);
+}
+
+/// R16 to R23 as `MultiplyRegister`s.
+pub mod multiply {
+    use super::MultiplyRegister;
+
+    pub const R16: MultiplyRegister = MultiplyRegister(::R16);
+    pub const R17: MultiplyRegister = MultiplyRegister(::R17);
+    pub const R18: MultiplyRegister = MultiplyRegister(::R18);
+    pub const R19: MultiplyRegister = MultiplyRegister(::R19);
+    pub const R20: MultiplyRegister = MultiplyRegister(::R20);
+    pub const R21: MultiplyRegister = MultiplyRegister(::R21);
+    pub const R22: MultiplyRegister = MultiplyRegister(::R22);
+    pub const R23: MultiplyRegister = MultiplyRegister(::R23);
+}
+
+/// The pairs `adiw` and `sbiw` take, as `WordPair`s.
+pub mod word_pair {
+    use super::WordPair;
+
+    pub const R25_R24: WordPair = WordPair(::RegisterPair(::R25, ::R24));
+    pub const X: WordPair = WordPair(::X);
+    pub const Y: WordPair = WordPair(::Y);
+    pub const Z: WordPair = WordPair(::Z);
+}
+
+/// X, Y and Z as `PointerPair`s.
+pub mod pointer {
+    use super::PointerPair;
+
+    pub const X: PointerPair = PointerPair(::X);
+    pub const Y: PointerPair = PointerPair(::Y);
+    pub const Z: PointerPair = PointerPair(::Z);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AsmError, Assembler, Core, R15, R16, R22, R23, R24, R25, R26, R27};
+
+    #[test]
+    fn conversions_check_the_class() {
+        assert_eq!(UpperRegister::try_from(R15), Err(Constraint::RegisterRange { min: 16, max: 31 }));
+        assert_eq!(UpperRegister::try_from(R16), Ok(upper::R16));
+        assert_eq!(MultiplyRegister::try_from(R24), Err(Constraint::RegisterRange { min: 16, max: 23 }));
+        assert_eq!(MultiplyRegister::try_from(R23), Ok(multiply::R23));
+        assert_eq!(WordPair::try_from(RegisterPair(R24, R25)), Err(Constraint::ConsecutivePair));
+        assert_eq!(WordPair::try_from(RegisterPair(R26, R25)), Err(Constraint::EvenPair));
+        assert_eq!(WordPair::try_from(RegisterPair(R23, R22)), Err(Constraint::PairRange { min: 24 }));
+        assert_eq!(WordPair::try_from(RegisterPair(R25, R24)), Ok(word_pair::R25_R24));
+        assert_eq!(PointerPair::try_from(RegisterPair(R25, R24)), Err(Constraint::PointerMode));
+        assert_eq!(PointerPair::try_from(RegisterPair(R27, R26)), Ok(pointer::X));
+    }
+
+    #[test]
+    fn typed_and_plain_registers_encode_the_same() {
+        let mut typed = Assembler::new(Core::Avr5);
+        typed.ldi(upper::R16, 0xff);
+        typed.adiw(word_pair::R25_R24, 1);
+        typed.ld(R16, pointer::Z);
+        let mut plain = Assembler::new(Core::Avr5);
+        plain.ldi(R16, 0xff);
+        plain.adiw(RegisterPair(R25, R24), 1);
+        plain.ld(R16, ::Z);
+        assert_eq!(typed.buf, plain.buf);
+        assert_eq!(typed.buf, [0x0f, 0xef, 0x01, 0x96, 0x00, 0x81]);
+
+        match plain.try_ldi(R15, 0) {
+            Err(AsmError::InvalidOperand { instruction: "ldi", operand: "Rd", constraint: Constraint::RegisterRange { min: 16, max: 31 } }) => {}
+            result => panic!("{:?}", result),
+        }
+        match plain.try_fmul(R16, R24) {
+            Err(AsmError::InvalidOperand { instruction: "fmul", operand: "Rr", constraint: Constraint::RegisterRange { min: 16, max: 23 } }) => {}
+            result => panic!("{:?}", result),
+        }
+        match plain.try_sbiw(RegisterPair(R23, R22), 1) {
+            Err(AsmError::InvalidOperand { instruction: "sbiw", operand: "Rd", constraint: Constraint::PairRange { min: 24 } }) => {}
+            result => panic!("{:?}", result),
+        }
+        assert_eq!(plain.buf.len(), 6);
+    }
+}
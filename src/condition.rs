@@ -0,0 +1,230 @@
+// Status register flags and the branch conditions built on them, so that code
+// generators can pick and invert branches without a table of mnemonics, and
+// the bit numbers that the instructions testing and copying single bits take.
+
+use std::convert::TryFrom;
+
+use {Arg, AsmError, Assembler, Constraint, ProgramAddress};
+
+/// A bit of SREG, as `brbs`, `brbc`, `bset` and `bclr` take.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Flag {
+    /// Carry
+    C = 0,
+    /// Zero
+    Z = 1,
+    /// Negative
+    N = 2,
+    /// Two's complement overflow
+    V = 3,
+    /// Sign, N ^ V
+    S = 4,
+    /// Half carry
+    H = 5,
+    /// Transfer bit, which `bst` and `bld` copy to and from
+    T = 6,
+    /// Global interrupt enable
+    I = 7,
+}
+
+impl Flag {
+    /// The bit number in SREG.
+    pub fn bit(self) -> u32 {
+        self as u32
+    }
+}
+
+impl TryFrom<u32> for Flag {
+    type Error = Constraint;
+
+    fn try_from(bit: u32) -> Result<Flag, Constraint> {
+        match bit {
+            0 => Ok(Flag::C),
+            1 => Ok(Flag::Z),
+            2 => Ok(Flag::N),
+            3 => Ok(Flag::V),
+            4 => Ok(Flag::S),
+            5 => Ok(Flag::H),
+            6 => Ok(Flag::T),
+            7 => Ok(Flag::I),
+            _ => Err(Constraint::Range { min: 0, max: 7 }),
+        }
+    }
+}
+
+impl From<Flag> for Arg {
+    fn from(flag: Flag) -> Arg {
+        Arg::U32(flag.bit())
+    }
+}
+
+/// A bit of a register or an I/O register, as `bst`, `bld`, `sbrc`, `sbrs`,
+/// `sbi`, `cbi`, `sbic` and `sbis` take.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Bit {
+    B0 = 0,
+    B1 = 1,
+    B2 = 2,
+    B3 = 3,
+    B4 = 4,
+    B5 = 5,
+    B6 = 6,
+    B7 = 7,
+}
+
+impl Bit {
+    /// The bit number, from 0 for the least significant bit.
+    pub fn number(self) -> u32 {
+        self as u32
+    }
+
+    /// The byte with only this bit set.
+    pub fn mask(self) -> u32 {
+        1 << self.number()
+    }
+}
+
+impl TryFrom<u32> for Bit {
+    type Error = Constraint;
+
+    fn try_from(number: u32) -> Result<Bit, Constraint> {
+        match number {
+            0 => Ok(Bit::B0),
+            1 => Ok(Bit::B1),
+            2 => Ok(Bit::B2),
+            3 => Ok(Bit::B3),
+            4 => Ok(Bit::B4),
+            5 => Ok(Bit::B5),
+            6 => Ok(Bit::B6),
+            7 => Ok(Bit::B7),
+            _ => Err(Constraint::Range { min: 0, max: 7 }),
+        }
+    }
+}
+
+impl From<Bit> for Arg {
+    fn from(bit: Bit) -> Arg {
+        Arg::U32(bit.number())
+    }
+}
+
+/// The condition of a conditional branch, named as the `br*` mnemonics are.
+/// Some name the same test: `Lo` is `Cs` and `Sh` is `Cc`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Condition {
+    /// Equal, Z set
+    Eq,
+    /// Not equal, Z clear
+    Ne,
+    /// Unsigned lower, C set
+    Lo,
+    /// Unsigned same or higher, C clear
+    Sh,
+    /// Signed less than, S set
+    Lt,
+    /// Signed greater or equal, S clear
+    Ge,
+    /// Minus, N set
+    Mi,
+    /// Plus, N clear
+    Pl,
+    Cs,
+    Cc,
+    Hs,
+    Hc,
+    Ts,
+    Tc,
+    Vs,
+    Vc,
+    /// Interrupts enabled, I set
+    Ie,
+    /// Interrupts disabled, I clear
+    Id,
+}
+
+impl Condition {
+    /// The condition that holds exactly when this one does not.
+    pub fn invert(self) -> Condition {
+        match self {
+            Condition::Eq => Condition::Ne,
+            Condition::Ne => Condition::Eq,
+            Condition::Lo => Condition::Sh,
+            Condition::Sh => Condition::Lo,
+            Condition::Lt => Condition::Ge,
+            Condition::Ge => Condition::Lt,
+            Condition::Mi => Condition::Pl,
+            Condition::Pl => Condition::Mi,
+            Condition::Cs => Condition::Cc,
+            Condition::Cc => Condition::Cs,
+            Condition::Hs => Condition::Hc,
+            Condition::Hc => Condition::Hs,
+            Condition::Ts => Condition::Tc,
+            Condition::Tc => Condition::Ts,
+            Condition::Vs => Condition::Vc,
+            Condition::Vc => Condition::Vs,
+            Condition::Ie => Condition::Id,
+            Condition::Id => Condition::Ie,
+        }
+    }
+
+    /// The flag the condition tests.
+    pub fn flag(self) -> Flag {
+        match self {
+            Condition::Eq | Condition::Ne => Flag::Z,
+            Condition::Lo | Condition::Sh | Condition::Cs | Condition::Cc => Flag::C,
+            Condition::Lt | Condition::Ge => Flag::S,
+            Condition::Mi | Condition::Pl => Flag::N,
+            Condition::Hs | Condition::Hc => Flag::H,
+            Condition::Ts | Condition::Tc => Flag::T,
+            Condition::Vs | Condition::Vc => Flag::V,
+            Condition::Ie | Condition::Id => Flag::I,
+        }
+    }
+
+    /// Whether the condition holds when its flag is set, rather than clear.
+    pub fn when_set(self) -> bool {
+        matches!(self, Condition::Eq | Condition::Lo | Condition::Lt | Condition::Mi | Condition::Cs
+            | Condition::Hs | Condition::Ts | Condition::Vs | Condition::Ie)
+    }
+}
+
+impl Assembler {
+    pub fn branch<P: Into<ProgramAddress>>(&mut self, condition: Condition, k: P) {
+        self.try_branch(condition, k).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Branches if `condition` holds, with `brbs` or `brbc` on its flag, which
+    /// is the same instruction as the `br*` mnemonic for it.
+    pub fn try_branch<P: Into<ProgramAddress>>(&mut self, condition: Condition, k: P) -> Result<(), AsmError> {
+        if condition.when_set() {
+            self.try_brbs(condition.flag(), k)
+        } else {
+            self.try_brbc(condition.flag(), k)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Core, R16, R7};
+
+    #[test]
+    fn branch_uses_the_mnemonic_for_the_condition() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.branch(Condition::Eq, ProgramAddress::Relative(1));
+        asm.branch(Condition::Eq.invert(), ProgramAddress::Relative(1));
+        asm.branch(Condition::Ge, ProgramAddress::Relative(-1));
+        assert_eq!(asm.buf, [0x09, 0xf0, 0x09, 0xf4, 0xfc, 0xf7]);
+    }
+
+    #[test]
+    fn bit_operands_take_a_bit_number() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.bst(R16, Bit::B7);
+        asm.bld(R7, Bit::B0);
+        assert_eq!(asm.buf, [0x07, 0xfb, 0x70, 0xf8]);
+        assert_eq!(Bit::try_from(8), Err(Constraint::Range { min: 0, max: 7 }));
+        assert_eq!(Bit::try_from(5).map(Bit::mask), Ok(0x20));
+    }
+}
@@ -0,0 +1,250 @@
+// Structured control flow: conditionals and loops whose bodies are closures,
+// with the branches between them placed on fresh labels. Their branches and
+// jumps grow into long forms when a body turns out too large for the short
+// ones, whether branch relaxation is turned on or not. The `try_` forms take
+// bodies that return a `Result` and stop at the first error.
+
+use decode::decode_for;
+use relax::RJMP_FORMAT;
+use {AsmError, Assembler, Bit, Condition, Constraint, Fixup, IoAddress, Label, OffsetKind, Register, UpperRegister};
+
+impl Assembler {
+    pub fn if_cond<T, E>(&mut self, condition: Condition, then: T, otherwise: E)
+        where T: FnOnce(&mut Assembler), E: FnOnce(&mut Assembler)
+    {
+        self.try_if_cond(condition, |asm| { then(asm); Ok(()) }, |asm| { otherwise(asm); Ok(()) })
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Runs `then` if `condition` holds and `otherwise` if not: the opposite
+    /// branch to `otherwise`, `then`, and an `rjmp` over `otherwise`.
+    pub fn try_if_cond<T, E>(&mut self, condition: Condition, then: T, otherwise: E) -> Result<(), AsmError>
+        where T: FnOnce(&mut Assembler) -> Result<(), AsmError>, E: FnOnce(&mut Assembler) -> Result<(), AsmError>
+    {
+        self.check_not_skipped("if_cond", 2)?;
+        let otherwise_label = self.new_label();
+        let end = self.new_label();
+        self.growing(|asm| asm.try_branch(condition.invert(), otherwise_label))?;
+        then(self)?;
+        self.growing(|asm| asm.try_rjmp(end))?;
+        self.try_bind(otherwise_label)?;
+        otherwise(self)?;
+        self.try_bind(end)
+    }
+
+    pub fn if_then<T: FnOnce(&mut Assembler)>(&mut self, condition: Condition, then: T) {
+        self.try_if_then(condition, |asm| { then(asm); Ok(()) }).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Runs `then` if `condition` holds, branching over it if not.
+    pub fn try_if_then<T>(&mut self, condition: Condition, then: T) -> Result<(), AsmError>
+        where T: FnOnce(&mut Assembler) -> Result<(), AsmError>
+    {
+        // The branch, and a body of at least one instruction
+        self.check_not_skipped("if_then", 2)?;
+        let end = self.new_label();
+        self.growing(|asm| asm.try_branch(condition.invert(), end))?;
+        then(self)?;
+        self.try_bind(end)
+    }
+
+    pub fn loop_counted<B: FnOnce(&mut Assembler)>(&mut self, counter: UpperRegister, count: u32, body: B) {
+        self.try_loop_counted(counter, count, |asm| { body(asm); Ok(()) }).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Runs `body` `count` times, from 1 to 256, counting down in `counter`
+    /// with `ldi` before it and `dec` and `brne` after it. The body must leave
+    /// `counter` alone.
+    pub fn try_loop_counted<B>(&mut self, counter: UpperRegister, count: u32, body: B) -> Result<(), AsmError>
+        where B: FnOnce(&mut Assembler) -> Result<(), AsmError>
+    {
+        if !(1..=256).contains(&count) {
+            return Err(AsmError::invalid_operand("ldi", b'K', Constraint::Range { min: 1, max: 256 }));
+        }
+        self.check_not_skipped("loop_counted", 3)?;
+        self.try_ldi(counter, count & 0xff)?;
+        let top = self.new_label();
+        self.try_bind(top)?;
+        body(self)?;
+        self.try_dec(counter.register())?;
+        self.growing(|asm| asm.try_brne(top))
+    }
+
+    pub fn while_cond<T, B>(&mut self, test: T, condition: Condition, body: B)
+        where T: FnOnce(&mut Assembler), B: FnOnce(&mut Assembler)
+    {
+        self.try_while_cond(|asm| { test(asm); Ok(()) }, condition, |asm| { body(asm); Ok(()) })
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Runs `body` for as long as `condition` holds after `test`, which sets
+    /// the flags, such as with a `cp`. The test goes after the body, with an
+    /// `rjmp` to it first, so each round takes one branch.
+    pub fn try_while_cond<T, B>(&mut self, test: T, condition: Condition, body: B) -> Result<(), AsmError>
+        where T: FnOnce(&mut Assembler) -> Result<(), AsmError>, B: FnOnce(&mut Assembler) -> Result<(), AsmError>
+    {
+        self.check_not_skipped("while_cond", 2)?;
+        let top = self.new_label();
+        let test_label = self.new_label();
+        self.growing(|asm| asm.try_rjmp(test_label))?;
+        self.try_bind(top)?;
+        body(self)?;
+        self.try_bind(test_label)?;
+        test(self)?;
+        self.growing(|asm| asm.try_branch(condition, top))
+    }
+
+    pub fn if_bit_set<B: FnOnce(&mut Assembler)>(&mut self, r: Register, bit: Bit, body: B) {
+        self.try_if_bit_set(r, bit, |asm| { body(asm); Ok(()) }).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Runs `body` if bit `bit` of `r` is set: behind an `sbrc` if it is a
+    /// single instruction, and otherwise behind an `sbrs` and an `rjmp` over it.
+    pub fn try_if_bit_set<B>(&mut self, r: Register, bit: Bit, body: B) -> Result<(), AsmError>
+        where B: FnOnce(&mut Assembler) -> Result<(), AsmError>
+    {
+        self.skip_block(true, body, |asm, when_set| if when_set { asm.try_sbrs(r, bit) } else { asm.try_sbrc(r, bit) })
+    }
+
+    pub fn if_bit_clear<B: FnOnce(&mut Assembler)>(&mut self, r: Register, bit: Bit, body: B) {
+        self.try_if_bit_clear(r, bit, |asm| { body(asm); Ok(()) }).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Runs `body` if bit `bit` of `r` is clear, as `try_if_bit_set` does if it is set.
+    pub fn try_if_bit_clear<B>(&mut self, r: Register, bit: Bit, body: B) -> Result<(), AsmError>
+        where B: FnOnce(&mut Assembler) -> Result<(), AsmError>
+    {
+        self.skip_block(false, body, |asm, when_set| if when_set { asm.try_sbrs(r, bit) } else { asm.try_sbrc(r, bit) })
+    }
+
+    pub fn if_io_bit_set<I: Into<IoAddress>, B: FnOnce(&mut Assembler)>(&mut self, a: I, bit: Bit, body: B) {
+        self.try_if_io_bit_set(a, bit, |asm| { body(asm); Ok(()) }).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Runs `body` if bit `bit` of the I/O register at `a`, in the first 32
+    /// bytes of I/O space, is set, with `sbic` or `sbis` as `try_if_bit_set`
+    /// uses `sbrc` or `sbrs`.
+    pub fn try_if_io_bit_set<I: Into<IoAddress>, B>(&mut self, a: I, bit: Bit, body: B) -> Result<(), AsmError>
+        where B: FnOnce(&mut Assembler) -> Result<(), AsmError>
+    {
+        let a = a.into();
+        self.skip_block(true, body, |asm, when_set| if when_set { asm.try_sbis(a, bit) } else { asm.try_sbic(a, bit) })
+    }
+
+    pub fn if_io_bit_clear<I: Into<IoAddress>, B: FnOnce(&mut Assembler)>(&mut self, a: I, bit: Bit, body: B) {
+        self.try_if_io_bit_clear(a, bit, |asm| { body(asm); Ok(()) }).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Runs `body` if bit `bit` of the I/O register at `a` is clear.
+    pub fn try_if_io_bit_clear<I: Into<IoAddress>, B>(&mut self, a: I, bit: Bit, body: B) -> Result<(), AsmError>
+        where B: FnOnce(&mut Assembler) -> Result<(), AsmError>
+    {
+        let a = a.into();
+        self.skip_block(false, body, |asm, when_set| if when_set { asm.try_sbis(a, bit) } else { asm.try_sbic(a, bit) })
+    }
+
+    // Emits `body`, then puts in front of it the skip instruction that `skip`
+    // emits, skipping if the bit is set or clear as asked. A body of a single
+    // instruction that cannot grow is skipped over; any other is jumped over,
+    // with the skip guarding the jump instead.
+    fn skip_block<B, S>(&mut self, run_when_set: bool, body: B, skip: S) -> Result<(), AsmError>
+        where B: FnOnce(&mut Assembler) -> Result<(), AsmError>, S: FnOnce(&mut Assembler, bool) -> Result<(), AsmError>
+    {
+        self.check_not_skipped("skip block", 2)?;
+        let position = self.buf.len();
+        let address = self.address();
+        let staying: Vec<Label> = (0..self.labels.len())
+            .filter(|&index| self.labels[index].address == Some(address))
+            .map(Label)
+            .collect();
+        body(self)?;
+        let body_end = self.buf.len();
+        let body_skipping = self.skipping;
+        if body_end == position {
+            return Ok(());
+        }
+
+        let single = decode_for(self.core(), &self.buf[position..], address).len() == 1
+            && !self.fixups.iter().any(|fixup| fixup.position >= position && self.relaxable(fixup));
+        skip(self, if single { !run_when_set } else { run_when_set })?;
+        let mut bytes = self.buf.split_off(body_end);
+        let end = self.new_label();
+        if !single {
+            bytes.extend_from_slice(&[0x00, 0xc0]);
+        }
+        self.insert(position, &bytes, &staying)?;
+        if single {
+            for fixup in self.fixups.iter_mut().filter(|fixup| fixup.position > position) {
+                fixup.skipped = true;
+            }
+        } else {
+            self.fixups.push(Fixup {
+                instruction: "rjmp",
+                position: position + 2,
+                label: end,
+                kind: OffsetKind::Relative,
+                format: RJMP_FORMAT,
+                field: b'k',
+                grows: true,
+                skipped: true,
+            });
+        }
+        self.reapply_fixups()?;
+        self.relax_branches()?;
+        // The skip went in front, so what follows comes right after the body
+        self.skipping = body_skipping;
+        self.try_bind(end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {upper, Core, R16, R17};
+
+    #[test]
+    fn if_then_branches_over_the_body() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.if_then(Condition::Eq, |asm| asm.nop());
+        assert_eq!(asm.buf, [0x09, 0xf4, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn if_bit_set_skips_a_single_instruction_and_jumps_over_more() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.if_bit_set(R16, Bit::B3, |asm| asm.nop());
+        asm.if_bit_set(R16, Bit::B3, |asm| { asm.nop(); asm.nop(); });
+        assert_eq!(asm.buf, [0x03, 0xfd, 0x00, 0x00, 0x03, 0xff, 0x02, 0xc0, 0x00, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn skip_block_keeps_a_skip_the_body_ends_with() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.if_bit_set(R16, Bit::B3, |asm| asm.sbrs(R17, Bit::B1));
+        assert_eq!(asm.skipping(), Some("sbrs"));
+        match asm.try_if_bit_set(R16, Bit::B3, |asm| asm.try_nop()) {
+            Err(AsmError::SkippedSequence { skip: "sbrs", .. }) => {}
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn try_builders_pass_on_errors_from_the_body() {
+        let mut asm = Assembler::new(Core::Avr5);
+        let result = asm.try_if_then(Condition::Eq, |asm| {
+            let label = asm.new_label();
+            asm.try_bind(label)?;
+            asm.try_bind(label)
+        });
+        match result {
+            Err(AsmError::LabelAlreadyBound { .. }) => {}
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn loop_counted_rejects_a_count_of_zero() {
+        let mut asm = Assembler::new(Core::Avr5);
+        assert!(asm.try_loop_counted(upper::R16, 0, |_| Ok(())).is_err());
+    }
+}
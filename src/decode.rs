@@ -0,0 +1,362 @@
+use std::fmt;
+
+use {Core, Direction, Feature, Register, RegisterPair, X, Y, Z};
+use {LD_FORMS, ST_FORMS, LPM_FORMS, ELPM_FORMS, LDD_FORMS, STD_FORMS};
+
+/// An instruction read back out of machine code.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Instruction {
+    /// Byte address of the first byte of the instruction.
+    pub address: u32,
+    /// Length of the instruction in bytes.
+    pub size: u32,
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+    /// For a skip instruction, the length in bytes of the instruction it
+    /// skips, if that was decoded too. Skipping it takes one more cycle when
+    /// it is two words long.
+    pub skipped: Option<u32>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Operand {
+    Register(Register),
+    RegisterPair(RegisterPair),
+    Immediate(u32),
+    /// Branch distance in 16-bit words, counted from the end of the instruction.
+    Relative(i32),
+    /// Byte address in program or data memory.
+    Address(u32),
+    Pointer(RegisterPair, Direction),
+    Displacement(RegisterPair, u32),
+}
+
+// What an instruction field decodes to
+#[derive(Copy, Clone)]
+enum OperandKind {
+    Register(u8),
+    RegisterPair(u8),
+    Unsigned(u8),
+    Relative(u8),
+    Absolute(u8),
+    AbsoluteDoubles(u8),
+    ReducedData(u8),
+    ImplicitZ,
+    Pointer(RegisterPair, Direction),
+    Displacement(RegisterPair, u8),
+}
+
+struct InstructionForm {
+    mnemonic: &'static str,
+    operands: &'static [OperandKind],
+    template: &'static [u8],
+    feature: Option<Feature>,
+}
+
+include!(concat!(env!("OUT_DIR"), "/decode_table.rs"));
+
+// An instruction form prepared for matching against machine code
+struct Candidate {
+    mnemonic: &'static str,
+    operands: Vec<OperandKind>,
+    template: &'static [u8],
+    bits: usize,
+    mask: u32,
+    pattern: u32,
+    // Higher wins when several forms match the same code, so that a name
+    // that fixes an operand, such as `breq` for `brbs 1`, is preferred
+    precedence: u32,
+}
+
+impl Candidate {
+    fn new(mnemonic: &'static str, operands: Vec<OperandKind>, template: &'static [u8]) -> Candidate {
+        let mut bits = 0;
+        let mut mask = 0;
+        let mut pattern = 0;
+        for &ch in template.iter().rev().filter(|&&ch| ch != b' ') {
+            match ch {
+                b'0' => { mask |= 1 << bits; }
+                b'1' => { mask |= 1 << bits; pattern |= 1 << bits; }
+                _ => {}
+            }
+            bits += 1;
+        }
+
+        Candidate {
+            mnemonic,
+            operands,
+            template,
+            bits,
+            mask,
+            pattern,
+            precedence: mask.count_ones(),
+        }
+    }
+
+    fn decode(&self, code: u32) -> Option<Vec<Operand>> {
+        if code & self.mask != self.pattern {
+            return None;
+        }
+
+        let mut values = [0u32; 256];
+        let mut counts = [0usize; 256];
+        for (bit, &ch) in self.template.iter().rev().filter(|&&ch| ch != b' ').enumerate() {
+            if ch != b'0' && ch != b'1' {
+                values[ch as usize] |= ((code >> bit) & 1) << counts[ch as usize];
+                counts[ch as usize] += 1;
+            }
+        }
+
+        Some(self.operands.iter().map(|kind| {
+            match *kind {
+                OperandKind::Register(ch) => {
+                    let value = values[ch as usize];
+                    match counts[ch as usize] {
+                        5 => Operand::Register(Register(value)),
+                        _ => Operand::Register(Register(16 + value)),
+                    }
+                }
+                OperandKind::RegisterPair(ch) => {
+                    let low = 32 - 2 * (1 << counts[ch as usize]) + 2 * values[ch as usize];
+                    Operand::RegisterPair(RegisterPair(Register(low + 1), Register(low)))
+                }
+                OperandKind::Unsigned(ch) => Operand::Immediate(values[ch as usize]),
+                OperandKind::Relative(ch) => {
+                    let unused = 32 - counts[ch as usize];
+                    Operand::Relative(((values[ch as usize] << unused) as i32) >> unused)
+                }
+                OperandKind::Absolute(ch) => Operand::Address(values[ch as usize]),
+                OperandKind::AbsoluteDoubles(ch) => Operand::Address(values[ch as usize] * 2),
+                OperandKind::ReducedData(ch) => Operand::Address(reduced_data_address(values[ch as usize])),
+                OperandKind::ImplicitZ => Operand::RegisterPair(Z),
+                OperandKind::Pointer(pair, direction) => Operand::Pointer(pair, direction),
+                OperandKind::Displacement(pair, ch) => Operand::Displacement(pair, values[ch as usize]),
+            }
+        }).collect())
+    }
+}
+
+// Undoes the scrambling of the reduced core's lds and sts, whose field holds
+// address bits 5, 4, 6, 3, 2, 1, 0, with bit 7 the inverse of bit 6
+fn reduced_data_address(field: u32) -> u32 {
+    let address = (field & 0x60) >> 1 | (field & 0x10) << 2 | field & 0x0f;
+    if address & 0x40 == 0 { address | 0x80 } else { address }
+}
+
+// The forms of every instruction for which `has` gives true of the feature it needs
+fn candidates<F: Fn(Feature) -> bool>(has: F) -> Vec<Candidate> {
+    let has = |feature: Option<Feature>| feature.is_none_or(&has);
+    let mut candidates: Vec<Candidate> = INSTRUCTION_FORMS.iter()
+        .filter(|form| has(form.feature))
+        .map(|form| Candidate::new(form.mnemonic, form.operands.to_vec(), form.template))
+        .collect();
+
+    // Only Z without an increment can be used without SRAM
+    let pointer_feature = |pair, direction| if pair == Z && direction == Direction::NoChange { None } else { Some(Feature::Sram) };
+    for &(pair, direction, template) in LD_FORMS.iter().filter(|&&(pair, direction, _)| has(pointer_feature(pair, direction))) {
+        candidates.push(Candidate::new("ld", vec![OperandKind::Register(b'd'), OperandKind::Pointer(pair, direction)], template));
+    }
+    for &(pair, direction, template) in ST_FORMS.iter().filter(|&&(pair, direction, _)| has(pointer_feature(pair, direction))) {
+        candidates.push(Candidate::new("st", vec![OperandKind::Pointer(pair, direction), OperandKind::Register(b'r')], template));
+    }
+    for &(pair, direction, template) in LPM_FORMS.iter().filter(|_| has(Some(Feature::LpmX))) {
+        candidates.push(Candidate::new("lpm", vec![OperandKind::Register(b'd'), OperandKind::Pointer(pair, direction)], template));
+    }
+    for &(pair, direction, template) in ELPM_FORMS.iter().filter(|_| has(Some(Feature::ElpmX))) {
+        candidates.push(Candidate::new("elpm", vec![OperandKind::Register(b'd'), OperandKind::Pointer(pair, direction)], template));
+    }
+    for &(pair, template) in LDD_FORMS.iter().filter(|_| has(Some(Feature::Adiw))) {
+        candidates.push(Candidate::new("ldd", vec![OperandKind::Register(b'd'), OperandKind::Displacement(pair, b'q')], template));
+    }
+    for &(pair, template) in STD_FORMS.iter().filter(|_| has(Some(Feature::Adiw))) {
+        candidates.push(Candidate::new("std", vec![OperandKind::Displacement(pair, b'q'), OperandKind::Register(b'r')], template));
+    }
+
+    candidates
+}
+
+/// Decodes machine code that starts at byte address `base_address`.
+///
+/// Words that are not a valid instruction come out as `.word`, and a trailing
+/// odd byte as `.byte`, each with the raw value as an immediate operand.
+///
+/// Every core's instructions are recognised but the reduced core's one-word
+/// `lds` and `sts`, whose encodings are `ldd` and `std` on the others. Use
+/// `decode_for` to decode for the reduced core.
+pub fn decode(bytes: &[u8], base_address: u32) -> Vec<Instruction> {
+    decode_with(&candidates(|feature| feature != Feature::Reduced), bytes, base_address)
+}
+
+/// Decodes machine code for `core`, as `decode` does, recognising only the
+/// instructions the core has. On the reduced core that reads one-word `lds`
+/// and `sts`, rather than the `ldd` and `std` it lacks.
+pub fn decode_for(core: Core, bytes: &[u8], base_address: u32) -> Vec<Instruction> {
+    decode_with(&candidates(|feature| core.has(feature)), bytes, base_address)
+}
+
+fn decode_with(candidates: &[Candidate], bytes: &[u8], base_address: u32) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+
+    let mut position = 0;
+    while position < bytes.len() {
+        let address = base_address + position as u32;
+        if position + 1 == bytes.len() {
+            instructions.push(Instruction {
+                address,
+                size: 1,
+                mnemonic: ".byte",
+                operands: vec![Operand::Immediate(bytes[position] as u32)],
+                skipped: None,
+            });
+            break;
+        }
+
+        let first = read_word(bytes, position);
+        let second = if position + 3 < bytes.len() { Some(read_word(bytes, position + 2)) } else { None };
+
+        let mut best: Option<(&Candidate, Vec<Operand>)> = None;
+        for candidate in candidates.iter() {
+            let code = match (candidate.bits, second) {
+                (16, _) => first,
+                (32, Some(second)) => (first << 16) | second,
+                _ => continue,
+            };
+            if let Some(operands) = candidate.decode(code) {
+                let better = match best {
+                    Some((current, _)) => candidate.precedence > current.precedence,
+                    None => true,
+                };
+                if better {
+                    best = Some((candidate, operands));
+                }
+            }
+        }
+
+        let instruction = match best {
+            Some((candidate, operands)) => Instruction {
+                address,
+                size: candidate.bits as u32 / 8,
+                mnemonic: candidate.mnemonic,
+                operands,
+                skipped: None,
+            },
+            None => Instruction {
+                address,
+                size: 2,
+                mnemonic: ".word",
+                operands: vec![Operand::Immediate(first)],
+                skipped: None,
+            },
+        };
+        position += instruction.size as usize;
+        instructions.push(instruction);
+    }
+
+    for index in 1..instructions.len() {
+        if is_skip(instructions[index - 1].mnemonic) {
+            instructions[index - 1].skipped = Some(instructions[index].size);
+        }
+    }
+    instructions
+}
+
+// Whether an instruction skips the one after it when its condition holds
+pub(crate) fn is_skip(mnemonic: &str) -> bool {
+    matches!(mnemonic, "cpse" | "sbrc" | "sbrs" | "sbic" | "sbis")
+}
+
+fn read_word(bytes: &[u8], position: usize) -> u32 {
+    (bytes[position] as u32) | ((bytes[position + 1] as u32) << 8)
+}
+
+fn pointer_name(pair: RegisterPair) -> String {
+    if pair == X {
+        "X".to_string()
+    } else if pair == Y {
+        "Y".to_string()
+    } else if pair == Z {
+        "Z".to_string()
+    } else {
+        format!("{}:{}", pair.0, pair.1)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::Register(r) => write!(f, "{}", r),
+            Operand::RegisterPair(pair) => write!(f, "{}", pointer_name(pair)),
+            Operand::Immediate(x) => write!(f, "0x{:02X}", x),
+            Operand::Relative(words) => write!(f, ".{:+}", words * 2),
+            Operand::Address(x) => write!(f, "0x{:04X}", x),
+            Operand::Pointer(pair, Direction::NoChange) => write!(f, "{}", pointer_name(pair)),
+            Operand::Pointer(pair, Direction::PostIncrement) => write!(f, "{}+", pointer_name(pair)),
+            Operand::Pointer(pair, Direction::PreDecrement) => write!(f, "-{}", pointer_name(pair)),
+            Operand::Displacement(pair, q) => write!(f, "{}+{}", pointer_name(pair), q),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mnemonic)?;
+        for (index, operand) in self.operands.iter().enumerate() {
+            write!(f, "{}{}", if index == 0 { " " } else { ", " }, operand)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Assembler, Bit, DataAddress, ProgramAddress, R0, R20};
+
+    fn text(instructions: &[Instruction]) -> Vec<String> {
+        instructions.iter().map(|instruction| instruction.to_string()).collect()
+    }
+
+    #[test]
+    fn shorthands_decode_as_avr_objdump_prints_them() {
+        let bytes = [0x11, 0x24, 0x0f, 0xef, 0x88, 0x0f, 0x09, 0xf0, 0x08, 0x94, 0x00, 0xf0];
+        assert_eq!(text(&decode(&bytes, 0)), ["eor r1, r1", "ldi r16, 0xFF", "add r24, r24", "breq .+2", "sec", "brcs .+0"]);
+    }
+
+    #[test]
+    fn decode_for_the_reduced_core_reads_one_word_lds_and_sts() {
+        let mut asm = Assembler::new(Core::AvrTiny);
+        asm.lds(R20, DataAddress(0x40));
+        asm.sts(DataAddress(0xbf), R20);
+        assert_eq!(text(&decode_for(Core::AvrTiny, &asm.buf, 0)), ["lds r20, 0x0040", "sts 0x00BF, r20"]);
+        assert_eq!(text(&decode(&asm.buf[..2], 0)), ["ldd r20, Z+32"]);
+        assert_eq!(text(&decode_for(Core::Avr5, &asm.buf[..2], 0)), ["ldd r20, Z+32"]);
+    }
+
+    #[test]
+    fn assembled_code_decodes_back_to_its_instructions() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.sbrs(R20, Bit::B3);
+        asm.call(ProgramAddress::Absolute(0x1234));
+        asm.ld(R0, X.post_increment());
+        asm.std(Y + 5, R20);
+        asm.emit_bytes(&[0xff, 0xff, 0x42]);
+        let instructions = decode(&asm.buf, 0x100);
+        let summary: Vec<_> = instructions.iter()
+            .map(|instruction| (instruction.address, instruction.size, instruction.mnemonic, instruction.skipped))
+            .collect();
+        assert_eq!(summary, [
+            (0x100, 2, "sbrs", Some(4)),
+            (0x102, 4, "call", None),
+            (0x106, 2, "ld", None),
+            (0x108, 2, "std", None),
+            (0x10a, 2, ".word", None),
+            (0x10c, 1, ".byte", None),
+        ]);
+        assert_eq!(instructions[0].operands, [Operand::Register(R20), Operand::Immediate(3)]);
+        assert_eq!(instructions[1].operands, [Operand::Address(0x2468)]);
+        assert_eq!(instructions[2].operands, [Operand::Register(R0), Operand::Pointer(X, Direction::PostIncrement)]);
+        assert_eq!(instructions[3].operands, [Operand::Displacement(Y, 5), Operand::Register(R20)]);
+        assert_eq!(instructions[4].operands, [Operand::Immediate(0xffff)]);
+        assert_eq!(instructions[5].operands, [Operand::Immediate(0x42)]);
+    }
+}
@@ -0,0 +1,190 @@
+// Busy-waiting for an exact number of cycles, as avr-gcc's
+// `__builtin_avr_delay_cycles` does: counted loops for the bulk of the time
+// and single instructions for what is left.
+
+use std::convert::TryFrom;
+
+use {AsmError, Assembler, Constraint, Feature, ProgramAddress, RegisterGroup, RegisterPair, WordPair};
+
+// Below this many cycles, `rjmp .+0` and `nop` take no more words than a loop
+const LOOP_THRESHOLD: u64 = 9;
+
+// The widest counter a loop uses; longer delays run several loops
+const MAX_WIDTH: u32 = 4;
+
+// The fewest bytes a loop takes: an `ldi`, a `dec` or `sbiw` and a `brne`
+const MIN_LOOP_BYTES: u32 = 6;
+
+// The flash that 22-bit word addresses reach, for an assembler without a device
+const MAX_FLASH: u32 = 8 * 1024 * 1024;
+
+// Cycles of a loop with a `width`-byte counter going round `iterations`
+// times: an `ldi` per byte, then a count down taking a cycle per byte and two
+// for the `brne`, which takes one when it falls through
+fn loop_cycles(width: u32, iterations: u64) -> u64 {
+    width as u64 + (width as u64 + 2) * iterations - 1
+}
+
+fn max_iterations(width: u32) -> u64 {
+    1 << (8 * width)
+}
+
+impl Assembler {
+    pub fn delay_cycles(&mut self, cycles: u64, scratch: RegisterGroup) {
+        self.try_delay_cycles(cycles, scratch).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Emits code that takes exactly `cycles` cycles and changes nothing but
+    /// `scratch` and the flags. It counts down in as few bytes of `scratch` as
+    /// the delay needs, up to four, with `dec` for one byte, `sbiw` for two on
+    /// cores with it when they make a pair it takes, and `subi` and `sbci`
+    /// otherwise, each followed by a `brne`. What the loops leave over is
+    /// made up with `rjmp .+0`, two cycles in one word, and a `nop`. Delays
+    /// that need a loop need `scratch` in R16..R31. A delay whose loops
+    /// cannot fit in the rest of flash fails with `OutsideFlash`.
+    pub fn try_delay_cycles(&mut self, cycles: u64, scratch: RegisterGroup) -> Result<(), AsmError> {
+        let widest = scratch.bytes().min(MAX_WIDTH);
+        let flash_size = self.device().map_or(MAX_FLASH, |device| device.flash_size);
+        let room = flash_size.saturating_sub(self.address());
+        let mut loops = Vec::new();
+        let mut remaining = cycles;
+        while remaining >= LOOP_THRESHOLD {
+            if (loops.len() as u32 + 1) * MIN_LOOP_BYTES > room {
+                return Err(AsmError::OutsideFlash { address: self.address(), flash_size });
+            }
+            // The narrowest loop that takes the rest in one go, or else the widest
+            let width = (1..widest).find(|&width| remaining <= loop_cycles(width, max_iterations(width))).unwrap_or(widest);
+            let iterations = ((remaining - (width as u64 - 1)) / (width as u64 + 2)).min(max_iterations(width));
+            loops.push((width, iterations));
+            remaining -= loop_cycles(width, iterations);
+        }
+        // A loop is at least an `ldi`, a count down and a `brne`
+        let instructions = if loops.is_empty() { remaining / 2 + remaining % 2 } else { 3 };
+        self.check_not_skipped("delay_cycles", instructions as u32)?;
+
+        for (width, iterations) in loops {
+            self.delay_loop(scratch, width, iterations)?;
+        }
+        while remaining >= 2 {
+            self.try_rjmp(ProgramAddress::Relative(0))?;
+            remaining -= 2;
+        }
+        if remaining == 1 {
+            self.try_nop()?;
+        }
+        Ok(())
+    }
+
+    pub fn delay_us(&mut self, us: u64, f_cpu: u64, scratch: RegisterGroup) {
+        self.try_delay_us(us, f_cpu, scratch).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Waits `us` microseconds at a clock of `f_cpu` Hz, rounding up to a
+    /// whole number of cycles, with `try_delay_cycles`.
+    pub fn try_delay_us(&mut self, us: u64, f_cpu: u64, scratch: RegisterGroup) -> Result<(), AsmError> {
+        let cycles = us.checked_mul(f_cpu)
+            .ok_or_else(|| {
+                let max = (u64::MAX / f_cpu).min(i64::MAX as u64) as i64;
+                AsmError::invalid_operand("delay_us", b'u', Constraint::Range { min: 0, max })
+            })?
+            .div_ceil(1_000_000);
+        self.try_delay_cycles(cycles, scratch)
+    }
+
+    // One loop going round `iterations` times, from 1 up to and including
+    // `max_iterations(width)`, which counts down from 0
+    fn delay_loop(&mut self, scratch: RegisterGroup, width: u32, iterations: u64) -> Result<(), AsmError> {
+        let count = iterations % max_iterations(width);
+        for index in 0..width {
+            self.try_ldi(scratch.byte(index), (count >> (8 * index)) as u32 & 0xff)?;
+        }
+        let top = self.new_label();
+        self.try_bind(top)?;
+        let word_pair = WordPair::try_from(RegisterPair(scratch.byte(1.min(width - 1)), scratch.byte(0)));
+        match (width, word_pair) {
+            (1, _) => self.try_dec(scratch.byte(0))?,
+            (2, Ok(pair)) if self.core().has(Feature::Adiw) => self.try_sbiw(pair, 1)?,
+            _ => {
+                self.try_subi(scratch.byte(0), 1)?;
+                for index in 1..width {
+                    self.try_sbci(scratch.byte(index), 0)?;
+                }
+            }
+        }
+        self.try_brne(top)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {upper, word_pair, Bit, Core, R24, R5};
+
+    #[test]
+    fn a_skip_may_come_before_a_delay_of_one_instruction() {
+        let scratch = RegisterGroup::new(R24, 2).unwrap();
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.sbrs(R5, Bit::B0);
+        asm.delay_cycles(2, scratch);
+        asm.sbrs(R5, Bit::B0);
+        assert!(asm.try_delay_cycles(3, scratch).is_err());
+        assert!(asm.try_delay_cycles(20, scratch).is_err());
+    }
+
+    #[test]
+    fn short_delays_are_rjmp_and_nop() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.delay_cycles(3, RegisterGroup::new(R24, 1).unwrap());
+        assert_eq!(asm.buf, [0x00, 0xc0, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn longer_delays_count_down_in_as_few_bytes_as_they_need() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.delay_cycles(10, RegisterGroup::new(R24, 2).unwrap());
+        asm.delay_cycles(1000, RegisterGroup::new(R24, 2).unwrap());
+
+        let mut expected = Assembler::new(Core::Avr5);
+        let top = expected.new_label();
+        expected.ldi(upper::R24, 3);
+        expected.bind(top);
+        expected.dec(R24);
+        expected.brne(top);
+        expected.nop();
+        let top = expected.new_label();
+        expected.ldi(upper::R24, 249);
+        expected.ldi(upper::R25, 0);
+        expected.bind(top);
+        expected.sbiw(word_pair::R25_R24, 1);
+        expected.brne(top);
+        expected.rjmp(ProgramAddress::Relative(0));
+        expected.nop();
+        assert_eq!(asm.buf, expected.buf);
+    }
+
+    #[test]
+    fn delay_us_rounds_up_and_rejects_overflow() {
+        let scratch = RegisterGroup::new(R24, 1).unwrap();
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.delay_us(1, 2_500_000, scratch);
+        assert_eq!(asm.buf, [0x00, 0xc0, 0x00, 0x00]);
+        match asm.try_delay_us(u64::MAX, 16_000_000, scratch) {
+            Err(AsmError::InvalidOperand { instruction: "delay_us", constraint: Constraint::Range { min: 0, max }, .. }) => {
+                assert_eq!(max, (u64::MAX / 16_000_000) as i64);
+            }
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn delays_too_long_for_flash_fail_at_once() {
+        let mut asm = Assembler::new(Core::Avr5);
+        assert_eq!(asm.try_delay_cycles(u64::MAX, RegisterGroup::new(R24, 1).unwrap()),
+            Err(AsmError::OutsideFlash { address: 0, flash_size: MAX_FLASH }));
+        let mut asm = Assembler::for_device(&::ATTINY85);
+        asm.nop();
+        assert_eq!(asm.try_delay_cycles(u64::MAX, RegisterGroup::new(R24, 4).unwrap()),
+            Err(AsmError::OutsideFlash { address: 2, flash_size: 8192 }));
+        assert_eq!(asm.buf.len(), 2);
+    }
+}
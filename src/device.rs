@@ -0,0 +1,231 @@
+use Core;
+
+/// The memories and interrupt vectors of one AVR part.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Device {
+    pub name: &'static str,
+    pub core: Core,
+    /// Bytes of flash.
+    pub flash_size: u32,
+    /// Data address of the first byte of internal SRAM.
+    pub sram_start: u32,
+    /// Bytes of internal SRAM.
+    pub sram_size: u32,
+    /// Bytes of EEPROM.
+    pub eeprom_size: u32,
+    /// Bytes in a flash page, the unit that `spm` erases and writes.
+    pub page_size: u32,
+    pub boot_section: BootSection,
+    /// Interrupt vectors, including reset.
+    pub vector_count: u32,
+    /// Bytes per vector: 4 where each holds a `jmp`, 2 where each holds an `rjmp`.
+    pub vector_size: u32,
+    /// The data address that flash is mapped at, and how many bytes of it, on
+    /// parts that map flash into data space.
+    pub mapped_flash: Option<(u32, u32)>,
+    /// Whether the part can address external SRAM above the internal one, up to 64 KiB.
+    pub external_sram: bool,
+}
+
+/// The sizes that fuses can give the boot section, at the end of flash on
+/// classic parts and at the start on AVRxt parts.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BootSection {
+    /// The part has no separate boot section.
+    None,
+    /// One of these sizes in bytes, smallest first.
+    Sizes(&'static [u32]),
+    /// Any whole number of blocks of this many bytes.
+    Blocks(u32),
+}
+
+impl Device {
+    /// The data address after the last byte of internal SRAM.
+    pub fn sram_end(&self) -> u32 {
+        self.sram_start + self.sram_size
+    }
+
+    /// Bytes taken by the interrupt vector table at the start of flash.
+    pub fn vector_table_size(&self) -> u32 {
+        self.vector_count * self.vector_size
+    }
+
+    /// Whether `lds` and `sts` can reach a data address: registers, I/O and
+    /// SRAM, external SRAM, and mapped flash.
+    pub fn in_data_space(&self, address: u32) -> bool {
+        address < self.sram_end()
+            || self.external_sram && address < 0x10000
+            || self.mapped_flash.is_some_and(|(start, size)| (start..start + size).contains(&address))
+    }
+
+    /// Looks up a built-in device by name, in any case.
+    pub fn from_name(name: &str) -> Option<&'static Device> {
+        DEVICES.iter().cloned().find(|device| device.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Every built-in device.
+pub static DEVICES: [&Device; 8] = [
+    &ATMEGA328P, &ATMEGA2560, &ATMEGA32U4, &ATTINY85, &ATTINY13A, &ATTINY10, &ATTINY1614, &AVR128DA,
+];
+
+pub static ATMEGA328P: Device = Device {
+    name: "ATmega328P",
+    core: Core::Avr5,
+    flash_size: 32 * 1024,
+    sram_start: 0x100,
+    sram_size: 2 * 1024,
+    eeprom_size: 1024,
+    page_size: 128,
+    boot_section: BootSection::Sizes(&[512, 1024, 2048, 4096]),
+    vector_count: 26,
+    vector_size: 4,
+    mapped_flash: None,
+    external_sram: false,
+};
+
+pub static ATMEGA2560: Device = Device {
+    name: "ATmega2560",
+    core: Core::Avr6,
+    flash_size: 256 * 1024,
+    sram_start: 0x200,
+    sram_size: 8 * 1024,
+    eeprom_size: 4 * 1024,
+    page_size: 256,
+    boot_section: BootSection::Sizes(&[1024, 2048, 4096, 8192]),
+    vector_count: 57,
+    vector_size: 4,
+    mapped_flash: None,
+    external_sram: true,
+};
+
+pub static ATMEGA32U4: Device = Device {
+    name: "ATmega32U4",
+    core: Core::Avr5,
+    flash_size: 32 * 1024,
+    sram_start: 0x100,
+    sram_size: 2560,
+    eeprom_size: 1024,
+    page_size: 128,
+    boot_section: BootSection::Sizes(&[512, 1024, 2048, 4096]),
+    vector_count: 43,
+    vector_size: 4,
+    mapped_flash: None,
+    external_sram: false,
+};
+
+pub static ATTINY85: Device = Device {
+    name: "ATtiny85",
+    core: Core::Avr25,
+    flash_size: 8 * 1024,
+    sram_start: 0x60,
+    sram_size: 512,
+    eeprom_size: 512,
+    page_size: 64,
+    boot_section: BootSection::None,
+    vector_count: 15,
+    vector_size: 2,
+    mapped_flash: None,
+    external_sram: false,
+};
+
+pub static ATTINY13A: Device = Device {
+    name: "ATtiny13A",
+    core: Core::Avr25,
+    flash_size: 1024,
+    sram_start: 0x60,
+    sram_size: 64,
+    eeprom_size: 64,
+    page_size: 32,
+    boot_section: BootSection::None,
+    vector_count: 10,
+    vector_size: 2,
+    mapped_flash: None,
+    external_sram: false,
+};
+
+pub static ATTINY10: Device = Device {
+    name: "ATtiny10",
+    core: Core::AvrTiny,
+    flash_size: 1024,
+    sram_start: 0x40,
+    sram_size: 32,
+    eeprom_size: 0,
+    page_size: 16,
+    boot_section: BootSection::None,
+    vector_count: 11,
+    vector_size: 2,
+    mapped_flash: Some((0x4000, 1024)),
+    external_sram: false,
+};
+
+pub static ATTINY1614: Device = Device {
+    name: "ATtiny1614",
+    core: Core::Xmega3,
+    flash_size: 16 * 1024,
+    sram_start: 0x3800,
+    sram_size: 2 * 1024,
+    eeprom_size: 256,
+    page_size: 64,
+    boot_section: BootSection::Blocks(256),
+    vector_count: 31,
+    vector_size: 4,
+    mapped_flash: Some((0x8000, 16 * 1024)),
+    external_sram: false,
+};
+
+/// The AVR128DA28, 32, 48 and 64, which share their memories.
+pub static AVR128DA: Device = Device {
+    name: "AVR128DA",
+    core: Core::AvrXt,
+    flash_size: 128 * 1024,
+    sram_start: 0x4000,
+    sram_size: 16 * 1024,
+    eeprom_size: 512,
+    page_size: 512,
+    boot_section: BootSection::Blocks(512),
+    vector_count: 64,
+    vector_size: 4,
+    // A 32 KiB window, which `FLMAP` in NVMCTRL moves through flash
+    mapped_flash: Some((0x8000, 32 * 1024)),
+    external_sram: false,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AsmError, Assembler, DataAddress, MemoryImage, ProgramAddress, R16, Z};
+
+    #[test]
+    fn devices_are_found_in_any_case() {
+        assert_eq!(Device::from_name("atmega328p"), Some(&ATMEGA328P));
+        assert_eq!(Device::from_name("ATmega328"), None);
+        assert_eq!(ATMEGA328P.vector_table_size(), 104);
+        assert!(ATMEGA328P.in_data_space(0x8ff) && !ATMEGA328P.in_data_space(0x900));
+    }
+
+    #[test]
+    fn assembler_keeps_to_the_device_memories() {
+        let mut asm = Assembler::for_device(&ATMEGA328P);
+        assert_eq!(asm.try_lds(R16, DataAddress(0x900)), Err(AsmError::OutsideDevice { instruction: "lds", address: 0x900, device: "ATmega328P" }));
+        assert!(asm.try_jmp(ProgramAddress::Absolute(0x4000)).is_err());
+        asm.jmp(ProgramAddress::Absolute(0x3fff));
+
+        let mut asm = Assembler::with_image_for_device(MemoryImage::new(), 0x7ffe, &ATMEGA328P);
+        asm.nop();
+        assert_eq!(asm.try_nop(), Err(AsmError::OutsideFlash { address: 0x8000, flash_size: 0x8000 }));
+    }
+
+    #[test]
+    fn avrxt_parts_lack_des_and_the_read_modify_write_instructions() {
+        for &device in [&ATTINY1614, &AVR128DA].iter() {
+            let mut asm = Assembler::for_device(device);
+            assert_eq!(asm.try_des(0), Err(AsmError::UnsupportedInstruction { instruction: "des", core: device.core }));
+            assert_eq!(asm.try_xch(Z, R16), Err(AsmError::UnsupportedInstruction { instruction: "xch", core: device.core }));
+        }
+        let mut asm = Assembler::for_device(&AVR128DA);
+        asm.elpm(R16, Z.post_increment());
+        asm.spm_z_plus();
+        assert_eq!(asm.buf, [0x07, 0x91, 0xf8, 0x95]);
+    }
+}
@@ -0,0 +1,749 @@
+use std::io::{self, Write};
+
+use {field_bits, patch_field, ArgConsumer, AsmError, Assembler, Label, Linkage, OffsetKind};
+
+const EM_AVR: u16 = 83;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_WRITE: u32 = 1;
+const SHF_ALLOC: u32 = 2;
+const SHF_EXECINSTR: u32 = 4;
+const SHF_INFO_LINK: u32 = 0x40;
+
+const PT_LOAD: u32 = 1;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_SECTION: u8 = 3;
+
+const HEADER_SIZE: u32 = 52;
+const PROGRAM_HEADER_SIZE: u32 = 32;
+const SECTION_HEADER_SIZE: u32 = 40;
+const SYMBOL_SIZE: u32 = 16;
+const RELA_SIZE: u32 = 12;
+
+/// The memories of an AVR, each of which avr-gcc places at its own range of
+/// virtual addresses.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MemorySection {
+    Text,
+    Data,
+    Eeprom,
+    Fuse,
+    Lock,
+    Signature,
+}
+
+impl MemorySection {
+    pub fn name(self) -> &'static str {
+        match self {
+            MemorySection::Text => ".text",
+            MemorySection::Data => ".data",
+            MemorySection::Eeprom => ".eeprom",
+            MemorySection::Fuse => ".fuse",
+            MemorySection::Lock => ".lock",
+            MemorySection::Signature => ".signature",
+        }
+    }
+
+    /// Where address 0 of this memory appears in the ELF file.
+    pub fn virtual_address_base(self) -> u32 {
+        match self {
+            MemorySection::Text => 0,
+            MemorySection::Data => 0x80_0000,
+            MemorySection::Eeprom => 0x81_0000,
+            MemorySection::Fuse => 0x82_0000,
+            MemorySection::Lock => 0x83_0000,
+            MemorySection::Signature => 0x84_0000,
+        }
+    }
+
+    fn flags(self) -> u32 {
+        match self {
+            MemorySection::Text => SHF_ALLOC | SHF_EXECINSTR,
+            MemorySection::Signature => SHF_ALLOC,
+            _ => SHF_ALLOC | SHF_WRITE,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ElfSymbol {
+    pub name: String,
+    pub section: MemorySection,
+    /// Address within the section's own memory, e.g. an SRAM address for `Data`.
+    pub address: u32,
+}
+
+/// An ELF executable, as avr-gcc would link it.
+///
+/// Sections left empty are not written. `.data` is given a load address right
+/// after `.text`, where startup code expects to copy it from.
+#[derive(Clone, Debug, Default)]
+pub struct Executable {
+    pub text: Vec<u8>,
+    /// Flash byte address of the start of `text`.
+    pub text_address: u32,
+    pub data: Vec<u8>,
+    /// SRAM address of the start of `data`.
+    pub data_address: u32,
+    pub eeprom: Vec<u8>,
+    pub fuse: Vec<u8>,
+    pub lock: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub symbols: Vec<ElfSymbol>,
+    /// The `e_flags` field, which holds the avr-gcc architecture number, e.g. 5 for avr5.
+    pub flags: u32,
+}
+
+impl Executable {
+    pub fn new() -> Executable {
+        Executable {
+            data_address: 0x100,
+            flags: 5,
+            ..Default::default()
+        }
+    }
+
+    /// Takes `.text` from everything the assembler holds, including a loaded
+    /// image, a symbol from each bound named label, and `flags` from its core.
+    /// Fails if any referenced label was never bound.
+    pub fn from_assembler(assembler: &Assembler) -> Result<Executable, AsmError> {
+        let mut executable = Executable::new();
+        executable.flags = assembler.core().elf_flags();
+        let image = assembler.image()?;
+        if let Some((start, end)) = image.bounds() {
+            executable.text = image.to_contiguous(start, end, 0xff);
+            executable.text_address = start;
+        }
+        for (name, address) in assembler.symbols() {
+            executable.symbols.push(ElfSymbol {
+                name: name.to_string(),
+                section: MemorySection::Text,
+                address,
+            });
+        }
+        Ok(executable)
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        // `.data` is loaded from flash, so its load address has no virtual address base
+        let text_end = self.text_address + self.text.len() as u32;
+        let memories = [
+            (MemorySection::Text, &self.text, self.text_address, None),
+            (MemorySection::Data, &self.data, self.data_address, Some(text_end)),
+            (MemorySection::Eeprom, &self.eeprom, 0, None),
+            (MemorySection::Fuse, &self.fuse, 0, None),
+            (MemorySection::Lock, &self.lock, 0, None),
+            (MemorySection::Signature, &self.signature, 0, None),
+        ];
+
+        let mut sections = Vec::new();
+        let mut segments = Vec::new();
+        for &(memory, bytes, address, load_address) in memories.iter() {
+            if bytes.is_empty() {
+                continue;
+            }
+            let base = memory.virtual_address_base();
+            let flags = memory.flags();
+            segments.push(Segment {
+                section: sections.len() + 1,
+                virtual_address: base + address,
+                physical_address: load_address.unwrap_or(base + address),
+                flags: PF_R | if flags & SHF_WRITE != 0 { PF_W } else { 0 } | if flags & SHF_EXECINSTR != 0 { PF_X } else { 0 },
+            });
+            sections.push(Section {
+                name: memory.name(),
+                kind: SHT_PROGBITS,
+                flags,
+                address: base + address,
+                data: bytes.clone(),
+                link: 0,
+                info: 0,
+                align: if memory == MemorySection::Text { 2 } else { 1 },
+                entry_size: 0,
+            });
+        }
+
+        // Symbols in sections that are not written are dropped
+        let mut strings = StringTable::new();
+        let mut symbol_table = vec![0; SYMBOL_SIZE as usize];
+        for symbol in self.symbols.iter() {
+            if let Some(index) = sections.iter().position(|section| section.name == symbol.section.name()) {
+                let kind = if symbol.section == MemorySection::Text { STT_NOTYPE } else { STT_OBJECT };
+                let value = symbol.section.virtual_address_base() + symbol.address;
+                push_symbol(&mut symbol_table, strings.add(&symbol.name), value, 0, (STB_GLOBAL << 4) | kind, index as u16 + 1);
+            }
+        }
+
+        let string_table_index = sections.len() as u32 + 2;
+        sections.push(Section {
+            name: ".symtab",
+            kind: SHT_SYMTAB,
+            flags: 0,
+            address: 0,
+            data: symbol_table,
+            link: string_table_index,
+            // Every symbol is global, so the first global is right after the null symbol
+            info: 1,
+            align: 4,
+            entry_size: SYMBOL_SIZE,
+        });
+        sections.push(Section {
+            name: ".strtab",
+            kind: SHT_STRTAB,
+            flags: 0,
+            address: 0,
+            data: strings.bytes,
+            link: 0,
+            info: 0,
+            align: 1,
+            entry_size: 0,
+        });
+
+        write_elf(out, ET_EXEC, self.flags, &sections, &segments)
+    }
+}
+
+/// How the linker fills in an instruction field, named after the `R_AVR_*`
+/// relocation types of binutils.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RelocationKind {
+    /// `R_AVR_7_PCREL`: the word offset of a conditional branch.
+    Pcrel7,
+    /// `R_AVR_13_PCREL`: the word offset of `rjmp` or `rcall`.
+    Pcrel13,
+    /// `R_AVR_16`: a 16-bit byte address, as in `lds` and `sts`.
+    Absolute16,
+    /// `R_AVR_LDS_STS_16`: the 7-bit address of the reduced core's one-word `lds` and `sts`.
+    LdsSts16,
+    /// `R_AVR_CALL`: the 22-bit word address of `call` or `jmp`.
+    Call,
+    /// `R_AVR_LO8_LDI`: bits 0-7 of a byte address, loaded by `ldi`.
+    Lo8Ldi,
+    /// `R_AVR_HI8_LDI`: bits 8-15 of a byte address, loaded by `ldi`.
+    Hi8Ldi,
+    /// `R_AVR_LO8_LDI_PM`: bits 0-7 of a word address, loaded by `ldi`.
+    Lo8LdiPm,
+    /// `R_AVR_HI8_LDI_PM`: bits 8-15 of a word address, loaded by `ldi`.
+    Hi8LdiPm,
+}
+
+impl RelocationKind {
+    /// The relocation type number written to the ELF file.
+    pub fn number(self) -> u32 {
+        match self {
+            RelocationKind::Pcrel7 => 2,
+            RelocationKind::Pcrel13 => 3,
+            RelocationKind::Absolute16 => 4,
+            RelocationKind::Lo8Ldi => 6,
+            RelocationKind::Hi8Ldi => 7,
+            RelocationKind::Lo8LdiPm => 12,
+            RelocationKind::Hi8LdiPm => 13,
+            RelocationKind::Call => 18,
+            RelocationKind::LdsSts16 => 33,
+        }
+    }
+
+    // The relocation for a field of `bits` bits that holds an offset of this kind
+    fn for_field(kind: OffsetKind, bits: usize) -> RelocationKind {
+        match (kind, bits) {
+            (OffsetKind::Relative, 7) => RelocationKind::Pcrel7,
+            (OffsetKind::Relative, 12) => RelocationKind::Pcrel13,
+            (OffsetKind::Absolute, 16) => RelocationKind::Absolute16,
+            (OffsetKind::ReducedData, _) => RelocationKind::LdsSts16,
+            (OffsetKind::AbsoluteDoubles, 22) => RelocationKind::Call,
+            (OffsetKind::Lo8, _) => RelocationKind::Lo8Ldi,
+            (OffsetKind::Hi8, _) => RelocationKind::Hi8Ldi,
+            (OffsetKind::PmLo8, _) => RelocationKind::Lo8LdiPm,
+            (OffsetKind::PmHi8, _) => RelocationKind::Hi8LdiPm,
+            (kind, bits) => panic!("no relocation for a {}-bit {:?} field", bits, kind),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Relocation {
+    /// Byte offset within `.text` of the word the field starts in, which is
+    /// the instruction's start except for the second word of `lds` and `sts`.
+    pub offset: u32,
+    pub kind: RelocationKind,
+    /// The symbol the address is taken from, or `None` for the start of `.text`.
+    pub symbol: Option<String>,
+    /// Bytes added to the symbol's address.
+    pub addend: i32,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ObjectSymbol {
+    pub name: String,
+    /// Byte offset within `.text`, or `None` for a symbol defined in another object file.
+    pub address: Option<u32>,
+    /// Whether other object files can refer to the symbol. Undefined symbols are always global.
+    pub global: bool,
+}
+
+/// An ELF relocatable object, as avr-as would assemble it, for linking with
+/// avr-gcc or avr-ld.
+#[derive(Clone, Debug, Default)]
+pub struct RelocatableObject {
+    /// Code that is placed by the linker, so it starts at offset 0.
+    pub text: Vec<u8>,
+    pub relocations: Vec<Relocation>,
+    pub symbols: Vec<ObjectSymbol>,
+    /// The `e_flags` field, which holds the avr-gcc architecture number, e.g. 5 for avr5.
+    pub flags: u32,
+}
+
+impl RelocatableObject {
+    pub fn new() -> RelocatableObject {
+        RelocatableObject {
+            flags: 5,
+            ..Default::default()
+        }
+    }
+
+    /// Takes `.text` from `buf`, ignoring any loaded image and the origin, and
+    /// `flags` from the assembler's core.
+    ///
+    /// Every named label becomes a symbol: local unless it was exported, and
+    /// undefined if it is external. References to external labels, and
+    /// absolute references to any label, become relocations. Branches to
+    /// labels defined here are already resolved and need none. An exported
+    /// label that was never bound is an `UnboundLabel` error, as is any
+    /// other unbound label that is referred to.
+    pub fn from_assembler(assembler: &Assembler) -> Result<RelocatableObject, AsmError> {
+        let mut object = RelocatableObject::new();
+        object.flags = assembler.core().elf_flags();
+        object.text = assembler.buf.clone();
+
+        for (index, state) in assembler.labels.iter().enumerate() {
+            if let Some(ref name) = state.name {
+                let address = state.address.map(|address| address - assembler.origin);
+                if address.is_none() && state.linkage == Linkage::Global {
+                    return Err(AsmError::UnboundLabel { label: Label(index), name: state.name.clone() });
+                }
+                if address.is_some() || state.linkage == Linkage::External {
+                    object.symbols.push(ObjectSymbol {
+                        name: name.clone(),
+                        address,
+                        global: state.linkage != Linkage::Local,
+                    });
+                }
+            }
+        }
+
+        for fixup in assembler.fixups.iter() {
+            let state = &assembler.labels[fixup.label.0];
+            let (symbol, addend) = match (state.linkage, state.address) {
+                (Linkage::External, _) => (state.name.clone(), 0),
+                (_, None) => return Err(AsmError::UnboundLabel { label: fixup.label, name: state.name.clone() }),
+                (_, Some(_)) if matches!(fixup.kind, OffsetKind::Relative) => continue,
+                (_, Some(address)) => (None, (address - assembler.origin) as i32),
+            };
+            let bits = field_bits(fixup.format, fixup.field);
+            object.relocations.push(Relocation {
+                offset: (fixup.position + field_offset(fixup.format, fixup.field)) as u32,
+                kind: RelocationKind::for_field(fixup.kind, bits),
+                symbol,
+                addend,
+            });
+            // The linker ORs some fields in, so they must start out clear
+            patch_field(&mut object.text, fixup.position, fixup.format, fixup.field, ArgConsumer { value: 0 });
+        }
+
+        Ok(object)
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut sections = vec![Section {
+            name: ".text",
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC | SHF_EXECINSTR,
+            address: 0,
+            data: self.text.clone(),
+            link: 0,
+            info: 0,
+            align: 2,
+            entry_size: 0,
+        }];
+
+        // The symbol for .text comes first, then locals, as ELF requires them
+        // to come before globals
+        let mut strings = StringTable::new();
+        let mut symbol_table = vec![0; SYMBOL_SIZE as usize];
+        push_symbol(&mut symbol_table, 0, 0, 0, (STB_LOCAL << 4) | STT_SECTION, 1);
+        let mut ordered: Vec<&ObjectSymbol> = self.symbols.iter().filter(|symbol| !symbol.global && symbol.address.is_some()).collect();
+        let first_global = ordered.len() as u32 + 2;
+        ordered.extend(self.symbols.iter().filter(|symbol| symbol.global || symbol.address.is_none()));
+        for symbol in ordered.iter() {
+            let binding = if symbol.global || symbol.address.is_none() { STB_GLOBAL } else { STB_LOCAL };
+            let section = if symbol.address.is_some() { 1 } else { 0 };
+            push_symbol(&mut symbol_table, strings.add(&symbol.name), symbol.address.unwrap_or(0), 0, (binding << 4) | STT_NOTYPE, section);
+        }
+
+        let mut relocation_table = Vec::new();
+        for relocation in self.relocations.iter() {
+            let symbol_index = match relocation.symbol {
+                Some(ref name) => {
+                    let position = ordered.iter().position(|symbol| &symbol.name == name);
+                    match position {
+                        Some(position) => position as u32 + 2,
+                        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("relocation refers to unknown symbol `{}`", name))),
+                    }
+                }
+                None => 1,
+            };
+            push_u32(&mut relocation_table, relocation.offset);
+            push_u32(&mut relocation_table, (symbol_index << 8) | relocation.kind.number());
+            push_u32(&mut relocation_table, relocation.addend as u32);
+        }
+
+        let has_relocations = !relocation_table.is_empty();
+        let symbol_table_index = if has_relocations { 3 } else { 2 };
+        if has_relocations {
+            sections.push(Section {
+                name: ".rela.text",
+                kind: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                address: 0,
+                data: relocation_table,
+                link: symbol_table_index,
+                info: 1,
+                align: 4,
+                entry_size: RELA_SIZE,
+            });
+        }
+        sections.push(Section {
+            name: ".symtab",
+            kind: SHT_SYMTAB,
+            flags: 0,
+            address: 0,
+            data: symbol_table,
+            link: symbol_table_index + 1,
+            info: first_global,
+            align: 4,
+            entry_size: SYMBOL_SIZE,
+        });
+        sections.push(Section {
+            name: ".strtab",
+            kind: SHT_STRTAB,
+            flags: 0,
+            address: 0,
+            data: strings.bytes,
+            link: 0,
+            info: 0,
+            align: 1,
+            entry_size: 0,
+        });
+
+        write_elf(out, ET_REL, self.flags, &sections, &[])
+    }
+}
+
+// A section to be written, other than the null section and the section name table
+struct Section {
+    name: &'static str,
+    kind: u32,
+    flags: u32,
+    address: u32,
+    data: Vec<u8>,
+    link: u32,
+    info: u32,
+    align: u32,
+    entry_size: u32,
+}
+
+// A loadable segment covering exactly one section
+struct Segment {
+    // Index in the section header table
+    section: usize,
+    virtual_address: u32,
+    physical_address: u32,
+    flags: u32,
+}
+
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> StringTable {
+        StringTable { bytes: vec![0] }
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(name.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
+
+fn push_symbol(table: &mut Vec<u8>, name: u32, value: u32, size: u32, info: u8, section: u16) {
+    push_u32(table, name);
+    push_u32(table, value);
+    push_u32(table, size);
+    table.push(info);
+    table.push(0);
+    push_u16(table, section);
+}
+
+fn push_u16(buf: &mut Vec<u8>, x: u16) {
+    buf.extend_from_slice(&[x as u8, (x >> 8) as u8]);
+}
+
+fn push_u32(buf: &mut Vec<u8>, x: u32) {
+    buf.extend_from_slice(&[x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8]);
+}
+
+// Bytes from the start of an instruction to the word its field starts in,
+// where the linker writes the field. That is the first word for the 22 bits
+// of `call` and `jmp`, as R_AVR_CALL expects.
+fn field_offset(format: &[u8], field: u8) -> usize {
+    let bit = format.iter().filter(|&&b| b != b' ').position(|&b| b == field).expect("format has no such field");
+    bit / 16 * 2
+}
+
+fn align_to(buf: &mut Vec<u8>, align: u32) {
+    while !(buf.len() as u32).is_multiple_of(align) {
+        buf.push(0);
+    }
+}
+
+// Lays out a complete ELF file: header, program headers, section contents,
+// the section name table, then the section headers.
+fn write_elf<W: Write>(out: &mut W, elf_type: u16, flags: u32, sections: &[Section], segments: &[Segment]) -> io::Result<()> {
+    let mut names = StringTable::new();
+    let name_offsets: Vec<u32> = sections.iter().map(|section| names.add(section.name)).collect();
+    let shstrtab_name = names.add(".shstrtab");
+
+    let mut body = Vec::new();
+    let body_start = HEADER_SIZE + PROGRAM_HEADER_SIZE * segments.len() as u32;
+    let mut offsets = Vec::new();
+    for section in sections.iter() {
+        align_to(&mut body, section.align.max(1));
+        offsets.push(body_start + body.len() as u32);
+        body.extend_from_slice(&section.data);
+    }
+    let shstrtab_offset = body_start + body.len() as u32;
+    body.extend_from_slice(&names.bytes);
+    align_to(&mut body, 4);
+    let section_headers_offset = body_start + body.len() as u32;
+
+    let mut file = Vec::new();
+    file.extend_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    push_u16(&mut file, elf_type);
+    push_u16(&mut file, EM_AVR);
+    push_u32(&mut file, 1);
+    push_u32(&mut file, 0);
+    push_u32(&mut file, if segments.is_empty() { 0 } else { HEADER_SIZE });
+    push_u32(&mut file, section_headers_offset);
+    push_u32(&mut file, flags);
+    push_u16(&mut file, HEADER_SIZE as u16);
+    push_u16(&mut file, PROGRAM_HEADER_SIZE as u16);
+    push_u16(&mut file, segments.len() as u16);
+    push_u16(&mut file, SECTION_HEADER_SIZE as u16);
+    push_u16(&mut file, sections.len() as u16 + 2);
+    push_u16(&mut file, sections.len() as u16 + 1);
+
+    for segment in segments.iter() {
+        let section = &sections[segment.section - 1];
+        push_u32(&mut file, PT_LOAD);
+        push_u32(&mut file, offsets[segment.section - 1]);
+        push_u32(&mut file, segment.virtual_address);
+        push_u32(&mut file, segment.physical_address);
+        push_u32(&mut file, section.data.len() as u32);
+        push_u32(&mut file, section.data.len() as u32);
+        push_u32(&mut file, segment.flags);
+        push_u32(&mut file, section.align);
+    }
+
+    file.extend_from_slice(&body);
+
+    file.extend_from_slice(&[0; SECTION_HEADER_SIZE as usize]);
+    for (index, section) in sections.iter().enumerate() {
+        push_u32(&mut file, name_offsets[index]);
+        push_u32(&mut file, section.kind);
+        push_u32(&mut file, section.flags);
+        push_u32(&mut file, section.address);
+        push_u32(&mut file, offsets[index]);
+        push_u32(&mut file, section.data.len() as u32);
+        push_u32(&mut file, section.link);
+        push_u32(&mut file, section.info);
+        push_u32(&mut file, section.align);
+        push_u32(&mut file, section.entry_size);
+    }
+    push_u32(&mut file, shstrtab_name);
+    push_u32(&mut file, SHT_STRTAB);
+    push_u32(&mut file, 0);
+    push_u32(&mut file, 0);
+    push_u32(&mut file, shstrtab_offset);
+    push_u32(&mut file, names.bytes.len() as u32);
+    push_u32(&mut file, 0);
+    push_u32(&mut file, 0);
+    push_u32(&mut file, 1);
+    push_u32(&mut file, 0);
+
+    out.write_all(&file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Core, R16};
+
+    #[test]
+    fn relocations_point_at_the_word_holding_the_field() {
+        let mut asm = Assembler::new(Core::Avr5);
+        let var = asm.external_label("var");
+        let function = asm.external_label("function");
+        asm.lds(R16, var);
+        asm.call(function);
+        asm.rjmp(function);
+        let object = RelocatableObject::from_assembler(&asm).unwrap();
+        let relocations: Vec<_> = object.relocations.iter().map(|relocation| (relocation.offset, relocation.kind)).collect();
+        assert_eq!(relocations, [
+            (2, RelocationKind::Absolute16),
+            (4, RelocationKind::Call),
+            (8, RelocationKind::Pcrel13),
+        ]);
+    }
+
+    fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+        bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+    }
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+    }
+
+    #[test]
+    fn executable_loads_data_after_text() {
+        let mut executable = Executable::new();
+        executable.text = vec![0xff, 0xcf];
+        executable.data = vec![1, 2, 3];
+        let mut file = Vec::new();
+        executable.write(&mut file).unwrap();
+
+        assert_eq!(&file[..4], b"\x7fELF");
+        assert_eq!(read_u16(&file, 16), ET_EXEC);
+        assert_eq!(read_u16(&file, 18), EM_AVR);
+        assert_eq!(read_u32(&file, 36), 5);
+        assert_eq!(read_u16(&file, 44), 2);
+        let data_header = read_u32(&file, 28) as usize + PROGRAM_HEADER_SIZE as usize;
+        assert_eq!(read_u32(&file, data_header), PT_LOAD);
+        assert_eq!(read_u32(&file, data_header + 8), 0x80_0100);
+        assert_eq!(read_u32(&file, data_header + 12), 2);
+        assert_eq!(read_u32(&file, data_header + 16), 3);
+        let offset = read_u32(&file, data_header + 4) as usize;
+        assert_eq!(&file[offset..offset + 3], [1, 2, 3]);
+    }
+
+    #[test]
+    fn executable_needs_every_label_bound() {
+        let mut asm = Assembler::new(Core::Avr5);
+        let label = asm.named_label("missing");
+        asm.rjmp(label);
+        match Executable::from_assembler(&asm) {
+            Err(AsmError::UnboundLabel { name: Some(ref name), .. }) if name == "missing" => {}
+            result => panic!("{:?}", result.map(|executable| executable.text)),
+        }
+        asm.bind(label);
+        let executable = Executable::from_assembler(&asm).unwrap();
+        assert_eq!(executable.text, [0x00, 0xc0]);
+        assert_eq!(executable.symbols[0].address, 2);
+    }
+
+    // Finds a section header by the name given in the section name table
+    fn section_header(file: &[u8], name: &str) -> usize {
+        let headers = read_u32(file, 32) as usize;
+        assert_eq!(read_u16(file, 46) as u32, SECTION_HEADER_SIZE);
+        let count = read_u16(file, 48) as usize;
+        let names = headers + read_u16(file, 50) as usize * SECTION_HEADER_SIZE as usize;
+        let names_offset = read_u32(file, names + 16) as usize;
+        (0..count).map(|index| headers + index * SECTION_HEADER_SIZE as usize).find(|&header| {
+            let start = names_offset + read_u32(file, header) as usize;
+            file[start..].split(|&b| b == 0).next().unwrap() == name.as_bytes()
+        }).unwrap_or_else(|| panic!("no section {}", name))
+    }
+
+    #[test]
+    fn object_symbols_and_relocations_read_back() {
+        let mut asm = Assembler::new(Core::Avr5);
+        let main = asm.named_label("main");
+        let puts = asm.external_label("puts");
+        let again = asm.named_label("again");
+        asm.export(main);
+        asm.bind(main);
+        asm.nop();
+        asm.bind(again);
+        asm.call(puts);
+        asm.rjmp(again);
+        let mut file = Vec::new();
+        RelocatableObject::from_assembler(&asm).unwrap().write(&mut file).unwrap();
+
+        assert_eq!(read_u16(&file, 16), ET_REL);
+        assert_eq!(read_u16(&file, 18), EM_AVR);
+        let text = section_header(&file, ".text");
+        assert_eq!(read_u32(&file, text + 20), 8);
+        let symtab = section_header(&file, ".symtab");
+        let strtab = section_header(&file, ".strtab");
+        assert_eq!(read_u32(&file, symtab + 4), SHT_SYMTAB);
+        assert_eq!(read_u32(&file, strtab + 4), SHT_STRTAB);
+        let headers = read_u32(&file, 32) as usize;
+        assert_eq!(headers + read_u32(&file, symtab + 24) as usize * SECTION_HEADER_SIZE as usize, strtab);
+        assert_eq!(read_u32(&file, symtab + 28), 3);
+
+        let symbols_offset = read_u32(&file, symtab + 16) as usize;
+        let strings_offset = read_u32(&file, strtab + 16) as usize;
+        let count = read_u32(&file, symtab + 20) / SYMBOL_SIZE;
+        let symbols: Vec<_> = (0..count as usize).map(|index| {
+            let symbol = symbols_offset + index * SYMBOL_SIZE as usize;
+            let start = strings_offset + read_u32(&file, symbol) as usize;
+            let name = String::from_utf8(file[start..].split(|&b| b == 0).next().unwrap().to_vec()).unwrap();
+            (name, read_u32(&file, symbol + 4), file[symbol + 12] >> 4, read_u16(&file, symbol + 14))
+        }).collect();
+        assert_eq!(symbols, [
+            (String::new(), 0, STB_LOCAL, 0),
+            (String::new(), 0, STB_LOCAL, 1),
+            ("again".to_string(), 2, STB_LOCAL, 1),
+            ("main".to_string(), 0, STB_GLOBAL, 1),
+            ("puts".to_string(), 0, STB_GLOBAL, 0),
+        ]);
+
+        let rela = section_header(&file, ".rela.text");
+        assert_eq!(read_u32(&file, rela + 4), SHT_RELA);
+        assert_eq!(read_u32(&file, rela + 20), RELA_SIZE);
+        assert_eq!(headers + read_u32(&file, rela + 24) as usize * SECTION_HEADER_SIZE as usize, symtab);
+        let relocation = read_u32(&file, rela + 16) as usize;
+        assert_eq!(read_u32(&file, relocation), 2);
+        assert_eq!(read_u32(&file, relocation + 4), (4 << 8) | RelocationKind::Call.number());
+        assert_eq!(read_u32(&file, relocation + 8), 0);
+    }
+
+    #[test]
+    fn object_needs_exported_labels_bound() {
+        let mut asm = Assembler::new(Core::Avr5);
+        let main = asm.named_label("main");
+        asm.export(main);
+        asm.nop();
+        match RelocatableObject::from_assembler(&asm) {
+            Err(AsmError::UnboundLabel { name: Some(ref name), .. }) if name == "main" => {}
+            result => panic!("{:?}", result.map(|object| object.symbols)),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::error::Error;
+use std::fmt;
+
+use {Core, Label};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AsmError {
+    /// An operand does not satisfy a constraint of the instruction it was given to.
+    InvalidOperand { instruction: &'static str, operand: &'static str, constraint: Constraint },
+    /// A label was bound a second time.
+    LabelAlreadyBound { label: Label, name: Option<String> },
+    /// A label was referenced but never bound.
+    UnboundLabel { label: Label, name: Option<String> },
+    /// A label created by `Assembler::external_label` was bound.
+    ExternalLabelBound { label: Label, name: Option<String> },
+    /// The instruction does not exist on the core the assembler targets.
+    UnsupportedInstruction { instruction: &'static str, core: Core },
+    /// Code would be placed at or past the end of the device's flash.
+    OutsideFlash { address: u32, flash_size: u32 },
+    /// An `lds` or `sts` address is outside the device's data space, or a
+    /// `call` or `jmp` target is outside its flash.
+    OutsideDevice { instruction: &'static str, address: u32, device: &'static str },
+    /// A skip instruction comes right before something that is emitted as
+    /// several instructions, of which it would only skip the first.
+    SkippedSequence { skip: &'static str, sequence: &'static str },
+}
+
+/// The rule an operand broke.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Constraint {
+    /// The register must be between R`min` and R`max`, inclusive.
+    RegisterRange { min: u32, max: u32 },
+    /// The pair must be consecutive registers, given high first.
+    ConsecutivePair,
+    /// The low register of the pair must be even-numbered.
+    EvenPair,
+    /// The low register of the pair must be at least R`min`.
+    PairRange { min: u32 },
+    /// The pointer register or its increment mode is not accepted by the instruction.
+    PointerMode,
+    /// Only Z (R31:R30) is accepted.
+    OnlyZ,
+    /// The offset must be a whole number of 16-bit words.
+    EvenOffset,
+    /// The value must be between `min` and `max`, inclusive, to fit in its field.
+    Range { min: i64, max: i64 },
+    /// The register group must be `bytes` wide, as the other one is.
+    Width { bytes: u32 },
+}
+
+impl AsmError {
+    pub(crate) fn invalid_operand(instruction: &'static str, field: u8, constraint: Constraint) -> AsmError {
+        AsmError::InvalidOperand {
+            instruction,
+            operand: operand_name(field),
+            constraint,
+        }
+    }
+}
+
+// Names the operand that a template character stands for, as the datasheet does
+pub(crate) fn operand_name(field: u8) -> &'static str {
+    match field {
+        b'd' => "Rd",
+        b'r' => "Rr",
+        b'K' => "K",
+        b'k' => "k",
+        b'q' => "q",
+        b'A' => "A",
+        b'b' => "b",
+        b's' => "s",
+        b'u' => "us",
+        b'z' => "Z",
+        b'p' => "pointer",
+        _ => "operand",
+    }
+}
+
+fn describe_label(f: &mut fmt::Formatter, label: Label, name: &Option<String>) -> fmt::Result {
+    match *name {
+        Some(ref name) => write!(f, "label `{}`", name),
+        None => write!(f, "label #{}", label.0),
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AsmError::InvalidOperand { instruction, operand, constraint } => {
+                write!(f, "{}: operand {} {}", instruction, operand, constraint)
+            }
+            AsmError::LabelAlreadyBound { label, ref name } => {
+                describe_label(f, label, name)?;
+                write!(f, " is already bound")
+            }
+            AsmError::UnboundLabel { label, ref name } => {
+                describe_label(f, label, name)?;
+                write!(f, " is referenced but never bound")
+            }
+            AsmError::ExternalLabelBound { label, ref name } => {
+                describe_label(f, label, name)?;
+                write!(f, " is external and cannot be bound")
+            }
+            AsmError::UnsupportedInstruction { instruction, core } => {
+                write!(f, "{}: not supported by the {} core", instruction, core)
+            }
+            AsmError::OutsideFlash { address, flash_size } => {
+                write!(f, "code at 0x{:x} does not fit in {} bytes of flash", address, flash_size)
+            }
+            AsmError::OutsideDevice { instruction, address, device } => {
+                write!(f, "{}: address 0x{:x} is outside the {}", instruction, address, device)
+            }
+            AsmError::SkippedSequence { skip, sequence } => {
+                write!(f, "{}: skips only the first instruction of the {} after it", skip, sequence)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Constraint::RegisterRange { min, max } => write!(f, "must be one of R{}..R{}", min, max),
+            Constraint::ConsecutivePair => write!(f, "must be a pair of consecutive registers, high register first"),
+            Constraint::EvenPair => write!(f, "must have an even-numbered low register"),
+            Constraint::PairRange { min } => write!(f, "must have a low register of at least R{}", min),
+            Constraint::PointerMode => write!(f, "is not a supported pointer form"),
+            Constraint::OnlyZ => write!(f, "must be Z (R31:R30)"),
+            Constraint::EvenOffset => write!(f, "must be an even number of bytes"),
+            Constraint::Range { min, max } => write!(f, "must be between {} and {}", min, max),
+            Constraint::Width { bytes } => write!(f, "must be {} bytes wide", bytes),
+        }
+    }
+}
+
+impl Error for AsmError {}
@@ -0,0 +1,493 @@
+// A front end for the GNU avr-as dialect.
+//
+// Source is read in two passes. The first finds the address of every label,
+// which only needs the size of each statement, and the second evaluates
+// operands and calls the instruction methods. Labels become labels of the
+// `Assembler`, so references to symbols that are never defined become
+// external labels, ready for a `RelocatableObject`.
+
+use std::collections::HashMap;
+
+use syntax::{assemble_instruction, constant, instruction_size, lex, operand_column, split_operands, strings, strip_block_comments};
+use syntax::{AddressByte, Expr, LexOptions, Located, SourceError, Symbols, Token, TokenKind};
+use {Assembler, Label};
+
+const LEX_OPTIONS: LexOptions = LexOptions {
+    leading_zero_octal: true,
+    local_labels: true,
+    dollar_hex: false,
+};
+
+// One line of source, split into its parts
+struct Statement {
+    line: usize,
+    // Labels defined at the start of the line, with their columns
+    labels: Vec<(String, usize)>,
+    kind: StatementKind,
+}
+
+enum StatementKind {
+    Empty,
+    // A mnemonic or directive, in lower case, with its column and operand tokens
+    Instruction(String, usize, Vec<Token>),
+    Directive(String, usize, Vec<Token>),
+    // `name = value`
+    Assignment(String, usize, Vec<Token>),
+}
+
+#[derive(Copy, Clone)]
+enum Symbol {
+    Label(u32),
+    Constant(i64),
+    // Referenced but not defined in this source
+    External,
+}
+
+struct Source<'a> {
+    file: &'a str,
+    symbols: HashMap<String, Symbol>,
+    // Names of labels in the order they are defined
+    label_order: Vec<String>,
+    labels: HashMap<String, Label>,
+    // Address of the statement being read, which is the value of `.`
+    address: u32,
+}
+
+impl Assembler {
+    /// Assembles GNU avr-as source, as found in `.S` files after the C
+    /// preprocessor has run, starting at the current address.
+    ///
+    /// Mnemonics, registers and directives may be in either case, while
+    /// symbols are case sensitive. Only the `.text` section is supported, and
+    /// `.org` counts from the origin. Symbols that are referenced by an
+    /// instruction but never defined become external labels; other uses of
+    /// them are errors.
+    ///
+    /// `file_name` is only used in errors.
+    pub fn assemble_gnu(&mut self, file_name: &str, source: &str) -> Result<(), SourceError> {
+        let statements = parse_statements(file_name, source)?;
+        let mut source = Source {
+            file: file_name,
+            symbols: HashMap::new(),
+            label_order: Vec::new(),
+            labels: HashMap::new(),
+            address: 0,
+        };
+        source.layout(self, &statements)?;
+
+        for name in source.label_order.iter() {
+            // Local labels are left out of the symbol table, as avr-as does
+            let label = if name.starts_with(".L") { self.new_label() } else { self.named_label(name) };
+            source.labels.insert(name.clone(), label);
+        }
+
+        for statement in statements.iter() {
+            source.emit(self, statement)
+                .map_err(|(column, message)| SourceError { file: file_name.to_string(), line: statement.line, column, message })?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_statements(file_name: &str, source: &str) -> Result<Vec<Statement>, SourceError> {
+    let source = strip_block_comments(source);
+    let mut statements = Vec::new();
+    // How many times each numeric local label has been defined so far
+    let mut local_counts: HashMap<u32, u32> = HashMap::new();
+    for (index, line) in source.lines().enumerate() {
+        let error = |(column, message)| SourceError { file: file_name.to_string(), line: index + 1, column, message };
+        // Lines left behind by the C preprocessor, and `#` comments
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        let mut tokens = lex(line, LEX_OPTIONS).map_err(&error)?;
+
+        let mut labels = Vec::new();
+        while tokens.len() >= 2 && tokens[1].is_punct(":") {
+            let name = match tokens[0].kind {
+                TokenKind::Identifier(ref name) => name.clone(),
+                TokenKind::Number(n) if n >= 0 => {
+                    let count = local_counts.entry(n as u32).or_insert(0);
+                    *count += 1;
+                    local_label_name(n as u32, *count - 1)
+                }
+                _ => return Err(error((tokens[0].column, "invalid label".to_string()))),
+            };
+            labels.push((name, tokens[0].column));
+            tokens.drain(..2);
+        }
+
+        // Numeric local label references become the name of the definition they mean
+        for token in tokens.iter_mut() {
+            if let TokenKind::LocalLabel(n, forward) = token.kind {
+                let count = local_counts.get(&n).cloned().unwrap_or(0);
+                if !forward && count == 0 {
+                    return Err(error((token.column, format!("no earlier definition of local label `{}`", n))));
+                }
+                token.kind = TokenKind::Identifier(local_label_name(n, if forward { count } else { count - 1 }));
+            }
+        }
+
+        let kind = if tokens.is_empty() {
+            StatementKind::Empty
+        } else {
+            let head = tokens.remove(0);
+            let name = match head.kind {
+                TokenKind::Identifier(ref name) => name.clone(),
+                _ => return Err(error((head.column, "expected a mnemonic or directive".to_string()))),
+            };
+            if tokens.first().is_some_and(|token| token.is_punct("=")) {
+                tokens.remove(0);
+                StatementKind::Assignment(name, head.column, tokens)
+            } else if name.starts_with('.') {
+                StatementKind::Directive(name.to_lowercase(), head.column, tokens)
+            } else {
+                StatementKind::Instruction(name.to_lowercase(), head.column, tokens)
+            }
+        };
+        statements.push(Statement { line: index + 1, labels, kind });
+    }
+    Ok(statements)
+}
+
+// The `k`th definition of numeric local label `n`, named as avr-as does internally
+fn local_label_name(n: u32, k: u32) -> String {
+    format!(".L{}\u{2}{}", n, k)
+}
+
+// The functions avr-as accepts in expressions
+fn function(name: &str, value: i64) -> Option<i64> {
+    Some(match name {
+        "lo8" => value & 0xff,
+        "hi8" => (value >> 8) & 0xff,
+        "hlo8" | "hh8" => (value >> 16) & 0xff,
+        "hhi8" => (value >> 24) & 0xff,
+        "pm" | "gs" => value >> 1,
+        "pm_lo8" => (value >> 1) & 0xff,
+        "pm_hi8" => (value >> 9) & 0xff,
+        "pm_hh8" => (value >> 17) & 0xff,
+        _ => return None,
+    })
+}
+
+impl<'a> Source<'a> {
+    // The first pass: gives every label its address
+    fn layout(&mut self, assembler: &Assembler, statements: &[Statement]) -> Result<(), SourceError> {
+        let file = self.file;
+        let mut address = assembler.address();
+        for statement in statements.iter() {
+            let error = |(column, message)| SourceError { file: file.to_string(), line: statement.line, column, message };
+            for &(ref name, column) in statement.labels.iter() {
+                if self.symbols.contains_key(name) {
+                    return Err(error((column, format!("symbol `{}` is already defined", name))));
+                }
+                self.symbols.insert(name.clone(), Symbol::Label(address));
+                self.label_order.push(name.clone());
+            }
+            self.address = address;
+            let size = self.statement_size(assembler, statement).map_err(&error)?;
+            address += size;
+        }
+        Ok(())
+    }
+
+    // The number of bytes a statement adds
+    fn statement_size(&mut self, assembler: &Assembler, statement: &Statement) -> Located<u32> {
+        match statement.kind {
+            StatementKind::Empty => Ok(0),
+            StatementKind::Instruction(ref mnemonic, column, ref tokens) => {
+                instruction_size(mnemonic, split_operands(tokens).len(), assembler.core())
+                    .ok_or_else(|| (column, format!("unknown instruction `{}`", mnemonic)))
+            }
+            StatementKind::Assignment(ref name, column, ref tokens) => {
+                // Values that need a later label are found again in the second pass
+                if let Ok(value) = constant(self, tokens, column) {
+                    self.symbols.insert(name.clone(), Symbol::Constant(value));
+                }
+                Ok(0)
+            }
+            StatementKind::Directive(ref name, column, ref tokens) => {
+                let operands = split_operands(tokens);
+                match &name[..] {
+                    ".equ" | ".set" => {
+                        let (symbol, value) = self.assignment(&operands, column)?;
+                        if let Ok(value) = constant(self, value, column) {
+                            self.symbols.insert(symbol, Symbol::Constant(value));
+                        }
+                        Ok(0)
+                    }
+                    ".byte" => Ok(operands.len() as u32),
+                    ".word" => Ok(2 * operands.len() as u32),
+                    ".ascii" | ".asciz" | ".string" => {
+                        let terminator = if name == ".ascii" { 0 } else { 1 };
+                        let strings = strings(&operands, column)?;
+                        Ok(strings.iter().map(|bytes| bytes.len() as u32 + terminator).sum())
+                    }
+                    _ => {
+                        let padding = self.padding(assembler, name, &operands, column)?;
+                        Ok(padding.map_or(0, |(length, _)| length))
+                    }
+                }
+            }
+        }
+    }
+
+    // The number of fill bytes, and their value, for directives that move
+    // the address forward. `None` for directives that emit nothing.
+    fn padding(&self, assembler: &Assembler, name: &str, operands: &[&[Token]], column: usize) -> Located<Option<(u32, u8)>> {
+        let address = self.address;
+        let argument = |index: usize| -> Located<i64> {
+            let tokens = operands.get(index).ok_or((column, format!("`{}` needs an operand", name)))?;
+            constant(self, tokens, column)
+        };
+        let length = match name {
+            ".org" => {
+                let target = assembler.origin() as i64 + argument(0)?;
+                if target < address as i64 {
+                    return Err((column, "`.org` cannot move the address backwards".to_string()));
+                }
+                (target - address as i64) as u32
+            }
+            ".align" | ".p2align" | ".balign" => {
+                let alignment = if name == ".balign" { argument(0)? } else { 1i64 << argument(0)?.clamp(0, 31) };
+                if alignment <= 0 || alignment & (alignment - 1) != 0 {
+                    return Err((column, "alignment must be a power of two".to_string()));
+                }
+                let alignment = alignment as u32;
+                (alignment - address % alignment) % alignment
+            }
+            ".space" | ".skip" => {
+                let length = argument(0)?;
+                if length < 0 {
+                    return Err((column, "size must not be negative".to_string()));
+                }
+                length as u32
+            }
+            ".text" | ".global" | ".globl" | ".extern" | ".type" | ".size" | ".file" | ".ident" | ".func" | ".endfunc" => return Ok(None),
+            ".section" => {
+                let section = operands.first().and_then(|tokens| tokens.first()).and_then(|token| token.identifier());
+                if section != Some(".text") && !section.is_some_and(|section| section.starts_with(".text.")) {
+                    return Err((column, "only the .text section is supported".to_string()));
+                }
+                return Ok(None);
+            }
+            ".data" | ".bss" => return Err((column, "only the .text section is supported".to_string())),
+            _ => return Err((column, format!("unknown directive `{}`", name))),
+        };
+        let fill = match operands.get(1) {
+            Some(tokens) => constant(self, tokens, column)? as u8,
+            None => 0,
+        };
+        Ok(Some((length, fill)))
+    }
+
+    // The second pass: emits one statement
+    fn emit(&mut self, assembler: &mut Assembler, statement: &Statement) -> Located<()> {
+        for &(ref name, column) in statement.labels.iter() {
+            let label = self.labels[name];
+            assembler.try_bind(label).map_err(|err| (column, err.to_string()))?;
+        }
+        self.address = assembler.address();
+        match statement.kind {
+            StatementKind::Empty => Ok(()),
+            StatementKind::Instruction(ref mnemonic, column, ref tokens) => {
+                assemble_instruction(assembler, self, mnemonic, column, tokens)
+            }
+            StatementKind::Assignment(ref name, column, ref tokens) => {
+                let value = constant(self, tokens, column)?;
+                self.symbols.insert(name.clone(), Symbol::Constant(value));
+                Ok(())
+            }
+            StatementKind::Directive(ref name, column, ref tokens) => {
+                let operands = split_operands(tokens);
+                match &name[..] {
+                    ".equ" | ".set" => {
+                        let (symbol, value) = self.assignment(&operands, column)?;
+                        let value = constant(self, value, column)?;
+                        self.symbols.insert(symbol, Symbol::Constant(value));
+                    }
+                    ".global" | ".globl" => {
+                        // Every label already exists, so later ones can be exported too
+                        for tokens in operands.iter() {
+                            match tokens.first().and_then(|token| token.identifier()) {
+                                Some(name) if tokens.len() == 1 => {
+                                    if let Some(&Symbol::Label(_)) = self.symbols.get(name) {
+                                        assembler.export(self.labels[name]);
+                                    }
+                                }
+                                _ => return Err((operand_column(tokens, column), "expected a symbol name".to_string())),
+                            }
+                        }
+                    }
+                    ".byte" => {
+                        for tokens in operands.iter() {
+                            let value = constant(self, tokens, column)?;
+                            if !(-128..=255).contains(&value) {
+                                return Err((operand_column(tokens, column), format!("{} does not fit in a byte", value)));
+                            }
+                            assembler.try_emit_bytes(&[value as u8]).map_err(|err| (column, err.to_string()))?;
+                        }
+                    }
+                    ".word" => {
+                        for tokens in operands.iter() {
+                            let value = constant(self, tokens, column)?;
+                            if !(-32768..=65535).contains(&value) {
+                                return Err((operand_column(tokens, column), format!("{} does not fit in a word", value)));
+                            }
+                            assembler.try_emit_bytes(&[value as u8, (value >> 8) as u8]).map_err(|err| (column, err.to_string()))?;
+                        }
+                    }
+                    ".ascii" | ".asciz" | ".string" => {
+                        for bytes in strings(&operands, column)? {
+                            assembler.try_emit_bytes(&bytes).map_err(|err| (column, err.to_string()))?;
+                            if name != ".ascii" {
+                                assembler.try_emit_bytes(&[0]).map_err(|err| (column, err.to_string()))?;
+                            }
+                        }
+                    }
+                    _ => {
+                        if let Some((length, fill)) = self.padding(assembler, name, &operands, column)? {
+                            assembler.try_emit_bytes(&vec![fill; length as usize]).map_err(|err| (column, err.to_string()))?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // The name and value of `.equ name, value`
+    fn assignment<'t>(&self, operands: &[&'t [Token]], column: usize) -> Located<(String, &'t [Token])> {
+        match (operands.len(), operands.first().and_then(|tokens| tokens.first()).and_then(|token| token.identifier())) {
+            (2, Some(name)) if operands[0].len() == 1 => Ok((name.to_string(), operands[1])),
+            _ => Err((column, "expected a symbol name and a value".to_string())),
+        }
+    }
+}
+
+impl<'a> Symbols for Source<'a> {
+    fn value(&self, name: &str) -> Option<i64> {
+        if name == "." {
+            return Some(self.address as i64);
+        }
+        match self.symbols.get(name) {
+            Some(&Symbol::Constant(value)) => Some(value),
+            Some(&Symbol::Label(address)) => Some(address as i64),
+            _ => None,
+        }
+    }
+
+    fn label(&mut self, assembler: &mut Assembler, symbol: &str) -> Option<Label> {
+        match self.symbols.get(symbol) {
+            Some(&Symbol::Label(_)) | Some(&Symbol::External) => Some(self.labels[symbol]),
+            Some(&Symbol::Constant(_)) => None,
+            None if symbol == "." => None,
+            None => {
+                let label = assembler.external_label(symbol);
+                self.symbols.insert(symbol.to_string(), Symbol::External);
+                self.labels.insert(symbol.to_string(), label);
+                Some(label)
+            }
+        }
+    }
+
+    fn function(&self, name: &str, value: i64) -> Option<i64> {
+        function(name, value)
+    }
+
+    // `lo8(x)`, `pm_hi8(x)`, `hi8(pm(x))` and so on
+    fn address_byte(&self, expr: &Expr) -> Option<(AddressByte, String)> {
+        address_byte(expr)
+    }
+}
+
+fn address_byte(expr: &Expr) -> Option<(AddressByte, String)> {
+    let (function, argument) = match *expr {
+        Expr::Call(ref function, ref argument, _) => (function, &**argument),
+        _ => return None,
+    };
+    let (byte, argument) = match (&function[..], argument) {
+        ("lo8", Expr::Call(inner, argument, _)) if inner == "pm" || inner == "gs" => (AddressByte::PmLo8, &**argument),
+        ("hi8", Expr::Call(inner, argument, _)) if inner == "pm" || inner == "gs" => (AddressByte::PmHi8, &**argument),
+        ("lo8", argument) => (AddressByte::Lo8, argument),
+        ("hi8", argument) => (AddressByte::Hi8, argument),
+        ("pm_lo8", argument) => (AddressByte::PmLo8, argument),
+        ("pm_hi8", argument) => (AddressByte::PmHi8, argument),
+        _ => return None,
+    };
+    match *argument {
+        Expr::Symbol(ref symbol, _) => Some((byte, symbol.clone())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Core;
+
+    #[test]
+    fn assembles_labels_expressions_and_data() {
+        let source = "\
+        .equ PORTB, 0x05
+main:   ldi r16, lo8(table)
+        LDI R17, hi8(pm(main))
+1:      out PORTB, r16
+        rjmp 1b
+        rcall ext   ; defined elsewhere
+table:  .byte 1, 2
+";
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.assemble_gnu("test.S", source).unwrap();
+        assert_eq!(asm.buf, [0x0a, 0xe0, 0x10, 0xe0, 0x05, 0xb9, 0xfe, 0xcf, 0x00, 0xd0, 0x01, 0x02]);
+        assert_eq!(asm.symbols().collect::<Vec<_>>(), [("main", 0), ("table", 10)]);
+    }
+
+    #[test]
+    fn errors_give_the_line() {
+        let mut asm = Assembler::new(Core::Avr5);
+        let err = asm.assemble_gnu("test.S", "nop\nfrobnicate r1\n").unwrap_err();
+        assert_eq!((&err.file[..], err.line, err.column), ("test.S", 2, 1));
+    }
+
+    #[test]
+    fn assembles_pointer_operands() {
+        let source = "ld r0, X+\nst -Y, r1\nldd r2, Z+5\nstd Y+63, r3\nld r4, Z\n";
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.assemble_gnu("test.S", source).unwrap();
+        assert_eq!(asm.buf, [0x0d, 0x90, 0x1a, 0x92, 0x25, 0x80, 0x3f, 0xae, 0x40, 0x80]);
+    }
+
+    #[test]
+    fn assembles_placement_and_string_directives() {
+        let source = "\
+        .global start
+start:  nop
+        .ascii \"AB\"
+        .align 3
+        .asciz \"C\"
+        .org 12
+end:    .word end
+";
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.assemble_gnu("test.S", source).unwrap();
+        assert_eq!(asm.buf, [0x00, 0x00, b'A', b'B', 0, 0, 0, 0, b'C', 0, 0, 0, 12, 0]);
+        let object = ::RelocatableObject::from_assembler(&asm).unwrap();
+        let start = object.symbols.iter().find(|symbol| symbol.name == "start").unwrap();
+        assert!(start.global);
+        assert!(!object.symbols.iter().find(|symbol| symbol.name == "end").unwrap().global);
+    }
+
+    #[test]
+    fn errors_give_the_column_of_the_operand() {
+        let column = |source: &str| {
+            let mut asm = Assembler::new(Core::Avr5);
+            let err = asm.assemble_gnu("test.S", source).unwrap_err();
+            (err.column, err.message)
+        };
+        assert_eq!(column("ldd r0, Y+64"), (11, "displacement must be between 0 and 63".to_string()));
+        assert_eq!(column("  ld r0, W"), (10, "expected a pointer register such as X, Y+ or -Z".to_string()));
+        assert_eq!(column("nop\nnop\n  .org 2"), (3, "`.org` cannot move the address backwards".to_string()));
+        assert_eq!(column("nop\nldi r16, 2 * 200"), (10, "ldi: operand K must be between 0 and 255".to_string()));
+    }
+}
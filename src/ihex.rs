@@ -0,0 +1,310 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use {Assembler, MemoryImage};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// How addresses above 64 KiB are expressed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExtendedAddressing {
+    /// Type 02 records, giving the address in 16-byte paragraphs. Reaches 1 MiB,
+    /// and is what avr-objcopy produces.
+    Segment,
+    /// Type 04 records, giving the upper 16 bits of the address.
+    Linear,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct HexOptions {
+    /// Number of data bytes per record, from 1 to 255.
+    pub record_length: usize,
+    pub addressing: ExtendedAddressing,
+}
+
+impl Default for HexOptions {
+    fn default() -> HexOptions {
+        HexOptions {
+            record_length: 16,
+            addressing: ExtendedAddressing::Segment,
+        }
+    }
+}
+
+/// Writes `data` as Intel HEX, placed at `base_address`, followed by an end of file record.
+pub fn write_hex<W: Write>(out: &mut W, data: &[u8], base_address: u32, options: &HexOptions) -> io::Result<()> {
+    write_segments(out, Some((base_address, data)).into_iter(), options)
+}
+
+pub(crate) fn write_segments<'a, W: Write, I: Iterator<Item = (u32, &'a [u8])>>(out: &mut W, segments: I, options: &HexOptions) -> io::Result<()> {
+    if options.record_length == 0 || options.record_length > 255 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "record length must be between 1 and 255"));
+    }
+    let limit: u64 = match options.addressing {
+        ExtendedAddressing::Segment => 0x10_0000,
+        ExtendedAddressing::Linear => 0x1_0000_0000,
+    };
+
+    // Upper address bits currently in effect. Readers start out at zero.
+    let mut upper: u32 = 0;
+    for (base_address, data) in segments {
+        if base_address as u64 + data.len() as u64 > limit {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "data does not fit in the address range of the chosen addressing"));
+        }
+
+        let mut position = 0;
+        while position < data.len() {
+            let address = base_address + position as u32;
+            if address >> 16 != upper {
+                upper = address >> 16;
+                match options.addressing {
+                    ExtendedAddressing::Segment => {
+                        let segment = (upper << 12) as u16;
+                        write_record(out, EXTENDED_SEGMENT_ADDRESS, 0, &[(segment >> 8) as u8, segment as u8])?;
+                    }
+                    ExtendedAddressing::Linear => {
+                        write_record(out, EXTENDED_LINEAR_ADDRESS, 0, &[(upper >> 8) as u8, upper as u8])?;
+                    }
+                }
+            }
+
+            // A record must not run past the end of its 64 KiB window
+            let to_boundary = 0x1_0000 - (address & 0xffff) as usize;
+            let length = options.record_length.min(to_boundary).min(data.len() - position);
+            write_record(out, DATA, address as u16, &data[position..position + length])?;
+            position += length;
+        }
+    }
+
+    write_record(out, END_OF_FILE, 0, &[])
+}
+
+fn write_record<W: Write>(out: &mut W, record_type: u8, address: u16, data: &[u8]) -> io::Result<()> {
+    let mut sum = data.len() as u8;
+    sum = sum.wrapping_add((address >> 8) as u8).wrapping_add(address as u8).wrapping_add(record_type);
+
+    write!(out, ":{:02X}{:04X}{:02X}", data.len(), address, record_type)?;
+    for &byte in data {
+        write!(out, "{:02X}", byte)?;
+        sum = sum.wrapping_add(byte);
+    }
+    writeln!(out, "{:02X}", sum.wrapping_neg())
+}
+
+impl Assembler {
+    /// Writes `buf` as Intel HEX placed at `base_address`, with 16-byte records.
+    /// If any referenced label was never bound, nothing is written and the
+    /// error is of kind `InvalidInput`, holding the `AsmError`.
+    pub fn write_hex<W: Write>(&self, out: &mut W, base_address: u32) -> io::Result<()> {
+        self.check_bound().map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        write_hex(out, &self.buf, base_address, &HexOptions::default())
+    }
+}
+
+#[derive(Debug)]
+pub enum HexError {
+    Io(io::Error),
+    /// A line is not a well-formed record. Lines are numbered from 1.
+    Syntax { line: usize, message: &'static str },
+    Checksum { line: usize, expected: u8, found: u8 },
+    /// The input ended without an end of file record.
+    MissingEndOfFile,
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HexError::Io(ref err) => write!(f, "{}", err),
+            HexError::Syntax { line, message } => write!(f, "line {}: {}", line, message),
+            HexError::Checksum { line, expected, found } => {
+                write!(f, "line {}: checksum is {:02X}, expected {:02X}", line, found, expected)
+            }
+            HexError::MissingEndOfFile => write!(f, "missing end of file record"),
+        }
+    }
+}
+
+impl Error for HexError {}
+
+impl From<io::Error> for HexError {
+    fn from(err: io::Error) -> HexError {
+        HexError::Io(err)
+    }
+}
+
+/// Reads Intel HEX into a memory image. Start address records are accepted
+/// and ignored, and anything after the end of file record is not read.
+pub fn read_hex<R: BufRead>(input: R) -> Result<MemoryImage, HexError> {
+    let mut image = MemoryImage::new();
+    // Added to the address of every data record
+    let mut base: u32 = 0;
+
+    for (index, line) in input.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let syntax = |message| HexError::Syntax { line: line_number, message };
+
+        if !line.starts_with(':') {
+            return Err(syntax("record does not start with ':'"));
+        }
+        let digits = &line[1..];
+        if !digits.is_ascii() {
+            return Err(syntax("record contains a character that is not a hex digit"));
+        }
+        if digits.len() % 2 != 0 || digits.len() < 10 {
+            return Err(syntax("record is too short"));
+        }
+        let bytes = (0..digits.len() / 2)
+            .map(|i| u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| syntax("record contains a character that is not a hex digit"))?;
+
+        let length = bytes[0] as usize;
+        if bytes.len() != length + 5 {
+            return Err(syntax("record length does not match its byte count"));
+        }
+        let sum = bytes[..bytes.len() - 1].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        let checksum = bytes[bytes.len() - 1];
+        if sum.wrapping_add(checksum) != 0 {
+            return Err(HexError::Checksum { line: line_number, expected: sum.wrapping_neg(), found: checksum });
+        }
+
+        let address = ((bytes[1] as u32) << 8) | bytes[2] as u32;
+        let data = &bytes[4..4 + length];
+        match bytes[3] {
+            DATA => {
+                let start = base.checked_add(address)
+                    .filter(|start| start.checked_add(length as u32).is_some())
+                    .ok_or_else(|| syntax("data record runs past the end of the address space"))?;
+                image.insert(start, data);
+            }
+            END_OF_FILE => return Ok(image),
+            EXTENDED_SEGMENT_ADDRESS | EXTENDED_LINEAR_ADDRESS if length != 2 => {
+                return Err(syntax("extended address record must hold 2 bytes"));
+            }
+            EXTENDED_SEGMENT_ADDRESS => base = (((data[0] as u32) << 8) | data[1] as u32) << 4,
+            EXTENDED_LINEAR_ADDRESS => base = (((data[0] as u32) << 8) | data[1] as u32) << 16,
+            START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => {}
+            _ => return Err(syntax("unknown record type")),
+        }
+    }
+
+    Err(HexError::MissingEndOfFile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_extended_addresses() {
+        let data: Vec<u8> = (0..40).collect();
+        for &addressing in &[ExtendedAddressing::Segment, ExtendedAddressing::Linear] {
+            let mut hex = Vec::new();
+            let options = HexOptions { record_length: 16, addressing };
+            write_hex(&mut hex, &data, 0xfff0, &options).unwrap();
+            let image = read_hex(&hex[..]).unwrap();
+            assert_eq!(image.segments().collect::<Vec<_>>(), [(0xfff0, &data[..])]);
+        }
+    }
+
+    #[test]
+    fn writes_records_as_avr_objcopy_does() {
+        let mut hex = Vec::new();
+        write_hex(&mut hex, &[0x0c, 0x94, 0x34, 0x00], 0, &HexOptions::default()).unwrap();
+        assert_eq!(String::from_utf8(hex).unwrap(), ":040000000C94340028\n:00000001FF\n");
+    }
+
+    #[test]
+    fn rejects_a_bad_checksum() {
+        let err = read_hex(&b":040000000C94340029\n:00000001FF\n"[..]).unwrap_err();
+        match err {
+            HexError::Checksum { line: 1, expected: 0x28, found: 0x29 } => {}
+            err => panic!("{}", err),
+        }
+    }
+
+    #[test]
+    fn rejects_data_past_the_end_of_the_address_space() {
+        let err = read_hex(&b":02000004FFFFFC\n:04FFFF0001020304F4\n:00000001FF\n"[..]).unwrap_err();
+        match err {
+            HexError::Syntax { line: 2, .. } => {}
+            err => panic!("{}", err),
+        }
+    }
+
+    #[test]
+    fn assembler_output_with_an_unbound_label_is_rejected() {
+        let mut asm = Assembler::new(::Core::Avr5);
+        let label = asm.named_label("missing");
+        asm.rjmp(label);
+        let mut hex = Vec::new();
+        let err = asm.write_hex(&mut hex, 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        match err.get_ref().and_then(|err| err.downcast_ref::<::AsmError>()) {
+            Some(&::AsmError::UnboundLabel { name: Some(ref name), .. }) if name == "missing" => {}
+            other => panic!("{:?}", other),
+        }
+        assert!(hex.is_empty());
+        asm.bind(label);
+        asm.write_hex(&mut hex, 0).unwrap();
+        assert_eq!(hex, b":0200000000C03E\n:00000001FF\n");
+    }
+
+    // The type and address of each record, and its data length
+    fn record_headers(hex: &[u8]) -> Vec<String> {
+        String::from_utf8(hex.to_vec()).unwrap().lines().map(|line| line[1..9].to_string()).collect()
+    }
+
+    #[test]
+    fn fills_records_up_to_the_record_length() {
+        let data: Vec<u8> = (0..255).map(|x| x as u8).collect();
+        for &record_length in &[16, 255] {
+            let options = HexOptions { record_length, ..HexOptions::default() };
+            let mut hex = Vec::new();
+            write_hex(&mut hex, &data[..record_length], 0, &options).unwrap();
+            assert_eq!(record_headers(&hex), [format!("{:02X}000000", record_length), "00000001".to_string()]);
+
+            let mut hex = Vec::new();
+            write_hex(&mut hex, &data[..record_length - 1], 0x100, &options).unwrap();
+            assert_eq!(record_headers(&hex)[0], format!("{:02X}010000", record_length - 1));
+        }
+        let mut hex = Vec::new();
+        write_hex(&mut hex, &data[..17], 0, &HexOptions::default()).unwrap();
+        assert_eq!(record_headers(&hex), ["10000000", "01001000", "00000001"]);
+
+        let options = HexOptions { record_length: 256, ..HexOptions::default() };
+        assert_eq!(write_hex(&mut Vec::new(), &data, 0, &options).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn splits_a_record_at_a_64_kib_boundary() {
+        let data: Vec<u8> = (0..12).collect();
+        let cases = [(ExtendedAddressing::Segment, ":020000021000EC"), (ExtendedAddressing::Linear, ":020000040001F9")];
+        for &(addressing, extended) in cases.iter() {
+            let mut hex = Vec::new();
+            let options = HexOptions { record_length: 16, addressing };
+            write_hex(&mut hex, &data, 0xfffa, &options).unwrap();
+            let text = String::from_utf8(hex.clone()).unwrap();
+            let lines: Vec<&str> = text.lines().collect();
+            assert_eq!(lines.len(), 4);
+            assert!(lines[0].starts_with(":06FFFA00"));
+            assert_eq!(lines[1], extended);
+            assert!(lines[2].starts_with(":0600000006"));
+            assert_eq!(lines[3], ":00000001FF");
+
+            let image = read_hex(&hex[..]).unwrap();
+            assert_eq!(image.segments().collect::<Vec<_>>(), [(0xfffa, &data[..])]);
+        }
+    }
+}
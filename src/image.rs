@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use ihex::{write_segments, HexOptions};
+
+/// Bytes at scattered addresses, such as the contents of a firmware file.
+///
+/// Kept as runs of consecutive bytes. Addresses that were never written are
+/// gaps, rather than being filled with a value.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MemoryImage {
+    // Keyed by start address. Runs never overlap or touch.
+    segments: BTreeMap<u32, Vec<u8>>,
+}
+
+impl MemoryImage {
+    pub fn new() -> MemoryImage {
+        MemoryImage { segments: BTreeMap::new() }
+    }
+
+    /// Writes `data` starting at `address`, replacing any bytes already there.
+    ///
+    /// Panics if `data` runs past the end of the 32-bit address space.
+    pub fn insert(&mut self, address: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let end = address.checked_add(data.len() as u32).expect("data runs past the end of the address space");
+
+        // Runs that overlap or touch the new bytes get merged with them
+        let touching: Vec<u32> = self.segments.iter()
+            .filter(|&(&start, bytes)| start <= end && address <= start + bytes.len() as u32)
+            .map(|(&start, _)| start)
+            .collect();
+
+        let mut merged_start = address;
+        let mut merged_end = end;
+        for start in touching.iter() {
+            merged_start = merged_start.min(*start);
+            merged_end = merged_end.max(*start + self.segments[start].len() as u32);
+        }
+
+        let mut merged = vec![0; (merged_end - merged_start) as usize];
+        for start in touching.iter() {
+            let bytes = self.segments.remove(start).unwrap();
+            let offset = (*start - merged_start) as usize;
+            merged[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+        let offset = (address - merged_start) as usize;
+        merged[offset..offset + data.len()].copy_from_slice(data);
+
+        self.segments.insert(merged_start, merged);
+    }
+
+    pub fn get(&self, address: u32) -> Option<u8> {
+        self.segments.range(..=address).next_back().and_then(|(&start, bytes)| {
+            bytes.get((address - start) as usize).cloned()
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// The runs of consecutive bytes, in address order.
+    pub fn segments(&self) -> impl Iterator<Item = (u32, &[u8])> {
+        self.segments.iter().map(|(&start, bytes)| (start, &bytes[..]))
+    }
+
+    /// The lowest and one past the highest address holding data.
+    pub fn bounds(&self) -> Option<(u32, u32)> {
+        let first = self.segments.iter().next();
+        let last = self.segments.iter().next_back();
+        match (first, last) {
+            (Some((&start, _)), Some((&last_start, bytes))) => Some((start, last_start + bytes.len() as u32)),
+            _ => None,
+        }
+    }
+
+    /// The bytes from `start` up to `end`, with gaps filled by `fill`.
+    ///
+    /// Erased flash reads as 0xFF, so that is the usual fill.
+    pub fn to_contiguous(&self, start: u32, end: u32, fill: u8) -> Vec<u8> {
+        let mut result = vec![fill; end.saturating_sub(start) as usize];
+        for (address, bytes) in self.segments() {
+            for (index, &byte) in bytes.iter().enumerate() {
+                let address = address + index as u32;
+                if address >= start && address < end {
+                    result[(address - start) as usize] = byte;
+                }
+            }
+        }
+        result
+    }
+
+    pub fn write_hex<W: Write>(&self, out: &mut W, options: &HexOptions) -> io::Result<()> {
+        write_segments(out, self.segments(), options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_touching_and_overlapping_runs() {
+        let mut image = MemoryImage::new();
+        image.insert(4, &[4, 5]);
+        image.insert(0, &[0, 1]);
+        image.insert(2, &[2, 3]);
+        image.insert(5, &[50, 6]);
+        image.insert(10, &[10]);
+        assert_eq!(image.segments().collect::<Vec<_>>(), [(0, &[0, 1, 2, 3, 4, 50, 6][..]), (10, &[10][..])]);
+        assert_eq!(image.bounds(), Some((0, 11)));
+        assert_eq!(image.to_contiguous(6, 12, 0xff), [6, 0xff, 0xff, 0xff, 10, 0xff]);
+    }
+
+    #[test]
+    #[should_panic(expected = "past the end of the address space")]
+    fn insert_past_the_end_of_the_address_space_panics() {
+        MemoryImage::new().insert(0xffff_fffe, &[1, 2, 3]);
+    }
+}
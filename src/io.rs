@@ -0,0 +1,167 @@
+use {AsmError, Assembler, Bit, Register, UpperRegister};
+
+/// An address in the 64 bytes of I/O space that `in` and `out` reach, the
+/// first 32 of which `sbi`, `cbi`, `sbic` and `sbis` also reach.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct IoAddress(pub u32);
+
+/// An address in data space, as `lds` and `sts` take. I/O space starts at
+/// `Core::io_in_data_space`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct DataAddress(pub u32);
+
+impl From<u32> for IoAddress {
+    fn from(address: u32) -> IoAddress {
+        IoAddress(address)
+    }
+}
+
+/// The address of an I/O register in either space, for the helpers that pick
+/// between `in`/`out` and `lds`/`sts`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RegisterAddress {
+    Io(IoAddress),
+    Data(DataAddress),
+}
+
+impl From<IoAddress> for RegisterAddress {
+    fn from(address: IoAddress) -> RegisterAddress {
+        RegisterAddress::Io(address)
+    }
+}
+
+impl From<DataAddress> for RegisterAddress {
+    fn from(address: DataAddress) -> RegisterAddress {
+        RegisterAddress::Data(address)
+    }
+}
+
+impl Assembler {
+    /// The I/O address of a register, if it is in I/O space on the target core.
+    pub fn io_address<A: Into<RegisterAddress>>(&self, address: A) -> Option<IoAddress> {
+        let io_start = self.core().io_in_data_space();
+        match address.into() {
+            RegisterAddress::Io(address) => Some(address),
+            RegisterAddress::Data(DataAddress(address)) if address >= io_start && address < io_start + 64 => {
+                Some(IoAddress(address - io_start))
+            }
+            RegisterAddress::Data(_) => None,
+        }
+    }
+
+    /// The data address of a register on the target core.
+    pub fn data_address<A: Into<RegisterAddress>>(&self, address: A) -> DataAddress {
+        match address.into() {
+            RegisterAddress::Io(IoAddress(address)) => DataAddress(address + self.core().io_in_data_space()),
+            RegisterAddress::Data(address) => address,
+        }
+    }
+
+    pub fn read_io<A: Into<RegisterAddress>>(&mut self, d: Register, a: A) {
+        self.try_read_io(d, a).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Reads an I/O register with `in` if it is in I/O space, and `lds` otherwise.
+    pub fn try_read_io<A: Into<RegisterAddress>>(&mut self, d: Register, a: A) -> Result<(), AsmError> {
+        let a = a.into();
+        match self.io_address(a) {
+            Some(io) => self.try_in_(d, io),
+            None => {
+                let data = self.data_address(a);
+                self.try_lds(d, data)
+            }
+        }
+    }
+
+    pub fn write_io<A: Into<RegisterAddress>>(&mut self, a: A, r: Register) {
+        self.try_write_io(a, r).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Writes an I/O register with `out` if it is in I/O space, and `sts` otherwise.
+    pub fn try_write_io<A: Into<RegisterAddress>>(&mut self, a: A, r: Register) -> Result<(), AsmError> {
+        let a = a.into();
+        match self.io_address(a) {
+            Some(io) => self.try_out(io, r),
+            None => {
+                let data = self.data_address(a);
+                self.try_sts(data, r)
+            }
+        }
+    }
+
+    pub fn set_bit<A: Into<RegisterAddress>>(&mut self, a: A, bit: Bit, scratch: UpperRegister) {
+        self.try_set_bit(a, bit, scratch).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Sets one bit of an I/O register: with `sbi` in the first 32 bytes of I/O
+    /// space, and otherwise by reading it into `scratch`, applying `ori` and
+    /// writing it back. Only `sbi` is atomic.
+    pub fn try_set_bit<A: Into<RegisterAddress>>(&mut self, a: A, bit: Bit, scratch: UpperRegister) -> Result<(), AsmError> {
+        let a = a.into();
+        match self.io_address(a) {
+            Some(io) if io.0 < 32 => self.try_sbi(io, bit),
+            _ => {
+                self.check_not_skipped("set_bit", 3)?;
+                self.try_read_io(scratch.register(), a)?;
+                self.try_ori(scratch, bit.mask())?;
+                self.try_write_io(a, scratch.register())
+            }
+        }
+    }
+
+    pub fn clear_bit<A: Into<RegisterAddress>>(&mut self, a: A, bit: Bit, scratch: UpperRegister) {
+        self.try_clear_bit(a, bit, scratch).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Clears one bit of an I/O register, with `cbi` or through `scratch` and
+    /// `andi` as `try_set_bit` sets it.
+    pub fn try_clear_bit<A: Into<RegisterAddress>>(&mut self, a: A, bit: Bit, scratch: UpperRegister) -> Result<(), AsmError> {
+        let a = a.into();
+        match self.io_address(a) {
+            Some(io) if io.0 < 32 => self.try_cbi(io, bit),
+            _ => {
+                self.check_not_skipped("clear_bit", 3)?;
+                self.try_read_io(scratch.register(), a)?;
+                self.try_andi(scratch, !bit.mask() & 0xff)?;
+                self.try_write_io(a, scratch.register())
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {upper, Core, R16};
+
+    #[test]
+    fn io_registers_are_reached_with_in_and_out_where_they_can_be() {
+        let mut asm = Assembler::new(Core::Avr5);
+        assert_eq!(asm.io_address(DataAddress(0x5f)), Some(IoAddress(0x3f)));
+        assert_eq!(asm.io_address(DataAddress(0x60)), None);
+        assert_eq!(asm.data_address(IoAddress(0x05)), DataAddress(0x25));
+        asm.read_io(R16, DataAddress(0x5f));
+        asm.read_io(R16, DataAddress(0x60));
+        asm.write_io(IoAddress(0x3f), R16);
+        asm.write_io(DataAddress(0x60), R16);
+        assert_eq!(asm.buf, [0x0f, 0xb7, 0x00, 0x91, 0x60, 0x00, 0x0f, 0xbf, 0x00, 0x93, 0x60, 0x00]);
+    }
+
+    #[test]
+    fn bits_are_set_and_cleared_with_sbi_and_cbi_where_they_can_be() {
+        let scratch = upper::R16;
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.set_bit(IoAddress(0x05), Bit::B5, scratch);
+        asm.clear_bit(DataAddress(0x25), Bit::B5, scratch);
+        assert_eq!(asm.buf, [0x2d, 0x9a, 0x2d, 0x98]);
+
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.set_bit(IoAddress(0x3f), Bit::B7, scratch);
+        asm.clear_bit(DataAddress(0x60), Bit::B0, scratch);
+        assert_eq!(asm.buf, [
+            0x0f, 0xb7, 0x00, 0x68, 0x0f, 0xbf,
+            0x00, 0x91, 0x60, 0x00, 0x0e, 0x7f, 0x00, 0x93, 0x60, 0x00,
+        ]);
+    }
+}
@@ -5,30 +5,195 @@
 
 
 use std::convert::Into;
+use std::fmt;
 use std::ops::Add;
 
+use decode::is_skip;
+
+mod arch;
+mod atdf;
+mod avrasm;
+mod class;
+mod condition;
+mod control;
+mod decode;
+mod delay;
+mod device;
+mod elf;
+mod error;
+mod gnu;
+mod ihex;
+mod image;
+mod io;
+mod relax;
+mod syntax;
+mod wide;
+
+pub use arch::{Core, Feature};
+pub use atdf::{read_atdf, AtdfBitfield, AtdfDevice, AtdfError, AtdfInterrupt, AtdfMemory, AtdfRegister};
+pub use class::{multiply, pointer, upper, word_pair, IntoClass, MultiplyRegister, PointerPair, UpperRegister, WordPair};
+pub use condition::{Bit, Condition, Flag};
+pub use decode::{decode, decode_for, Instruction, Operand};
+pub use device::*;
+pub use elf::{ElfSymbol, Executable, MemorySection, ObjectSymbol, RelocatableObject, Relocation, RelocationKind};
+pub use error::{AsmError, Constraint};
+pub use ihex::{read_hex, write_hex, ExtendedAddressing, HexError, HexOptions};
+pub use image::MemoryImage;
+pub use io::{DataAddress, IoAddress, RegisterAddress};
+pub use syntax::SourceError;
+pub use wide::RegisterGroup;
+
 
 pub struct Assembler {
-    pub buf: Vec<u8>
+    pub buf: Vec<u8>,
+    origin: u32,
+    image: MemoryImage,
+    labels: Vec<LabelState>,
+    fixups: Vec<Fixup>,
+    core: Core,
+    device: Option<&'static Device>,
+    relax: bool,
+    shorten: bool,
+    grow: bool,
+    skipping: Option<&'static str>,
 }
 
 impl Assembler {
-    pub fn new() -> Assembler{
+    /// Creates an assembler for `core`, which rejects instructions the core does not have.
+    pub fn new(core: Core) -> Assembler{
+        Assembler::with_image(MemoryImage::new(), 0, core)
+    }
+    
+    /// Creates an assembler whose output goes on top of `image`, starting at
+    /// byte address `origin`.
+    pub fn with_image(image: MemoryImage, origin: u32, core: Core) -> Assembler {
         Assembler {
-            buf: Vec::new()
+            buf: Vec::new(),
+            origin,
+            image,
+            labels: Vec::new(),
+            fixups: Vec::new(),
+            core,
+            device: None,
+            relax: false,
+            shorten: false,
+            grow: false,
+            skipping: None,
+        }
+    }
+    
+    /// Creates an assembler for `device`, which also rejects code beyond the
+    /// end of its flash and `lds`, `sts`, `jmp` and `call` targets outside its memories.
+    pub fn for_device(device: &'static Device) -> Assembler {
+        Assembler::with_image_for_device(MemoryImage::new(), 0, device)
+    }
+    
+    /// Creates an assembler for `device` whose output goes on top of `image`,
+    /// starting at byte address `origin`.
+    pub fn with_image_for_device(image: MemoryImage, origin: u32, device: &'static Device) -> Assembler {
+        let mut assembler = Assembler::with_image(image, origin, device.core);
+        assembler.device = Some(device);
+        assembler
+    }
+    
+    /// The core that instructions are checked against.
+    pub fn core(&self) -> Core {
+        self.core
+    }
+    
+    /// The device that addresses are checked against, if one was given.
+    pub fn device(&self) -> Option<&'static Device> {
+        self.device
+    }
+    
+    // Rejects an instruction from a group the core does not have
+    fn require(&self, instruction: &'static str, feature: Feature) -> Result<(), AsmError> {
+        if self.core.has(feature) {
+            Ok(())
+        } else {
+            Err(AsmError::UnsupportedInstruction { instruction, core: self.core })
+        }
+    }
+    
+    // Only Z without an increment can be used without SRAM
+    fn require_pointer(&self, instruction: &'static str, pointer: &DirectionalRegisterPair) -> Result<(), AsmError> {
+        if pointer.pair == Z && pointer.direction == Direction::NoChange {
+            Ok(())
+        } else {
+            self.require(instruction, Feature::Sram)
+        }
+    }
+    
+    /// The byte address of the first byte of `buf`.
+    pub fn origin(&self) -> u32 {
+        self.origin
+    }
+    
+    /// The byte address the next instruction will be placed at.
+    pub fn address(&self) -> u32 {
+        self.origin + self.buf.len() as u32
+    }
+    
+    /// The image the assembler was created with, overwritten by `buf` at the
+    /// origin, or an error if any referenced label was never bound.
+    pub fn image(&self) -> Result<MemoryImage, AsmError> {
+        self.check_bound()?;
+        let mut image = self.image.clone();
+        image.insert(self.origin, &self.buf);
+        Ok(image)
+    }
+    
+    pub fn emit_bytes(&mut self, bytes: &[u8]) {
+        self.try_emit_bytes(bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+    
+    /// Appends raw bytes, such as a table of constants, at the current address.
+    /// Any bytes are taken to be what a skip instruction before them skips,
+    /// so helpers emitting several instructions may follow them.
+    pub fn try_emit_bytes(&mut self, bytes: &[u8]) -> Result<(), AsmError> {
+        self.check_flash(bytes.len())?;
+        self.buf.extend_from_slice(bytes);
+        if !bytes.is_empty() {
+            self.skipping = None;
+        }
+        Ok(())
+    }
+    
+    // Rejects an `lds` or `sts` address outside data space, or a `call` or `jmp`
+    // target outside flash. Labels are left alone, as they are always in flash.
+    fn check_target(&self, instruction: &'static str, offset: Offset) -> Result<(), AsmError> {
+        let device = match self.device {
+            Some(device) => device,
+            None => return Ok(()),
+        };
+        let address = match offset {
+            Offset::Absolute(address) => address,
+            _ => return Ok(()),
+        };
+        let reachable = match instruction {
+            "lds" | "sts" => device.in_data_space(address),
+            "call" | "jmp" => address < device.flash_size,
+            _ => true,
+        };
+        if reachable {
+            Ok(())
+        } else {
+            Err(AsmError::OutsideDevice { instruction, address, device: device.name })
+        }
+    }
+    
+    // Rejects code that would run past the end of the device's flash
+    fn check_flash(&self, length: usize) -> Result<(), AsmError> {
+        match self.device {
+            Some(device) if self.address() + length as u32 > device.flash_size => {
+                Err(AsmError::OutsideFlash { address: self.address(), flash_size: device.flash_size })
+            }
+            _ => Ok(()),
         }
     }
 }
 
-pub fn relative(x: i32) -> Offset {
-    assert!(x % 2 == 0);
-    Offset::Relative(x / 2)
-}
-pub fn absolute(x: u32) -> Offset {
-    Offset::Absolute(x)
-}
-
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Register(u32);
 
 pub const R0: Register = Register(0);
@@ -64,14 +229,21 @@ pub const R29: Register = Register(29);
 pub const R30: Register = Register(30);
 pub const R31: Register = Register(31);
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "r{}", self.0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct RegisterPair(pub Register, pub Register);
 
 pub const X: RegisterPair = RegisterPair(R27, R26);
 pub const Y: RegisterPair = RegisterPair(R29, R28);
 pub const Z: RegisterPair = RegisterPair(R31, R30);
 
-enum Direction {
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
     PreDecrement,
     NoChange,
     PostIncrement,
@@ -104,10 +276,10 @@ impl RegisterPair {
     }
 }
 
-impl Into<DirectionalRegisterPair> for RegisterPair {
-    fn into(self) -> DirectionalRegisterPair {
+impl From<RegisterPair> for DirectionalRegisterPair {
+    fn from(pair: RegisterPair) -> DirectionalRegisterPair {
         DirectionalRegisterPair{
-            pair: self,
+            pair,
             direction: Direction::NoChange,
         }
     }
@@ -124,10 +296,146 @@ impl Add<u8> for RegisterPair {
     }
 }
 
+// An instruction operand that refers to memory, with absolute addresses in
+// bytes and relative ones in words from the end of the instruction
 #[derive(Copy, Clone)]
-pub enum Offset {
+pub(crate) enum Offset {
+    Absolute(u32),
+    Relative(i32),
+    Label(Label),
+}
+
+/// A place in program memory, counted in 16-bit words, as jumps, calls,
+/// branches and `pm_lo8` and `pm_hi8` take.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProgramAddress {
+    /// Words from the start of flash.
     Absolute(u32),
+    /// Words from the end of the instruction.
     Relative(i32),
+    Label(Label),
+}
+
+impl ProgramAddress {
+    /// The word holding a byte address, which must be even.
+    pub fn from_byte_address(address: u32) -> Result<ProgramAddress, Constraint> {
+        if !address.is_multiple_of(2) {
+            return Err(Constraint::EvenOffset);
+        }
+        Ok(ProgramAddress::Absolute(address / 2))
+    }
+    
+    /// A distance in bytes from the end of the instruction, which must be even.
+    pub fn from_byte_distance(distance: i32) -> Result<ProgramAddress, Constraint> {
+        if distance % 2 != 0 {
+            return Err(Constraint::EvenOffset);
+        }
+        Ok(ProgramAddress::Relative(distance / 2))
+    }
+    
+    /// The byte address of an absolute program address.
+    pub fn byte_address(self) -> Option<u32> {
+        match self {
+            ProgramAddress::Absolute(words) => Some(words * 2),
+            _ => None,
+        }
+    }
+    
+    pub(crate) fn offset(self) -> Offset {
+        match self {
+            ProgramAddress::Absolute(words) => Offset::Absolute(words * 2),
+            ProgramAddress::Relative(words) => Offset::Relative(words),
+            ProgramAddress::Label(label) => Offset::Label(label),
+        }
+    }
+}
+
+impl From<Label> for ProgramAddress {
+    fn from(label: Label) -> ProgramAddress {
+        ProgramAddress::Label(label)
+    }
+}
+
+/// A place in data memory, counted in bytes, as `lds`, `sts`, `lo8` and `hi8`
+/// take. A label, which is in flash, stands for its address in data space on
+/// cores that map flash there, and for its byte address otherwise.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DataReference {
+    Address(DataAddress),
+    Label(Label),
+}
+
+impl DataReference {
+    pub(crate) fn offset(self) -> Offset {
+        match self {
+            DataReference::Address(DataAddress(address)) => Offset::Absolute(address),
+            DataReference::Label(label) => Offset::Label(label),
+        }
+    }
+}
+
+impl From<DataAddress> for DataReference {
+    fn from(address: DataAddress) -> DataReference {
+        DataReference::Address(address)
+    }
+}
+
+impl From<Label> for DataReference {
+    fn from(label: Label) -> DataReference {
+        DataReference::Label(label)
+    }
+}
+
+/// A position in the output that can be referenced before it is bound.
+///
+/// Created by `Assembler::new_label` or `Assembler::named_label`, and given an
+/// address by `Assembler::bind`. Instructions that reference a label before
+/// it is bound are patched when it gets bound.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Label(usize);
+
+
+struct LabelState {
+    name: Option<String>,
+    address: Option<u32>,
+    linkage: Linkage,
+}
+
+// Whether a label is visible to, or provided by, other object files
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Linkage {
+    Local,
+    Global,
+    External,
+}
+
+// How an offset is turned into the value of an instruction field
+#[derive(Copy, Clone, Debug)]
+enum OffsetKind {
+    Relative,        // words from the end of the instruction
+    Absolute,        // bytes from the start of the buffer
+    AbsoluteDoubles, // words from the start of the buffer
+    Lo8,             // bits 0-7 of the byte address
+    Hi8,             // bits 8-15 of the byte address
+    PmLo8,           // bits 0-7 of the word address
+    PmHi8,           // bits 8-15 of the word address
+    ReducedData,     // data address 0x40-0xBF, as the reduced core's lds and sts scramble it
+}
+
+// An instruction field that refers to a label
+struct Fixup {
+    instruction: &'static str,
+    position: usize,
+    label: Label,
+    kind: OffsetKind,
+    format: &'static [u8],
+    field: u8,
+    // Whether relaxation lengthens this even when it is turned off, as for a
+    // shortened `call` or `jmp` and the branches of control-flow builders
+    grows: bool,
+    // Whether a skip instruction comes right before, so the instruction must
+    // not become two
+    skipped: bool,
 }
 
 #[derive(Copy, Clone)]
@@ -137,17 +445,18 @@ enum Arg {
     U32(u32),
     U8(u8),
     I32(i32),
+    Offset(Offset, OffsetKind),
 }
 
-impl Into<Arg> for Register {
-    fn into(self) -> Arg {
-        Arg::Register(self)
+impl From<Register> for Arg {
+    fn from(r: Register) -> Arg {
+        Arg::Register(r)
     }
 }
 
-impl Into<Arg> for RegisterPair {
-    fn into(self) -> Arg {
-        Arg::RegisterPair(self)
+impl From<RegisterPair> for Arg {
+    fn from(pair: RegisterPair) -> Arg {
+        Arg::RegisterPair(pair)
     }
 }
 
@@ -174,211 +483,780 @@ struct ArgConsumer {
 }
 
 impl ArgConsumer {
-    fn new(arg: Arg, bit_count: usize) -> ArgConsumer{
-        ArgConsumer{
-            value: match arg {
-                Arg::Register(r) => {
-                    match bit_count {
-                        10 => {
-                            // This assumes a bit pattern xy xxxx yyyy
-                            assert!(r.0 < 32);
-                            (r.0 << 4) | ((r.0 & 16) << 9) | (r.0 & 0x0f)
-                        }
-                        5 => {
-                            assert!(r.0 < 32);
-                            r.0
-                        }
-                        4 => {
-                            assert!(16 <= r.0 && r.0 < 32);
-                            r.0 & 15
+    fn new(arg: Arg, bit_count: usize) -> Result<ArgConsumer, Constraint> {
+        let value = match arg {
+            Arg::Register(r) => {
+                match bit_count {
+                    10 => {
+                        // This assumes a bit pattern xy xxxx yyyy
+                        if r.0 >= 32 {
+                            return Err(Constraint::RegisterRange{ min: 0, max: 31 });
                         }
-                        3 => {
-                            assert!(16 <= r.0 && r.0 < 24);
-                            r.0 & 7
-                        }
-                        _ => {
-                            panic!("unrecognized number of register bits: {}", bit_count);
+                        (r.0 << 4) | ((r.0 & 16) << 5) | (r.0 & 0x0f)
+                    }
+                    5 => {
+                        if r.0 >= 32 {
+                            return Err(Constraint::RegisterRange{ min: 0, max: 31 });
                         }
+                        r.0
                     }
-                },
-                Arg::RegisterPair(RegisterPair(high, low)) => {
-                    if high.0 != low.0+1 {
-                        panic!("Register pair must be consecutive HI,LO registers");
+                    4 => {
+                        if !(16 <= r.0 && r.0 < 32) {
+                            return Err(Constraint::RegisterRange{ min: 16, max: 31 });
+                        }
+                        r.0 & 15
                     }
-                    if low.0 % 2 == 1 {
-                        panic!("Low register in register pair must be an even-numbered register");
+                    3 => {
+                        if !(16 <= r.0 && r.0 < 24) {
+                            return Err(Constraint::RegisterRange{ min: 16, max: 23 });
+                        }
+                        r.0 & 7
                     }
-                    let min_representable = 32 - 2 * (1 << bit_count);
-                    if low.0 < min_representable {
-                        panic!("Register pair out of representable range. Must be at least R{}", min_representable);
+                    _ => {
+                        panic!("unrecognized number of register bits: {}", bit_count);
                     }
-                    low.0 / 2
                 }
-                Arg::U32(x) => {
-                    x
+            },
+            Arg::RegisterPair(RegisterPair(high, low)) => {
+                if high.0 != low.0+1 {
+                    return Err(Constraint::ConsecutivePair);
                 }
-                Arg::U8(x) => {
-                    x as u32
+                if low.0 % 2 == 1 {
+                    return Err(Constraint::EvenPair);
                 }
-                Arg::I32(x) => {
-                    // TODO: Validate that it fits in the big count?
-                    x as u32
+                let min_representable = 32 - 2 * (1 << bit_count);
+                if low.0 < min_representable {
+                    return Err(Constraint::PairRange{ min: min_representable });
                 }
+                low.0 / 2
             }
-        }
+            Arg::U32(x) => {
+                check_unsigned(x, bit_count)?
+            }
+            Arg::U8(x) => {
+                check_unsigned(x as u32, bit_count)?
+            }
+            Arg::I32(x) => {
+                let min = -(1i64 << (bit_count - 1));
+                let max = (1i64 << (bit_count - 1)) - 1;
+                if (x as i64) < min || (x as i64) > max {
+                    return Err(Constraint::Range{ min, max });
+                }
+                // Keep only the low bits, so the sign does not spill into neighbouring fields
+                (x as u32) & (((1u64 << bit_count) - 1) as u32)
+            }
+            Arg::Offset(..) => {
+                panic!("offsets must be resolved before encoding");
+            }
+        };
+        Ok(ArgConsumer{ value })
     }
     
     fn consume_bit(&mut self) -> u32 {
         let ret = self.value & 1;
-        self.value = self.value >> 1;
+        self.value >>= 1;
         //println!("consumed {}, has {} left", ret, self.value);
-        return ret;
+        ret
+    }
+}
+
+fn check_unsigned(x: u32, bit_count: usize) -> Result<u32, Constraint> {
+    let max = (1u64 << bit_count) - 1;
+    if x as u64 > max {
+        return Err(Constraint::Range{ min: 0, max: max as i64 });
+    }
+    Ok(x)
+}
+
+// Packs the arguments into the bit positions named by `format`, returning
+// the instruction and its length in bits, or the field whose argument broke
+// a constraint.
+fn assemble(args: &[(Arg, u8)], format: &[u8]) -> Result<(u32, usize), (u8, Constraint)> {
+    let mut occurrence_count = [0; 256];
+    for format_byte in format {
+        occurrence_count[*format_byte as usize] += 1;
+    }
+    
+    let mut arg_consumers: [Option<ArgConsumer>; 256] = [None; 256];
+    for &(arg, format_byte) in args {
+        let consumer = ArgConsumer::new(arg, occurrence_count[format_byte as usize]).map_err(|constraint| (format_byte, constraint))?;
+        arg_consumers[format_byte as usize] = Some(consumer);
+    }
+    
+    let mut result: u32 = 0;
+    let mut result_bits: usize = 0;
+    
+    for format_byte in format.iter().rev() {
+        //println!("{}", format_byte);
+        let bit = match *format_byte {
+            b' ' => { continue },
+            b'0' => 0,
+            b'1' => 1,
+            ch => arg_consumers[ch as usize].as_mut().expect("Format character has no argument").consume_bit()
+        };
+        result |= bit << result_bits;
+        result_bits += 1;
+    }
+    
+    Ok((result, result_bits))
+}
+
+fn instruction_bits(format: &[u8]) -> usize {
+    format.iter().filter(|&&b| b != b' ').count()
+}
+
+fn field_bits(format: &[u8], field: u8) -> usize {
+    format.iter().filter(|&&b| b == field).count()
+}
+
+// The 7-bit field of the reduced core's lds and sts holds address bits 5, 4,
+// 6, 3, 2, 1, 0 in that order; bit 7 is the inverse of bit 6.
+fn reduced_data_field(address: u32) -> Result<u32, Constraint> {
+    if !(0x40..=0xbf).contains(&address) {
+        return Err(Constraint::Range { min: 0x40, max: 0xbf });
+    }
+    Ok((address & 0x30) << 1 | (address & 0x40) >> 2 | address & 0x0f)
+}
+
+// Overwrites one field of the instruction already stored at `position`,
+// leaving its other bits alone
+fn patch_field(buf: &mut [u8], position: usize, format: &[u8], field: u8, mut consumer: ArgConsumer) {
+    let words = instruction_bits(format) / 16;
+    let mut result: u32 = 0;
+    for word_index in 0..words {
+        let low = buf[position + word_index*2] as u32;
+        let high = buf[position + word_index*2 + 1] as u32;
+        result = (result << 16) | (high << 8) | low;
+    }
+    
+    let mut result_bit: usize = 0;
+    for format_byte in format.iter().rev() {
+        match *format_byte {
+            b' ' => { continue },
+            ch if ch == field => {
+                result = (result & !(1 << result_bit)) | (consumer.consume_bit() << result_bit);
+            }
+            _ => {}
+        }
+        result_bit += 1;
+    }
+    
+    for word_index in 0..words {
+        let word = result >> (16 * (words - 1 - word_index));
+        buf[position + word_index*2] = word as u8;
+        buf[position + word_index*2 + 1] = (word >> 8) as u8;
     }
 }
 
 impl Assembler {
-    fn encode(&mut self, args: &[(Arg, u8)], format: &[u8]) {
-        let mut occurrence_count = [0; 256];
-        for format_byte in format {
-            occurrence_count[*format_byte as usize] += 1;
+    /// Creates a label that is not yet bound to an address.
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(LabelState{ name: None, address: None, linkage: Linkage::Local });
+        Label(self.labels.len() - 1)
+    }
+    
+    /// Creates a label with a name, which is used when reporting it.
+    pub fn named_label(&mut self, name: &str) -> Label {
+        self.labels.push(LabelState{ name: Some(name.to_string()), address: None, linkage: Linkage::Local });
+        Label(self.labels.len() - 1)
+    }
+    
+    /// Creates a label for a symbol defined in another object file. It cannot
+    /// be bound; references to it become relocations in a `RelocatableObject`.
+    pub fn external_label(&mut self, name: &str) -> Label {
+        self.labels.push(LabelState{ name: Some(name.to_string()), address: None, linkage: Linkage::External });
+        Label(self.labels.len() - 1)
+    }
+    
+    /// Makes a named label a global symbol of a `RelocatableObject`, so other
+    /// object files can refer to it.
+    pub fn export(&mut self, label: Label) {
+        let state = &mut self.labels[label.0];
+        assert!(state.name.is_some(), "only named labels can be exported");
+        assert!(state.linkage != Linkage::External, "external labels cannot be exported");
+        state.linkage = Linkage::Global;
+    }
+    
+    /// Binds `label` to the current address and patches every instruction
+    /// already emitted that refers to it.
+    pub fn bind(&mut self, label: Label) {
+        self.try_bind(label).unwrap_or_else(|err| panic!("{}", err))
+    }
+    
+    pub fn try_bind(&mut self, label: Label) -> Result<(), AsmError> {
+        if self.labels[label.0].address.is_some() {
+            return Err(AsmError::LabelAlreadyBound{ label, name: self.labels[label.0].name.clone() });
+        }
+        if self.labels[label.0].linkage == Linkage::External {
+            return Err(AsmError::ExternalLabelBound{ label, name: self.labels[label.0].name.clone() });
         }
+        self.labels[label.0].address = Some(self.address());
         
-        let mut arg_consumers: [Option<ArgConsumer>; 256] = [None; 256];
+        // Patch every reference even if one is out of range, and report the first
+        // failure, leaving those that relaxation can lengthen until the end
+        let mut result = Ok(());
+        let mut relax = false;
+        for index in 0..self.fixups.len() {
+            if self.fixups[index].label == label {
+                match self.apply_fixup(index) {
+                    Err(_) if self.relaxable(&self.fixups[index]) => relax = true,
+                    Err(err) if result.is_ok() => result = Err(err),
+                    _ => {}
+                }
+            }
+        }
+        result?;
+        if relax {
+            self.relax_branches()?;
+        }
+        Ok(())
+    }
+    
+    pub fn label_address(&self, label: Label) -> Option<u32> {
+        self.labels[label.0].address
+    }
+    
+    pub fn label_name(&self, label: Label) -> Option<&str> {
+        self.labels[label.0].name.as_ref().map(|name| &name[..])
+    }
+    
+    /// The name and address of every named label that is bound.
+    pub fn symbols(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.labels.iter().filter_map(|state| {
+            match (&state.name, state.address) {
+                (Some(name), Some(address)) => Some((&name[..], address)),
+                _ => None,
+            }
+        })
+    }
+    
+    /// Returns the assembled bytes, or an error if any referenced label was never bound.
+    pub fn finish(self) -> Result<Vec<u8>, AsmError> {
+        self.check_bound()?;
+        Ok(self.buf)
+    }
+    
+    // Rejects output with a field still waiting for its label, which would
+    // otherwise be written as zeros
+    pub(crate) fn check_bound(&self) -> Result<(), AsmError> {
+        for fixup in self.fixups.iter() {
+            let state = &self.labels[fixup.label.0];
+            if state.address.is_none() {
+                return Err(AsmError::UnboundLabel{ label: fixup.label, name: state.name.clone() });
+            }
+        }
+        Ok(())
+    }
+    
+    fn encode(&mut self, instruction: &'static str, args: &[(Arg, u8)], format: &'static [u8]) -> Result<(), AsmError> {
+        if let Some(result) = self.shorten_call(instruction, args) {
+            return result;
+        }
+        let position = self.buf.len();
+        self.check_flash(instruction_bits(format) / 8)?;
+        let mut resolved_args: Vec<(Arg, u8)> = Vec::with_capacity(args.len());
+        let mut fixups = Vec::new();
+        let mut relax = false;
+        let first_register = self.core.first_register();
         for &(arg, format_byte) in args {
-            arg_consumers[format_byte as usize] = Some(ArgConsumer::new(arg, occurrence_count[format_byte as usize]));
+            if let Arg::Register(Register(r)) = arg {
+                if r < first_register {
+                    return Err(AsmError::invalid_operand(instruction, format_byte, Constraint::RegisterRange { min: first_register, max: 31 }));
+                }
+            }
+            let arg = match arg {
+                Arg::Offset(Offset::Label(label), kind) => {
+                    let fixup = Fixup{
+                        instruction,
+                        position,
+                        label,
+                        kind,
+                        format,
+                        field: format_byte,
+                        grows: self.grow,
+                        skipped: self.skipping.is_some(),
+                    };
+                    let value = match self.label_value(label, kind, position, format) {
+                        Some(value) => value.map_err(|constraint| AsmError::invalid_operand(instruction, format_byte, constraint))?,
+                        None => Arg::U32(0),
+                    };
+                    // A branch that does not reach is stored as is, then lengthened
+                    let value = if self.relaxable(&fixup) && ArgConsumer::new(value, field_bits(format, format_byte)).is_err() {
+                        relax = true;
+                        Arg::I32(0)
+                    } else {
+                        value
+                    };
+                    fixups.push(fixup);
+                    value
+                }
+                Arg::Offset(offset, kind) => {
+                    self.check_target(instruction, offset)?;
+                    self.offset_value(offset, kind, format).map_err(|constraint| AsmError::invalid_operand(instruction, format_byte, constraint))?
+                }
+                arg => arg
+            };
+            resolved_args.push((arg, format_byte));
         }
         
-        let mut result: u32 = 0;
-        let mut result_bits: usize = 0;
-        
-        for format_byte in format.iter().rev() {
-            //println!("{}", format_byte);
-            let bit = match *format_byte {
-                b' ' => { continue },
-                b'0' => 0,
-                b'1' => 1,
-                ch => arg_consumers[ch as usize].as_mut().expect("Format character has no argument").consume_bit()
-            };
-            result = result | (bit << result_bits);
-            result_bits += 1;
+        let (result, result_bits) = assemble(&resolved_args, format)
+            .map_err(|(field, constraint)| AsmError::invalid_operand(instruction, field, constraint))?;
+        self.store(position, result, result_bits);
+        self.fixups.extend(fixups);
+        self.skipping = if is_skip(instruction) { Some(instruction) } else { None };
+        if relax {
+            self.relax_branches()?;
         }
+        Ok(())
+    }
+    
+    /// The skip instruction emitted last, if the last instruction was one,
+    /// which will skip the next instruction when its condition holds.
+    pub fn skipping(&self) -> Option<&'static str> {
+        self.skipping
+    }
+
+    // Fails if a skip instruction was emitted last and `sequence` is about to
+    // emit more than one instruction. A single one is skipped as a whole.
+    pub(crate) fn check_not_skipped(&self, sequence: &'static str, instructions: u32) -> Result<(), AsmError> {
+        match self.skipping {
+            Some(skip) if instructions > 1 => Err(AsmError::SkippedSequence { skip, sequence }),
+            _ => Ok(()),
+        }
+    }
+
+    fn label_value(&self, label: Label, kind: OffsetKind, position: usize, format: &[u8]) -> Option<Result<Arg, Constraint>> {
+        self.labels[label.0].address.map(|address| {
+            // Labels are in flash, which `ld` reads at an offset on the reduced core
+            let data_address = address + self.core.flash_in_data_space().unwrap_or(0);
+            Ok(match kind {
+                OffsetKind::Relative => {
+                    let next = self.origin as usize + position + instruction_bits(format) / 8;
+                    Arg::I32(self.wrap_distance((address as i32 - next as i32) / 2))
+                }
+                OffsetKind::Absolute => Arg::U32(address),
+                OffsetKind::AbsoluteDoubles => Arg::U32(address / 2),
+                OffsetKind::Lo8 => Arg::U32(data_address & 0xff),
+                OffsetKind::Hi8 => Arg::U32((data_address >> 8) & 0xff),
+                OffsetKind::PmLo8 => Arg::U32((address >> 1) & 0xff),
+                OffsetKind::PmHi8 => Arg::U32((address >> 9) & 0xff),
+                OffsetKind::ReducedData => Arg::U32(reduced_data_field(address)?),
+            })
+        })
+    }
+    
+    fn apply_fixup(&mut self, index: usize) -> Result<(), AsmError> {
+        let (instruction, position, format, field, value) = {
+            let fixup = &self.fixups[index];
+            let value = self.label_value(fixup.label, fixup.kind, fixup.position, fixup.format).expect("fixup label is unbound");
+            (fixup.instruction, fixup.position, fixup.format, fixup.field, value)
+        };
         
-        assert!(result_bits % 16 == 0);
+        let value = value.map_err(|constraint| AsmError::invalid_operand(instruction, field, constraint))?;
+        let consumer = ArgConsumer::new(value, field_bits(format, field))
+            .map_err(|constraint| AsmError::invalid_operand(instruction, field, constraint))?;
+        patch_field(&mut self.buf, position, format, field, consumer);
+        Ok(())
+    }
+    
+    // Writes an encoded instruction at `position`, which is either inside `buf`
+    // (when patching) or at its end.
+    fn store(&mut self, position: usize, result: u32, mut result_bits: usize) {
+        assert!(result_bits.is_multiple_of(16));
+        let mut position = position;
         while result_bits > 0 {
-            self.buf.push(((result >> (result_bits - 16)) & 0xff) as u8);
-            self.buf.push(((result >> (result_bits - 8)) & 0xff) as u8);
+            let bytes = [((result >> (result_bits - 16)) & 0xff) as u8, ((result >> (result_bits - 8)) & 0xff) as u8];
+            for &byte in bytes.iter() {
+                if position < self.buf.len() {
+                    self.buf[position] = byte;
+                } else {
+                    self.buf.push(byte);
+                }
+                position += 1;
+            }
             result_bits -= 16;
         }
     }
     
-    fn resolve_absolute_offset(&self, offset: Offset) -> u32 {
+    // The value of an instruction field for an offset that is not a label
+    fn offset_value(&self, offset: Offset, kind: OffsetKind, format: &[u8]) -> Result<Arg, Constraint> {
+        let absolute = self.known_absolute_offset(offset).expect("offset is not a label");
+        Ok(match kind {
+            OffsetKind::Relative => {
+                match offset {
+                    Offset::Relative(x) => Arg::I32(x),
+                    _ => {
+                        // In words from the end of the instruction, as for a label
+                        let next = self.address() + instruction_bits(format) as u32 / 8;
+                        let distance = absolute as i32 - next as i32;
+                        if distance % 2 != 0 {
+                            return Err(Constraint::EvenOffset);
+                        }
+                        Arg::I32(self.wrap_distance(distance / 2))
+                    }
+                }
+            }
+            OffsetKind::Absolute => Arg::U32(absolute),
+            OffsetKind::AbsoluteDoubles => {
+                if !absolute.is_multiple_of(2) {
+                    return Err(Constraint::EvenOffset);
+                }
+                Arg::U32(absolute / 2)
+            }
+            OffsetKind::Lo8 => Arg::U32(absolute & 0xff),
+            OffsetKind::Hi8 => Arg::U32((absolute >> 8) & 0xff),
+            OffsetKind::PmLo8 | OffsetKind::PmHi8 if !absolute.is_multiple_of(2) => {
+                return Err(Constraint::EvenOffset);
+            }
+            OffsetKind::PmLo8 => Arg::U32((absolute >> 1) & 0xff),
+            OffsetKind::PmHi8 => Arg::U32((absolute >> 9) & 0xff),
+            OffsetKind::ReducedData => Arg::U32(reduced_data_field(absolute)?),
+        })
+    }
+    
+    // The absolute address an offset refers to, if it is already known
+    fn known_absolute_offset(&self, offset: Offset) -> Option<u32> {
         match offset {
-            Offset::Absolute(x) => x,
-            Offset::Relative(x) => (self.buf.len() as i32 + x) as u32
+            Offset::Absolute(x) => Some(x),
+            Offset::Relative(x) => Some((self.address() as i32 + 2 * x) as u32),
+            Offset::Label(label) => self.label_address(label),
         }
     }
-    fn resolve_absolute_offset_doubles(&self, offset: Offset) -> u32 {
-        let offset = self.resolve_absolute_offset(offset);
-        assert!(offset % 2 == 0, "offset must be even");
-        offset / 2
+    
+    pub fn lds<D: Into<DataReference>>(&mut self, d: Register, k: D) {
+        self.try_lds(d, k).unwrap_or_else(|err| panic!("{}", err))
+    }
+    
+    /// Loads from data space with the form the core has: one word on the
+    /// reduced core, which only reaches 0x40 to 0xBF, and two words elsewhere.
+    pub fn try_lds<D: Into<DataReference>>(&mut self, d: Register, k: D) -> Result<(), AsmError> {
+        if self.core.has(Feature::Reduced) {
+            self.try_lds_7(d, k)
+        } else {
+            self.try_lds_16(d, k)
+        }
     }
-    fn resolve_relative_offset(&self, offset: Offset) -> i32 {
-        (self.resolve_absolute_offset(offset) as i32) - (self.buf.len() as i32)
+    
+    pub fn sts<D: Into<DataReference>>(&mut self, k: D, d: Register) {
+        self.try_sts(k, d).unwrap_or_else(|err| panic!("{}", err))
     }
     
-    pub fn lds(&mut self, d: Register, k: Offset) {
-        let addr = self.resolve_absolute_offset(k);
-        //println!("resolved to {}", addr);
-        if addr < 128 {
-            self.lds_7(d, k);
+    /// Stores to data space with the form the core has, as `try_lds` loads.
+    pub fn try_sts<D: Into<DataReference>>(&mut self, k: D, d: Register) -> Result<(), AsmError> {
+        if self.core.has(Feature::Reduced) {
+            self.try_sts_7(k, d)
         } else {
-            self.lds_16(d, k);
+            self.try_sts_16(k, d)
         }
     }
     
     pub fn ld<R: Into<DirectionalRegisterPair>>(&mut self, d: Register, r: R) {
-        let r: DirectionalRegisterPair = r.into();
-        let template = match (r.pair, r.direction) {
-            (x, Direction::NoChange) if x == X => b"1001 000d dddd 1100",
-            (x, Direction::PostIncrement) if x == X => b"1001 000d dddd 1101",
-            (x, Direction::PreDecrement) if x == X => b"1001 000d dddd 1110",
-            (y, Direction::NoChange) if y == Y => b"1000 000d dddd 1000",
-            (y, Direction::PostIncrement) if y == Y => b"1001 000d dddd 1001",
-            (y, Direction::PreDecrement) if y == Y => b"1001 000d dddd 1010",
-            (z, Direction::NoChange) if z == Z => b"1000 000d dddd 0000",
-            (z, Direction::PostIncrement) if z == Z => b"1001 000d dddd 0001",
-            (z, Direction::PreDecrement) if z == Z => b"1001 000d dddd 0010",
-            _ => panic!("Invalid LD arguments")
-        };
-        
-        self.encode(&[(d.into(), b'd')][..], template)
+        self.try_ld(d, r).unwrap_or_else(|err| panic!("{}", err))
+    }
+    
+    pub fn try_ld<R: Into<DirectionalRegisterPair>>(&mut self, d: Register, r: R) -> Result<(), AsmError> {
+        let r = r.into();
+        self.require_pointer("ld", &r)?;
+        let template = pointer_template(&LD_FORMS, r)
+            .ok_or_else(|| AsmError::invalid_operand("ld", b'p', Constraint::PointerMode))?;
+        self.encode("ld", &[(d.into(), b'd')][..], template)
     }
     
     pub fn ldd(&mut self, d: Register, r: OffsetRegisterPair) {
-        let template = 
-            if r.pair == Y {
-                b"10q0 qq0d dddd 1qqq"
-            } else if r.pair == Z {
-                b"10q0 qq0d dddd 0qqq"
-            } else {
-                panic!("Invalid pointer for LDD");
-            };
-        self.encode(&[(d.into(), b'd'), (r.offset.into(), b'q')][..], template)
+        self.try_ldd(d, r).unwrap_or_else(|err| panic!("{}", err))
+    }
+    
+    pub fn try_ldd(&mut self, d: Register, r: OffsetRegisterPair) -> Result<(), AsmError> {
+        self.require("ldd", Feature::Adiw)?;
+        let template = displacement_template(&LDD_FORMS, r.pair)
+            .ok_or_else(|| AsmError::invalid_operand("ldd", b'p', Constraint::PointerMode))?;
+        self.encode("ldd", &[(d.into(), b'd'), (r.offset.into(), b'q')][..], template)
     }
     
     pub fn st<D: Into<DirectionalRegisterPair>>(&mut self, d: D, r: Register) {
-        let d: DirectionalRegisterPair = d.into();
-        let template = match (d.pair, d.direction) {
-            (x, Direction::NoChange) if x == X => b"1001 001r rrrr 1100",
-            (x, Direction::PostIncrement) if x == X => b"1001 001r rrrr 1101",
-            (x, Direction::PreDecrement) if x == X => b"1001 001r rrrr 1110",
-            (y, Direction::NoChange) if y == Y => b"1000 001r rrrr 1000",
-            (y, Direction::PostIncrement) if y == Y => b"1001 001r rrrr 1001",
-            (y, Direction::PreDecrement) if y == Y => b"1001 001r rrrr 1010",
-            (z, Direction::NoChange) if z == Z => b"1000 001r rrrr 0000",
-            (z, Direction::PostIncrement) if z == Z => b"1001 001r rrrr 0001",
-            (z, Direction::PreDecrement) if z == Z => b"1001 001r rrrr 0010",
-            _ => panic!("Invalid ST arguments")
-        };
-        
-        self.encode(&[(r.into(), b'r')][..], template)
+        self.try_st(d, r).unwrap_or_else(|err| panic!("{}", err))
+    }
+    
+    pub fn try_st<D: Into<DirectionalRegisterPair>>(&mut self, d: D, r: Register) -> Result<(), AsmError> {
+        let d = d.into();
+        self.require_pointer("st", &d)?;
+        let template = pointer_template(&ST_FORMS, d)
+            .ok_or_else(|| AsmError::invalid_operand("st", b'p', Constraint::PointerMode))?;
+        self.encode("st", &[(r.into(), b'r')][..], template)
     }
     
     pub fn std(&mut self, d: OffsetRegisterPair, r: Register) {
-        let template = 
-            if d.pair == Y {
-                b"10q0 qq1r rrrr 1qqq"
-            } else if d.pair == Z {
-                b"10q0 qq1r rrrr 0qqq"
-            } else {
-                panic!("Invalid pointer for STD");
-            };
-        self.encode(&[(r.into(), b'r'), (d.offset.into(), b'q')][..], template)
+        self.try_std(d, r).unwrap_or_else(|err| panic!("{}", err))
+    }
+    
+    pub fn try_std(&mut self, d: OffsetRegisterPair, r: Register) -> Result<(), AsmError> {
+        self.require("std", Feature::Adiw)?;
+        let template = displacement_template(&STD_FORMS, d.pair)
+            .ok_or_else(|| AsmError::invalid_operand("std", b'p', Constraint::PointerMode))?;
+        self.encode("std", &[(r.into(), b'r'), (d.offset.into(), b'q')][..], template)
     }
     
     pub fn lpm<R: Into<DirectionalRegisterPair>>(&mut self, d: Register, r: R) {
-        let r: DirectionalRegisterPair = r.into();
-        let template = match (r.pair, r.direction) {
-            (z, Direction::NoChange) if z == Z => b"1001 000d dddd 0100",
-            (z, Direction::PostIncrement) if z == Z => b"1001 000d dddd 0101",
-            _ => panic!("Invalid LPM arguments")
-        };
-        
-        self.encode(&[(d.into(), b'd')][..], template)
+        self.try_lpm(d, r).unwrap_or_else(|err| panic!("{}", err))
+    }
+    
+    pub fn try_lpm<R: Into<DirectionalRegisterPair>>(&mut self, d: Register, r: R) -> Result<(), AsmError> {
+        self.require("lpm", Feature::LpmX)?;
+        let template = pointer_template(&LPM_FORMS, r.into())
+            .ok_or_else(|| AsmError::invalid_operand("lpm", b'p', Constraint::PointerMode))?;
+        self.encode("lpm", &[(d.into(), b'd')][..], template)
     }
     
     pub fn elpm<R: Into<DirectionalRegisterPair>>(&mut self, d: Register, r: R) {
-        let r: DirectionalRegisterPair = r.into();
-        let template = match (r.pair, r.direction) {
-            (z, Direction::NoChange) if z == Z => b"1001 000d dddd 0110",
-            (z, Direction::PostIncrement) if z == Z => b"1001 000d dddd 0111",
-            _ => panic!("Invalid ELPM arguments")
-        };
-        
-        self.encode(&[(d.into(), b'd')][..], template)
+        self.try_elpm(d, r).unwrap_or_else(|err| panic!("{}", err))
+    }
+    
+    pub fn try_elpm<R: Into<DirectionalRegisterPair>>(&mut self, d: Register, r: R) -> Result<(), AsmError> {
+        self.require("elpm", Feature::ElpmX)?;
+        let template = pointer_template(&ELPM_FORMS, r.into())
+            .ok_or_else(|| AsmError::invalid_operand("elpm", b'p', Constraint::PointerMode))?;
+        self.encode("elpm", &[(d.into(), b'd')][..], template)
     }
 }
 
+// Templates for the instructions whose pointer operand selects the opcode,
+// shared by the assembler and the decoder
+type PointerForm = (RegisterPair, Direction, &'static [u8]);
+type DisplacementForm = (RegisterPair, &'static [u8]);
+
+static LD_FORMS: [PointerForm; 9] = [
+    (X, Direction::NoChange, b"1001 000d dddd 1100"),
+    (X, Direction::PostIncrement, b"1001 000d dddd 1101"),
+    (X, Direction::PreDecrement, b"1001 000d dddd 1110"),
+    (Y, Direction::NoChange, b"1000 000d dddd 1000"),
+    (Y, Direction::PostIncrement, b"1001 000d dddd 1001"),
+    (Y, Direction::PreDecrement, b"1001 000d dddd 1010"),
+    (Z, Direction::NoChange, b"1000 000d dddd 0000"),
+    (Z, Direction::PostIncrement, b"1001 000d dddd 0001"),
+    (Z, Direction::PreDecrement, b"1001 000d dddd 0010"),
+];
+
+static ST_FORMS: [PointerForm; 9] = [
+    (X, Direction::NoChange, b"1001 001r rrrr 1100"),
+    (X, Direction::PostIncrement, b"1001 001r rrrr 1101"),
+    (X, Direction::PreDecrement, b"1001 001r rrrr 1110"),
+    (Y, Direction::NoChange, b"1000 001r rrrr 1000"),
+    (Y, Direction::PostIncrement, b"1001 001r rrrr 1001"),
+    (Y, Direction::PreDecrement, b"1001 001r rrrr 1010"),
+    (Z, Direction::NoChange, b"1000 001r rrrr 0000"),
+    (Z, Direction::PostIncrement, b"1001 001r rrrr 0001"),
+    (Z, Direction::PreDecrement, b"1001 001r rrrr 0010"),
+];
+
+static LPM_FORMS: [PointerForm; 2] = [
+    (Z, Direction::NoChange, b"1001 000d dddd 0100"),
+    (Z, Direction::PostIncrement, b"1001 000d dddd 0101"),
+];
+
+static ELPM_FORMS: [PointerForm; 2] = [
+    (Z, Direction::NoChange, b"1001 000d dddd 0110"),
+    (Z, Direction::PostIncrement, b"1001 000d dddd 0111"),
+];
+
+static LDD_FORMS: [DisplacementForm; 2] = [
+    (Y, b"10q0 qq0d dddd 1qqq"),
+    (Z, b"10q0 qq0d dddd 0qqq"),
+];
+
+static STD_FORMS: [DisplacementForm; 2] = [
+    (Y, b"10q0 qq1r rrrr 1qqq"),
+    (Z, b"10q0 qq1r rrrr 0qqq"),
+];
+
+fn pointer_template(forms: &[PointerForm], r: DirectionalRegisterPair) -> Option<&'static [u8]> {
+    forms.iter()
+        .find(|&&(pair, direction, _)| pair == r.pair && direction == r.direction)
+        .map(|&(_, _, template)| template)
+}
+
+fn displacement_template(forms: &[DisplacementForm], pair: RegisterPair) -> Option<&'static [u8]> {
+    forms.iter()
+        .find(|&&(form_pair, _)| form_pair == pair)
+        .map(|&(_, template)| template)
+}
+
 
 include!(concat!(env!("OUT_DIR"), "/ops.rs"));
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn absolute_relative_branch_counts_words_from_the_end() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.rjmp(ProgramAddress::Absolute(10));
+        asm.breq(ProgramAddress::Absolute(0));
+        assert_eq!(asm.buf, [0x09, 0xc0, 0xf1, 0xf3]);
+    }
+
+    #[test]
+    fn program_addresses_count_words_and_data_addresses_bytes() {
+        assert_eq!(ProgramAddress::from_byte_address(0x200), Ok(ProgramAddress::Absolute(0x100)));
+        assert_eq!(ProgramAddress::from_byte_address(0x201), Err(Constraint::EvenOffset));
+        assert_eq!(ProgramAddress::from_byte_distance(-4), Ok(ProgramAddress::Relative(-2)));
+        assert_eq!(ProgramAddress::from_byte_distance(3), Err(Constraint::EvenOffset));
+        assert_eq!(ProgramAddress::Absolute(0x100).byte_address(), Some(0x200));
+        assert_eq!(ProgramAddress::Relative(1).byte_address(), None);
+
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.jmp(ProgramAddress::Absolute(0x100));
+        asm.lds(R16, DataAddress(0x100));
+        assert_eq!(asm.buf, [0x0c, 0x94, 0x00, 0x01, 0x00, 0x91, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn labels_are_patched_when_bound() {
+        let mut asm = Assembler::new(Core::Avr5);
+        let top = asm.named_label("top");
+        let end = asm.new_label();
+        asm.bind(top);
+        asm.rjmp(end);
+        asm.nop();
+        asm.rjmp(top);
+        asm.bind(end);
+        assert_eq!(asm.label_address(end), Some(6));
+        assert_eq!(asm.symbols().collect::<Vec<_>>(), [("top", 0)]);
+        assert_eq!(asm.finish().unwrap(), [0x02, 0xc0, 0x00, 0x00, 0xfd, 0xcf]);
+    }
+
+    #[test]
+    fn labels_are_bound_once_and_must_be_bound() {
+        let mut asm = Assembler::new(Core::Avr5);
+        let label = asm.named_label("twice");
+        asm.bind(label);
+        match asm.try_bind(label) {
+            Err(AsmError::LabelAlreadyBound { name: Some(ref name), .. }) if name == "twice" => {}
+            result => panic!("{:?}", result),
+        }
+        let unbound = asm.new_label();
+        asm.rcall(unbound);
+        match asm.image() {
+            Err(AsmError::UnboundLabel { label, name: None }) if label == unbound => {}
+            result => panic!("{:?}", result.map(|image| image.bounds())),
+        }
+        match asm.finish() {
+            Err(AsmError::UnboundLabel { label, name: None }) if label == unbound => {}
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn try_methods_return_errors_and_emit_nothing() {
+        let mut asm = Assembler::new(Core::Avr5);
+        let err = asm.try_out(64, R0).unwrap_err();
+        assert_eq!(err, AsmError::InvalidOperand { instruction: "out", operand: "A", constraint: Constraint::Range { min: 0, max: 63 } });
+        assert!(asm.buf.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "out: operand A must be between 0 and 63")]
+    fn panicking_methods_report_the_error() {
+        Assembler::new(Core::Avr5).out(64, R0);
+    }
+
+    #[test]
+    fn skip_instructions_are_tracked_until_the_next_instruction() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.cpse(R0, R1);
+        assert_eq!(asm.skipping(), Some("cpse"));
+        asm.sbrc(R0, Bit::B1);
+        assert_eq!(asm.skipping(), Some("sbrc"));
+        asm.sbic(IoAddress(0x03), Bit::B0);
+        assert_eq!(asm.skipping(), Some("sbic"));
+        asm.sbis(IoAddress(0x03), Bit::B0);
+        match asm.try_ldi_wide(RegisterGroup::new(R16, 2).unwrap(), 0) {
+            Err(AsmError::SkippedSequence { skip: "sbis", sequence: "ldi_wide" }) => {}
+            result => panic!("{:?}", result),
+        }
+        asm.nop();
+        assert_eq!(asm.skipping(), None);
+        asm.ldi_wide(RegisterGroup::new(R16, 2).unwrap(), 0);
+    }
+
+    #[test]
+    fn skipped_branches_are_not_relaxed_but_skipped_jumps_are() {
+        let mut asm = Assembler::for_device(&ATMEGA328P);
+        asm.set_relaxation(true);
+        let end = asm.new_label();
+        asm.sbrs(R5, Bit::B0);
+        asm.breq(end);
+        for _ in 0..64 {
+            asm.nop();
+        }
+        assert!(asm.try_bind(end).is_err());
+
+        let mut asm = Assembler::for_device(&ATMEGA328P);
+        asm.set_relaxation(true);
+        let end = asm.new_label();
+        asm.sbrs(R5, Bit::B0);
+        asm.rjmp(end);
+        for _ in 0..2048 {
+            asm.nop();
+        }
+        asm.bind(end);
+        assert_eq!(asm.buf[..6], [0x50, 0xfe, 0x0c, 0x94, 0x03, 0x08]);
+    }
+
+    #[test]
+    fn raw_bytes_end_a_skip() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.sbrs(R5, Bit::B0);
+        asm.emit_bytes(&[]);
+        assert_eq!(asm.skipping(), Some("sbrs"));
+        asm.emit_bytes(&[0x00, 0x00]);
+        assert_eq!(asm.skipping(), None);
+        asm.ldi_wide(RegisterGroup::new(R16, 2).unwrap(), 0x1234);
+    }
+
+    #[test]
+    fn repeated_register_fields_hold_the_register_twice() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.clr(R16);
+        asm.lsl(R31);
+        asm.tst(R17);
+        asm.rol(R0);
+        assert_eq!(asm.buf, [0x00, 0x27, 0xff, 0x0f, 0x11, 0x23, 0x00, 0x1c]);
+    }
+
+    #[test]
+    fn operands_are_checked_against_their_fields() {
+        let mut asm = Assembler::new(Core::Avr5);
+        let range = |min, max| Constraint::Range { min, max };
+        let constraint = |result: Result<(), AsmError>| match result {
+            Err(AsmError::InvalidOperand { constraint, .. }) => constraint,
+            result => panic!("{:?}", result),
+        };
+        assert_eq!(constraint(asm.try_ldd(R0, Y + 64)), range(0, 63));
+        assert_eq!(constraint(asm.try_adiw(word_pair::R25_R24, 64)), range(0, 63));
+        assert_eq!(constraint(asm.try_ldi(upper::R16, 256)), range(0, 255));
+        assert_eq!(constraint(asm.try_brne(ProgramAddress::Relative(64))), range(-64, 63));
+        assert_eq!(constraint(asm.try_rjmp(ProgramAddress::Relative(-2049))), range(-2048, 2047));
+        asm.ldd(R0, Y + 63);
+        asm.brne(ProgramAddress::Relative(-64));
+        assert_eq!(asm.buf, [0x0f, 0xac, 0x01, 0xf6]);
+    }
+
+    #[test]
+    fn reduced_core_has_only_r16_to_r31_and_one_word_lds() {
+        let mut asm = Assembler::new(Core::AvrTiny);
+        match asm.try_mov(R15, R16) {
+            Err(AsmError::InvalidOperand { constraint: Constraint::RegisterRange { min: 16, max: 31 }, .. }) => {}
+            result => panic!("{:?}", result),
+        }
+        match asm.try_lds(R16, DataAddress(0xc0)) {
+            Err(AsmError::InvalidOperand { constraint: Constraint::Range { min: 0x40, max: 0xbf }, .. }) => {}
+            result => panic!("{:?}", result),
+        }
+        assert!(asm.try_adiw(word_pair::R25_R24, 1).is_err());
+        asm.lds(R16, DataAddress(0xbf));
+        asm.sts(DataAddress(0x40), R31);
+        assert_eq!(asm.buf, [0x0f, 0xa6, 0xf0, 0xa9]);
+    }
+}
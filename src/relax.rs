@@ -0,0 +1,267 @@
+// Branch relaxation: rewriting a branch, `rjmp` or `rcall` whose label is out
+// of reach into a longer form, and moving the code after it along. Shortening
+// emits `call` and `jmp` as `rcall` and `rjmp` and leaves it to relaxation to
+// lengthen those that do not reach.
+
+use {field_bits, Arg, ArgConsumer, AsmError, Assembler, Feature, Fixup, Label, Linkage, Offset, OffsetKind};
+
+static BRANCH_FORMAT: &[u8] = b"1111 0skk kkkk ksss";
+pub(crate) static RJMP_FORMAT: &[u8] = b"1100 kkkk kkkk kkkk";
+static RCALL_FORMAT: &[u8] = b"1101 kkkk kkkk kkkk";
+static JMP_FORMAT: &[u8] = b"1001 010k kkkk 110k kkkk kkkk kkkk kkkk";
+static CALL_FORMAT: &[u8] = b"1001 010k kkkk 111k kkkk kkkk kkkk kkkk";
+
+impl Assembler {
+    /// Turns branch relaxation on or off. It is off to begin with.
+    ///
+    /// While it is on, a conditional branch to a label more than 64 words away
+    /// becomes the opposite branch over an `rjmp` to the label, or over a `jmp`
+    /// where the `rjmp` would not reach either, and an `rjmp` or `rcall` more
+    /// than 2K words away becomes a `jmp` or `call` on cores that have them.
+    /// This happens when the label is bound, or at once for labels already
+    /// bound, and moves every later label along. Branches given as a number
+    /// of words rather than a label are not moved, so they should not cross
+    /// a branch that may grow.
+    pub fn set_relaxation(&mut self, relax: bool) {
+        self.relax = relax;
+    }
+
+    /// Turns call and jump shortening on or off. It is off to begin with.
+    ///
+    /// While it is on, a `call` or `jmp` to a label is emitted as an `rcall` or
+    /// `rjmp`, which becomes a `call` or `jmp` again if the label turns out to
+    /// be more than 2K words away, as branch relaxation would lengthen it. On
+    /// a device with at most 8 KiB of flash, where the program counter wraps
+    /// around, `rcall` and `rjmp` reach the whole flash. Labels of other object
+    /// files are left to `call` and `jmp`, as their distance is not known.
+    pub fn set_shortening(&mut self, shorten: bool) {
+        self.shorten = shorten;
+    }
+
+    // Emits `call` or `jmp` to a label as `rcall` or `rjmp`, if shortening is on
+    pub(crate) fn shorten_call(&mut self, instruction: &'static str, args: &[(Arg, u8)]) -> Option<Result<(), AsmError>> {
+        let (short, format) = match instruction {
+            "call" if self.shorten => ("rcall", RCALL_FORMAT),
+            "jmp" if self.shorten => ("rjmp", RJMP_FORMAT),
+            _ => return None,
+        };
+        let label = match args {
+            [(Arg::Offset(Offset::Label(label), _), _)] => *label,
+            _ => return None,
+        };
+        if self.labels[label.0].linkage == Linkage::External {
+            return None;
+        }
+        Some(self.growing(|asm| asm.encode(short, &[(Arg::Offset(Offset::Label(label), OffsetKind::Relative), b'k')], format)))
+    }
+
+    // Emits branches and jumps that relaxation lengthens if they do not reach,
+    // whether it is turned on or not
+    pub(crate) fn growing<F: FnOnce(&mut Assembler) -> Result<(), AsmError>>(&mut self, emit: F) -> Result<(), AsmError> {
+        let grow = self.grow;
+        self.grow = true;
+        let result = emit(self);
+        self.grow = grow;
+        result
+    }
+
+    // A distance in words, taken the short way around flash on devices small
+    // enough for the program counter's wrapping to be in reach of `rjmp`
+    pub(crate) fn wrap_distance(&self, words: i32) -> i32 {
+        match self.device() {
+            Some(device) if device.flash_size <= 8 * 1024 => {
+                let flash_words = (device.flash_size / 2) as i32;
+                let words = words.rem_euclid(flash_words);
+                if words >= flash_words / 2 { words - flash_words } else { words }
+            }
+            _ => words,
+        }
+    }
+
+    // Whether a fixup can be rewritten into a longer form if it does not reach
+    pub(crate) fn relaxable(&self, fixup: &Fixup) -> bool {
+        if !(self.relax || fixup.grows) || !matches!(fixup.kind, OffsetKind::Relative) {
+            return false;
+        }
+        if fixup.format.starts_with(b"1111 0") {
+            // A skip would skip only the opposite branch
+            !fixup.skipped
+        } else {
+            (fixup.format == RJMP_FORMAT || fixup.format == RCALL_FORMAT) && self.core().has(Feature::JmpCall)
+        }
+    }
+
+    // Whether a fixup's label is bound and its value does not fit the field
+    fn out_of_range(&self, fixup: &Fixup) -> bool {
+        match self.label_value(fixup.label, fixup.kind, fixup.position, fixup.format) {
+            Some(Ok(value)) => ArgConsumer::new(value, field_bits(fixup.format, fixup.field)).is_err(),
+            Some(Err(_)) => true,
+            None => false,
+        }
+    }
+
+    // Expands out-of-range relaxable fixups, one at a time since each
+    // expansion moves the code after it, until every one reaches its label
+    pub(crate) fn relax_branches(&mut self) -> Result<(), AsmError> {
+        while let Some(index) = self.fixups.iter().position(|fixup| self.relaxable(fixup) && self.out_of_range(fixup)) {
+            self.expand(index)?;
+        }
+        Ok(())
+    }
+
+    fn expand(&mut self, index: usize) -> Result<(), AsmError> {
+        let (instruction, position, label, format, grows, skipped) = {
+            let fixup = &self.fixups[index];
+            (fixup.instruction, fixup.position, fixup.label, fixup.format, fixup.grows, fixup.skipped)
+        };
+        let word = self.buf[position] as u16 | (self.buf[position + 1] as u16) << 8;
+        let long_jump = |format: &'static [u8], opcode: u16, instruction: &'static str| {
+            (vec![opcode as u8, (opcode >> 8) as u8, 0, 0], vec![Fixup {
+                instruction,
+                position,
+                label,
+                kind: OffsetKind::AbsoluteDoubles,
+                format,
+                field: b'k',
+                grows: false,
+                skipped,
+            }])
+        };
+
+        let mut skip = None;
+        let (bytes, fixups) = if format == RJMP_FORMAT {
+            long_jump(JMP_FORMAT, 0x940c, "jmp")
+        } else if format == RCALL_FORMAT {
+            long_jump(CALL_FORMAT, 0x940e, "call")
+        } else {
+            // The opposite condition, which is the other bit 10, skipping over an rjmp
+            let skip_label = self.new_label();
+            skip = Some(skip_label);
+            let branch = (word & !0x03f8) ^ 0x0400;
+            (vec![branch as u8, (branch >> 8) as u8, 0x00, 0xc0], vec![
+                Fixup { instruction, position, label: skip_label, kind: OffsetKind::Relative, format: BRANCH_FORMAT, field: b'k', grows: false, skipped: false },
+                Fixup { instruction: "rjmp", position: position + 2, label, kind: OffsetKind::Relative, format: RJMP_FORMAT, field: b'k', grows, skipped: false },
+            ])
+        };
+
+        self.insert(position + 2, &bytes[2..], &[])?;
+        self.buf[position] = bytes[0];
+        self.buf[position + 1] = bytes[1];
+        self.fixups.remove(index);
+        self.fixups.extend(fixups);
+        if let Some(skip) = skip {
+            self.labels[skip.0].address = Some(self.origin + position as u32 + 4);
+        }
+        self.reapply_fixups()
+    }
+
+    // Inserts `bytes` at `position`, moving along the fixups from there on and
+    // the labels after it, and those on it except `staying`. The fixups are
+    // left for `reapply_fixups`.
+    pub(crate) fn insert(&mut self, position: usize, bytes: &[u8], staying: &[Label]) -> Result<(), AsmError> {
+        self.check_flash(bytes.len())?;
+        self.buf.splice(position..position, bytes.iter().cloned());
+        let address = self.origin + position as u32;
+        for (index, state) in self.labels.iter_mut().enumerate() {
+            if let Some(ref mut label_address) = state.address {
+                if *label_address > address || (*label_address == address && !staying.contains(&Label(index))) {
+                    *label_address += bytes.len() as u32;
+                }
+            }
+        }
+        for fixup in self.fixups.iter_mut() {
+            if fixup.position >= position {
+                fixup.position += bytes.len();
+            }
+        }
+        Ok(())
+    }
+
+    // Patches every fixup of a bound label again, after code moved
+    pub(crate) fn reapply_fixups(&mut self) -> Result<(), AsmError> {
+        for index in 0..self.fixups.len() {
+            if self.labels[self.fixups[index].label.0].address.is_none() {
+                continue;
+            }
+            match self.apply_fixup(index) {
+                Err(_) if self.relaxable(&self.fixups[index]) => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Core, Device};
+
+    fn nops(asm: &mut Assembler, count: usize) {
+        for _ in 0..count {
+            asm.nop();
+        }
+    }
+
+    #[test]
+    fn branches_out_of_reach_become_an_opposite_branch_over_rjmp() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.set_relaxation(true);
+        let top = asm.new_label();
+        let end = asm.new_label();
+        asm.bind(top);
+        asm.breq(end);
+        nops(&mut asm, 64);
+        asm.bind(end);
+        asm.breq(top);
+        assert_eq!(asm.buf.len(), 136);
+        assert_eq!(asm.buf[..4], [0x09, 0xf4, 0x40, 0xc0]);
+        assert_eq!(asm.buf[132..], [0x09, 0xf4, 0xbc, 0xcf]);
+    }
+
+    #[test]
+    fn branches_out_of_reach_are_errors_without_relaxation() {
+        let mut asm = Assembler::new(Core::Avr5);
+        let end = asm.new_label();
+        asm.breq(end);
+        nops(&mut asm, 64);
+        assert!(asm.try_bind(end).is_err());
+    }
+
+    #[test]
+    fn rjmp_out_of_reach_becomes_jmp() {
+        let mut asm = Assembler::for_device(Device::from_name("ATmega328P").unwrap());
+        asm.set_relaxation(true);
+        let end = asm.new_label();
+        asm.rjmp(end);
+        nops(&mut asm, 2048);
+        asm.bind(end);
+        assert_eq!(asm.buf[..4], [0x0c, 0x94, 0x02, 0x08]);
+    }
+
+    #[test]
+    fn shortened_calls_are_rcall_when_they_reach() {
+        let mut asm = Assembler::for_device(Device::from_name("ATmega328P").unwrap());
+        asm.set_shortening(true);
+        let near = asm.new_label();
+        let far = asm.new_label();
+        let external = asm.external_label("elsewhere");
+        asm.call(near);
+        asm.jmp(external);
+        asm.bind(near);
+        asm.jmp(far);
+        nops(&mut asm, 2048);
+        asm.bind(far);
+        assert_eq!(asm.buf[..10], [0x02, 0xd0, 0x0c, 0x94, 0x00, 0x00, 0x0c, 0x94, 0x05, 0x08]);
+    }
+
+    #[test]
+    fn rjmp_wraps_around_small_flash() {
+        let mut asm = Assembler::for_device(Device::from_name("ATtiny85").unwrap());
+        let end = asm.new_label();
+        asm.rjmp(end);
+        nops(&mut asm, 4094);
+        asm.bind(end);
+        assert_eq!(asm.buf[..2], [0xfe, 0xcf]);
+    }
+}
@@ -0,0 +1,766 @@
+// Pieces shared by the textual front ends: tokens, expressions, errors, and
+// calling an instruction method by name.
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+use error::operand_name;
+use {AsmError, Assembler, Bit, Core, DataAddress, DataReference, Feature, Direction, DirectionalRegisterPair, Flag, Label, OffsetRegisterPair, ProgramAddress, Register, RegisterPair, UpperRegister, X, Y, Z};
+
+/// An error in assembly source, located by file, line and column. Lines and
+/// columns are numbered from 1.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.message)
+    }
+}
+
+impl Error for SourceError {}
+
+// An error whose file and line are filled in by the caller
+pub(crate) type Located<T> = Result<T, (usize, String)>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum TokenKind {
+    Identifier(String),
+    Number(i64),
+    String(Vec<u8>),
+    // A reference to a numeric local label, such as `1b` or `2f`
+    LocalLabel(u32, bool),
+    Punct(&'static str),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Token {
+    pub kind: TokenKind,
+    pub column: usize,
+}
+
+impl Token {
+    pub fn is_punct(&self, punct: &str) -> bool {
+        match self.kind {
+            TokenKind::Punct(p) => p == punct,
+            _ => false,
+        }
+    }
+
+    pub fn identifier(&self) -> Option<&str> {
+        match self.kind {
+            TokenKind::Identifier(ref name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+// Longest first, so that `<<` is not read as two `<`
+static PUNCTUATION: [&str; 27] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+    "+", "-", "*", "/", "%", "&", "|", "^", "~", "!", "(", ")", ",", ":", "=", "<", ">", "[", "]",
+];
+
+// What the dialects disagree on when splitting a line into tokens
+#[derive(Copy, Clone)]
+pub(crate) struct LexOptions {
+    // `017` is octal rather than decimal
+    pub leading_zero_octal: bool,
+    // `1b` and `1f` refer to numeric local labels
+    pub local_labels: bool,
+    // `$1F` is a hex number
+    pub dollar_hex: bool,
+}
+
+// Blanks out `/* */` comments, which may span lines, keeping line and column numbers
+pub(crate) fn strip_block_comments(source: &str) -> String {
+    let mut result = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    let mut in_comment = false;
+    let mut quote: Option<char> = None;
+    while let Some(c) = chars.next() {
+        if in_comment {
+            if c == '*' && chars.peek() == Some(&'/') {
+                chars.next();
+                result.push_str("  ");
+                in_comment = false;
+            } else {
+                result.push(if c == '\n' { '\n' } else { ' ' });
+            }
+        } else if let Some(q) = quote {
+            result.push(c);
+            if c == '\\' {
+                if let Some(next) = chars.next() {
+                    result.push(next);
+                }
+            } else if c == q || c == '\n' {
+                quote = None;
+            }
+        } else if c == '/' && chars.peek() == Some(&'*') {
+            chars.next();
+            result.push_str("  ");
+            in_comment = true;
+        } else {
+            if c == '"' {
+                quote = Some(c);
+            }
+            result.push(c);
+        }
+    }
+    result
+}
+
+// Splits one line into tokens, stopping at a `;` or `//` comment
+pub(crate) fn lex(line: &str, options: LexOptions) -> Located<Vec<Token>> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == ';' || (c == '/' && chars.get(i + 1) == Some(&'/')) {
+            break;
+        } else if c.is_ascii_digit() || (options.dollar_hex && c == '$' && chars.get(i + 1).is_some_and(|c| c.is_ascii_hexdigit())) {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '$' || chars[i] == '_') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            tokens.push(Token { kind: number(&text, options).ok_or((column, format!("invalid number `{}`", text)))?, column });
+        } else if c.is_alphabetic() || c == '_' || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.' || chars[i] == '$') {
+                i += 1;
+            }
+            tokens.push(Token { kind: TokenKind::Identifier(chars[start..i].iter().collect()), column });
+        } else if c == '"' {
+            let (bytes, end) = quoted(&chars, i + 1, '"').ok_or((column, "unterminated string".to_string()))?;
+            tokens.push(Token { kind: TokenKind::String(bytes), column });
+            i = end;
+        } else if c == '\'' {
+            let (bytes, end) = quoted(&chars, i + 1, '\'').ok_or((column, "unterminated character".to_string()))?;
+            if bytes.len() != 1 {
+                return Err((column, "a character constant must hold one character".to_string()));
+            }
+            tokens.push(Token { kind: TokenKind::Number(bytes[0] as i64), column });
+            i = end;
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let punct = PUNCTUATION.iter().find(|&&p| rest.starts_with(p)).ok_or((column, format!("unexpected character `{}`", c)))?;
+            tokens.push(Token { kind: TokenKind::Punct(punct), column });
+            i += punct.len();
+        }
+    }
+    Ok(tokens)
+}
+
+fn number(text: &str, options: LexOptions) -> Option<TokenKind> {
+    let lower = text.to_lowercase();
+    if options.local_labels && lower.len() > 1 && (lower.ends_with('b') || lower.ends_with('f')) {
+        let digits = &lower[..lower.len() - 1];
+        if digits.chars().all(|c| c.is_ascii_digit()) && !(lower.starts_with("0b") && lower.len() > 2) {
+            return digits.parse().ok().map(|n| TokenKind::LocalLabel(n, lower.ends_with('f')));
+        }
+    }
+    let value = if let Some(digits) = lower.strip_prefix("0x") {
+        i64::from_str_radix(digits, 16)
+    } else if let Some(digits) = lower.strip_prefix("0b") {
+        i64::from_str_radix(digits, 2)
+    } else if let Some(digits) = lower.strip_prefix('$') {
+        i64::from_str_radix(digits, 16)
+    } else if options.leading_zero_octal && lower.len() > 1 && lower.starts_with('0') {
+        i64::from_str_radix(&lower[1..], 8)
+    } else {
+        lower.parse()
+    };
+    value.ok().map(TokenKind::Number)
+}
+
+// Reads a quoted string or character starting just after the opening quote,
+// returning its bytes and the index just past the closing quote
+fn quoted(chars: &[char], start: usize, quote: char) -> Option<(Vec<u8>, usize)> {
+    let mut bytes = Vec::new();
+    let mut i = start;
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        if c == quote {
+            return Some((bytes, i));
+        }
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        let escaped = *chars.get(i)?;
+        i += 1;
+        match escaped {
+            'n' => bytes.push(b'\n'),
+            'r' => bytes.push(b'\r'),
+            't' => bytes.push(b'\t'),
+            'b' => bytes.push(8),
+            'f' => bytes.push(12),
+            'x' => {
+                let digits: String = chars[i..].iter().take_while(|c| c.is_ascii_hexdigit()).take(2).collect();
+                i += digits.len();
+                bytes.push(u8::from_str_radix(&digits, 16).ok()?);
+            }
+            '0'..='7' => {
+                let digits: String = chars[i - 1..].iter().take_while(|c| ('0'..='7').contains(c)).take(3).collect();
+                i += digits.len() - 1;
+                bytes.push(u32::from_str_radix(&digits, 8).ok()? as u8);
+            }
+            c => bytes.push(c as u8),
+        }
+    }
+    None
+}
+
+// Splits operand tokens at the commas that are not inside parentheses
+pub(crate) fn split_operands(tokens: &[Token]) -> Vec<&[Token]> {
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+    let mut operands = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, token) in tokens.iter().enumerate() {
+        if token.is_punct("(") {
+            depth += 1;
+        } else if token.is_punct(")") {
+            depth -= 1;
+        } else if token.is_punct(",") && depth == 0 {
+            operands.push(&tokens[start..index]);
+            start = index + 1;
+        }
+    }
+    operands.push(&tokens[start..]);
+    operands
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Expr {
+    Number(i64),
+    // A symbol, and the column it appears at
+    Symbol(String, usize),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    // A function such as `lo8(x)`, with the column of its name
+    Call(String, Box<Expr>, usize),
+}
+
+// Binary operators from loosest to tightest binding
+static BINARY_OPERATORS: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+// Parses all of `tokens` as one expression
+pub(crate) fn parse_expression(tokens: &[Token], column: usize) -> Located<Expr> {
+    let mut position = 0;
+    let expr = parse_binary(tokens, &mut position, 0, column)?;
+    match tokens.get(position) {
+        Some(token) => Err((token.column, "unexpected token after expression".to_string())),
+        None => Ok(expr),
+    }
+}
+
+fn parse_binary(tokens: &[Token], position: &mut usize, level: usize, column: usize) -> Located<Expr> {
+    if level == BINARY_OPERATORS.len() {
+        return parse_unary(tokens, position, column);
+    }
+    let mut left = parse_binary(tokens, position, level + 1, column)?;
+    loop {
+        let operator = match tokens.get(*position).map(|token| &token.kind) {
+            Some(&TokenKind::Punct(punct)) if BINARY_OPERATORS[level].contains(&punct) => punct,
+            _ => return Ok(left),
+        };
+        *position += 1;
+        let right = parse_binary(tokens, position, level + 1, column)?;
+        left = Expr::Binary(operator, Box::new(left), Box::new(right));
+    }
+}
+
+fn parse_unary(tokens: &[Token], position: &mut usize, column: usize) -> Located<Expr> {
+    let token = tokens.get(*position).ok_or((column, "expected an expression".to_string()))?;
+    *position += 1;
+    match token.kind {
+        TokenKind::Punct(operator) if operator == "-" || operator == "~" || operator == "!" || operator == "+" => {
+            let operand = parse_unary(tokens, position, token.column)?;
+            Ok(Expr::Unary(operator, Box::new(operand)))
+        }
+        TokenKind::Punct("(") => {
+            let expr = parse_binary(tokens, position, 0, token.column)?;
+            expect_close(tokens, position, token.column)?;
+            Ok(expr)
+        }
+        TokenKind::Number(value) => Ok(Expr::Number(value)),
+        TokenKind::Identifier(ref name) => {
+            if tokens.get(*position).is_some_and(|next| next.is_punct("(")) {
+                *position += 1;
+                let argument = parse_binary(tokens, position, 0, token.column)?;
+                expect_close(tokens, position, token.column)?;
+                Ok(Expr::Call(name.clone(), Box::new(argument), token.column))
+            } else {
+                Ok(Expr::Symbol(name.clone(), token.column))
+            }
+        }
+        _ => Err((token.column, "expected an expression".to_string())),
+    }
+}
+
+fn expect_close(tokens: &[Token], position: &mut usize, column: usize) -> Located<()> {
+    match tokens.get(*position) {
+        Some(token) if token.is_punct(")") => {
+            *position += 1;
+            Ok(())
+        }
+        Some(token) => Err((token.column, "expected `)`".to_string())),
+        None => Err((column, "unclosed `(`".to_string())),
+    }
+}
+
+// Evaluates an expression. `symbol` gives the value of a symbol, and
+// `function` applies a function, returning `None` if there is no such one.
+pub(crate) fn evaluate<S, F>(expr: &Expr, symbol: &S, function: &F) -> Located<i64>
+    where S: Fn(&str) -> Option<i64>, F: Fn(&str, i64) -> Option<i64>
+{
+    Ok(match *expr {
+        Expr::Number(value) => value,
+        Expr::Symbol(ref name, column) => symbol(name).ok_or_else(|| (column, format!("undefined symbol `{}`", name)))?,
+        Expr::Unary(operator, ref operand) => {
+            let value = evaluate(operand, symbol, function)?;
+            match operator {
+                "-" => value.wrapping_neg(),
+                "~" => !value,
+                "!" => (value == 0) as i64,
+                _ => value,
+            }
+        }
+        Expr::Binary(operator, ref left, ref right) => {
+            let left_value = evaluate(left, symbol, function)?;
+            let right_value = evaluate(right, symbol, function)?;
+            match operator {
+                "||" => (left_value != 0 || right_value != 0) as i64,
+                "&&" => (left_value != 0 && right_value != 0) as i64,
+                "|" => left_value | right_value,
+                "^" => left_value ^ right_value,
+                "&" => left_value & right_value,
+                "==" => (left_value == right_value) as i64,
+                "!=" => (left_value != right_value) as i64,
+                "<" => (left_value < right_value) as i64,
+                "<=" => (left_value <= right_value) as i64,
+                ">" => (left_value > right_value) as i64,
+                ">=" => (left_value >= right_value) as i64,
+                "<<" => left_value.wrapping_shl(right_value as u32),
+                ">>" => left_value.wrapping_shr(right_value as u32),
+                "+" => left_value.wrapping_add(right_value),
+                "-" => left_value.wrapping_sub(right_value),
+                "*" => left_value.wrapping_mul(right_value),
+                _ if right_value == 0 => return Err((expression_column(right), "division by zero".to_string())),
+                "/" => left_value.wrapping_div(right_value),
+                _ => left_value.wrapping_rem(right_value),
+            }
+        }
+        Expr::Call(ref name, ref argument, column) => {
+            let value = evaluate(argument, symbol, function)?;
+            function(name, value).ok_or_else(|| (column, format!("unknown function `{}`", name)))?
+        }
+    })
+}
+
+// A column to blame for an error in the whole of `expr`
+fn expression_column(expr: &Expr) -> usize {
+    match *expr {
+        Expr::Symbol(_, column) | Expr::Call(_, _, column) => column,
+        Expr::Unary(_, ref operand) => expression_column(operand),
+        Expr::Binary(_, ref left, _) => expression_column(left),
+        Expr::Number(_) => 0,
+    }
+}
+
+// What a front end must supply for each operand of an instruction method
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum OperandType {
+    Register,
+    RegisterPair,
+    Unsigned,
+    Relative,
+    Absolute,
+    AbsoluteDoubles,
+    AddressByte,
+    ImplicitZ,
+}
+
+struct SourceForm {
+    name: &'static str,
+    operands: &'static [OperandType],
+    // The template character of each operand, which errors name it by
+    fields: &'static [u8],
+    bytes: u32,
+}
+
+// An operand ready to be passed to an instruction method
+#[derive(Copy, Clone)]
+enum Value {
+    Register(Register),
+    RegisterPair(RegisterPair),
+    Unsigned(u32),
+    Program(ProgramAddress),
+    Data(DataReference),
+}
+
+impl Value {
+    fn register(self) -> Register {
+        match self {
+            Value::Register(r) => r,
+            _ => panic!("operand is not a register"),
+        }
+    }
+
+    fn pair(self) -> RegisterPair {
+        match self {
+            Value::RegisterPair(pair) => pair,
+            _ => panic!("operand is not a register pair"),
+        }
+    }
+
+    fn unsigned(self) -> u32 {
+        match self {
+            Value::Unsigned(x) => x,
+            _ => panic!("operand is not a number"),
+        }
+    }
+
+    fn program(self) -> ProgramAddress {
+        match self {
+            Value::Program(address) => address,
+            _ => panic!("operand is not a program address"),
+        }
+    }
+
+    fn data(self) -> DataReference {
+        match self {
+            Value::Data(reference) => reference,
+            _ => panic!("operand is not a data reference"),
+        }
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/source_forms.rs"));
+
+impl SourceForm {
+    // Which operand an error names, such as `Rd`
+    fn operand_index(&self, operand: &str) -> Option<usize> {
+        self.fields.iter().position(|&field| operand_name(field) == operand)
+    }
+}
+
+fn source_form(name: &str) -> Option<&'static SourceForm> {
+    SOURCE_FORMS.iter().find(|form| form.name == name)
+}
+
+// Reads `r0` to `r31`, in either case
+pub(crate) fn register_name(name: &str) -> Option<Register> {
+    let lower = name.to_lowercase();
+    if !lower.starts_with('r') || lower.len() < 2 || lower.len() > 3 || lower[1..].starts_with('0') && lower.len() > 2 {
+        return None;
+    }
+    match lower[1..].parse::<u32>() {
+        Ok(n) if n < 32 => Some(Register(n)),
+        _ => None,
+    }
+}
+
+// Reads `X`, `Y` or `Z`, in either case
+fn pointer_name(name: &str) -> Option<RegisterPair> {
+    match &name.to_lowercase()[..] {
+        "x" => Some(X),
+        "y" => Some(Y),
+        "z" => Some(Z),
+        _ => None,
+    }
+}
+
+// The byte of a label's address that an `ldi` operand asks for
+#[derive(Copy, Clone)]
+pub(crate) enum AddressByte {
+    Lo8,
+    Hi8,
+    PmLo8,
+    PmHi8,
+}
+
+// What a dialect's symbols look like to instruction operands
+pub(crate) trait Symbols {
+    // The value of a symbol in an expression
+    fn value(&self, name: &str) -> Option<i64>;
+
+    // The label for a symbol that names code, or for one that is not defined
+    // at all, which becomes an external label
+    fn label(&mut self, assembler: &mut Assembler, name: &str) -> Option<Label>;
+
+    fn function(&self, name: &str, value: i64) -> Option<i64>;
+
+    // What part of which symbol's address an `ldi` operand such as `lo8(x)` takes
+    fn address_byte(&self, expr: &Expr) -> Option<(AddressByte, String)>;
+
+    // Bytes per unit of a program memory address written in the source
+    fn program_address_unit(&self) -> u32 {
+        1
+    }
+
+    // A name given to a register, other than `r0` to `r31`
+    fn register_alias(&self, _name: &str) -> Option<Register> {
+        None
+    }
+}
+
+// The method that implements a mnemonic, given the number of operands
+fn form_name(mnemonic: &str, operand_count: usize, core: Core) -> &str {
+    match (mnemonic, operand_count) {
+        ("break", _) => "break_",
+        ("in", _) => "in_",
+        // Only the reduced core has the short forms, and it has no others
+        ("lds", _) if core.has(Feature::Reduced) => "lds_7",
+        ("sts", _) if core.has(Feature::Reduced) => "sts_7",
+        ("lds", _) => "lds_16",
+        ("sts", _) => "sts_16",
+        ("lpm", 0) => "lpm_r0",
+        ("elpm", 0) => "elpm_r0",
+        ("spm", 1) => "spm_z_plus",
+        _ => mnemonic,
+    }
+}
+
+// Instructions with a pointer operand, which are not in the generated table
+fn is_pointer_instruction(name: &str) -> bool {
+    matches!(name, "ld" | "ldd" | "st" | "std" | "lpm" | "elpm")
+}
+
+// The size in bytes of an instruction, which does not depend on its operands
+pub(crate) fn instruction_size(mnemonic: &str, operand_count: usize, core: Core) -> Option<u32> {
+    let name = form_name(mnemonic, operand_count, core);
+    if is_pointer_instruction(name) {
+        return Some(2);
+    }
+    match source_form(name) {
+        Some(form) if !mnemonic.contains('_') => Some(form.bytes),
+        _ => None,
+    }
+}
+
+// Assembles one instruction, with `mnemonic` in lower case
+pub(crate) fn assemble_instruction<S: Symbols>(assembler: &mut Assembler, symbols: &mut S, mnemonic: &str, column: usize, tokens: &[Token]) -> Located<()> {
+    let operands = split_operands(tokens);
+    let name = form_name(mnemonic, operands.len(), assembler.core());
+    let asm_error = |err: AsmError| (column, err.to_string());
+
+    if is_pointer_instruction(name) {
+        if operands.len() != 2 {
+            return Err((column, format!("`{}` takes 2 operands", mnemonic)));
+        }
+        // The register operand comes second only in stores
+        let register_index = if name.starts_with("st") { 1 } else { 0 };
+        return match name {
+            "ld" => assembler.try_ld(register(symbols, operands[0], column)?, pointer(operands[1], column)?),
+            "lpm" => assembler.try_lpm(register(symbols, operands[0], column)?, pointer(operands[1], column)?),
+            "elpm" => assembler.try_elpm(register(symbols, operands[0], column)?, pointer(operands[1], column)?),
+            "st" => assembler.try_st(pointer(operands[0], column)?, register(symbols, operands[1], column)?),
+            "ldd" => assembler.try_ldd(register(symbols, operands[0], column)?, displacement(symbols, operands[1], column)?),
+            _ => assembler.try_std(displacement(symbols, operands[0], column)?, register(symbols, operands[1], column)?),
+        }.map_err(|err| {
+            operand_error(err, |operand| Some(if operand == "Rd" || operand == "Rr" { register_index } else { 1 - register_index }), &operands, column)
+        });
+    }
+
+    if name == "spm_z_plus" {
+        let pointer = pointer(operands[0], column)?;
+        if pointer.pair != Z || pointer.direction != Direction::PostIncrement {
+            return Err((operand_column(operands[0], column), "expected Z+".to_string()));
+        }
+        return assembler.try_spm_z_plus().map_err(asm_error);
+    }
+
+    let form = match source_form(name) {
+        Some(form) if !mnemonic.contains('_') => form,
+        _ => return Err((column, format!("unknown instruction `{}`", mnemonic))),
+    };
+    if operands.len() != form.operands.len() {
+        return Err((column, format!("`{}` takes {} operand{}", mnemonic, form.operands.len(), if form.operands.len() == 1 { "" } else { "s" })));
+    }
+
+    // `ldi` of part of a label's address becomes one of the forms that leaves it to the linker
+    if name == "ldi" {
+        let expr = parse_expression(operands[1], operand_column(operands[1], column))?;
+        if let Some((byte, symbol)) = symbols.address_byte(&expr) {
+            if let Some(label) = symbols.label(assembler, &symbol) {
+                let d = register(symbols, operands[0], column)?;
+                let d = UpperRegister::try_from(d)
+                    .map_err(|constraint| (operand_column(operands[0], column), AsmError::invalid_operand("ldi", b'd', constraint).to_string()))?;
+                return match byte {
+                    AddressByte::Lo8 => assembler.try_ldi_lo8(d, label),
+                    AddressByte::Hi8 => assembler.try_ldi_hi8(d, label),
+                    AddressByte::PmLo8 => assembler.try_ldi_pm_lo8(d, label),
+                    AddressByte::PmHi8 => assembler.try_ldi_pm_hi8(d, label),
+                }.map_err(|err| operand_error(err, |operand| form.operand_index(operand), &operands, column));
+            }
+        }
+    }
+
+    let address = assembler.address();
+    let mut values = Vec::with_capacity(operands.len());
+    for (tokens, &operand_type) in operands.iter().zip(form.operands.iter()) {
+        let operand_column = operand_column(tokens, column);
+        let value = match operand_type {
+            OperandType::Register => Value::Register(register(symbols, tokens, column)?),
+            OperandType::RegisterPair => Value::RegisterPair(register_pair(symbols, tokens, column)?),
+            OperandType::ImplicitZ => {
+                match (tokens.len(), tokens.first().and_then(|token| token.identifier())) {
+                    (1, Some(name)) if name.to_lowercase() == "z" => Value::RegisterPair(Z),
+                    _ => return Err((operand_column, "expected Z".to_string())),
+                }
+            }
+            OperandType::Unsigned | OperandType::AddressByte => {
+                let value = constant(symbols, tokens, column)?;
+                // Negative numbers are accepted for byte immediates, as two's complement
+                let value = if (-128..0).contains(&value) { value & 0xff } else { value };
+                if !(0..=u32::MAX as i64).contains(&value) {
+                    return Err((operand_column, format!("{} is out of range", value)));
+                }
+                Value::Unsigned(value as u32)
+            }
+            OperandType::Relative | OperandType::Absolute | OperandType::AbsoluteDoubles => {
+                let expr = parse_expression(tokens, operand_column)?;
+                let label = match expr {
+                    Expr::Symbol(ref symbol, _) => symbols.label(assembler, symbol),
+                    _ => None,
+                };
+                match (label, operand_type) {
+                    (Some(label), OperandType::Absolute) => Value::Data(DataReference::Label(label)),
+                    (Some(label), _) => Value::Program(ProgramAddress::Label(label)),
+                    (None, _) => {
+                        let target = evaluate(&expr, &|name| symbols.value(name), &|name, value| symbols.function(name, value))?;
+                        match operand_type {
+                            OperandType::Absolute => Value::Data(DataReference::Address(DataAddress(target as u32))),
+                            OperandType::AbsoluteDoubles => {
+                                let bytes = (target * symbols.program_address_unit() as i64) as u32;
+                                let address = ProgramAddress::from_byte_address(bytes)
+                                    .map_err(|_| (operand_column, "jump target must be an even address".to_string()))?;
+                                Value::Program(address)
+                            }
+                            _ => {
+                                // Relative to the end of the instruction, in words
+                                let distance = target * symbols.program_address_unit() as i64 - (address + form.bytes) as i64;
+                                let distance = ProgramAddress::from_byte_distance(distance as i32)
+                                    .map_err(|_| (operand_column, "branch target must be an even address".to_string()))?;
+                                Value::Program(distance)
+                            }
+                        }
+                    }
+                }
+            }
+        };
+        values.push(value);
+    }
+    assembler.call_form(name, &values).map_err(|err| operand_error(err, |operand| form.operand_index(operand), &operands, column))
+}
+
+// Reports an error at the operand it is about, if known, rather than at the mnemonic
+fn operand_error<F>(err: AsmError, operand_index: F, operands: &[&[Token]], column: usize) -> (usize, String)
+    where F: Fn(&str) -> Option<usize>
+{
+    let index = match err {
+        AsmError::InvalidOperand { operand, .. } => operand_index(operand),
+        _ => None,
+    };
+    let column = index.and_then(|index| operands.get(index)).map_or(column, |tokens| operand_column(tokens, column));
+    (column, err.to_string())
+}
+
+// Evaluates an operand that must be a number
+pub(crate) fn constant<S: Symbols>(symbols: &S, tokens: &[Token], column: usize) -> Located<i64> {
+    let expr = parse_expression(tokens, operand_column(tokens, column))?;
+    evaluate(&expr, &|name| symbols.value(name), &|name, value| symbols.function(name, value))
+}
+
+fn register<S: Symbols>(symbols: &S, tokens: &[Token], column: usize) -> Located<Register> {
+    let name = tokens.first().and_then(|token| token.identifier());
+    match (tokens.len(), name.and_then(|name| register_name(name).or_else(|| symbols.register_alias(name)))) {
+        (1, Some(r)) => Ok(r),
+        _ => Err((operand_column(tokens, column), "expected a register".to_string())),
+    }
+}
+
+// `r24` for the pair r25:r24, `r25:r24` itself, or a pointer register
+fn register_pair<S: Symbols>(symbols: &S, tokens: &[Token], column: usize) -> Located<RegisterPair> {
+    let one = |index: usize| register(symbols, &tokens[index..index + 1], column).ok();
+    let pair = match tokens.len() {
+        1 => tokens[0].identifier().and_then(pointer_name)
+            .or_else(|| one(0).map(|low| RegisterPair(Register(low.0 + 1), low))),
+        3 if tokens[1].is_punct(":") => match (one(0), one(2)) {
+            (Some(high), Some(low)) => Some(RegisterPair(high, low)),
+            _ => None,
+        },
+        _ => None,
+    };
+    pair.ok_or_else(|| (operand_column(tokens, column), "expected a register pair".to_string()))
+}
+
+// `X`, `X+` or `-X`, and likewise for Y and Z
+fn pointer(tokens: &[Token], column: usize) -> Located<DirectionalRegisterPair> {
+    let found = match tokens.len() {
+        1 => tokens[0].identifier().and_then(pointer_name).map(|pair| (pair, Direction::NoChange)),
+        2 if tokens[1].is_punct("+") => tokens[0].identifier().and_then(pointer_name).map(|pair| (pair, Direction::PostIncrement)),
+        2 if tokens[0].is_punct("-") => tokens[1].identifier().and_then(pointer_name).map(|pair| (pair, Direction::PreDecrement)),
+        _ => None,
+    };
+    found.map(|(pair, direction)| DirectionalRegisterPair { pair, direction })
+        .ok_or_else(|| (operand_column(tokens, column), "expected a pointer register such as X, Y+ or -Z".to_string()))
+}
+
+// `Y+q` or `Z+q`
+fn displacement<S: Symbols>(symbols: &S, tokens: &[Token], column: usize) -> Located<OffsetRegisterPair> {
+    let pair = tokens.first().and_then(|token| token.identifier()).and_then(pointer_name);
+    match pair {
+        Some(pair) if tokens.len() > 2 && tokens[1].is_punct("+") => {
+            let offset = constant(symbols, &tokens[2..], column)?;
+            if !(0..=63).contains(&offset) {
+                return Err((tokens[2].column, "displacement must be between 0 and 63".to_string()));
+            }
+            Ok(pair + offset as u8)
+        }
+        _ => Err((operand_column(tokens, column), "expected a displacement such as Y+2".to_string())),
+    }
+}
+
+// The bytes of operands that must each be a string
+pub(crate) fn strings(operands: &[&[Token]], column: usize) -> Located<Vec<Vec<u8>>> {
+    operands.iter().map(|tokens| {
+        match tokens.first().map(|token| &token.kind) {
+            Some(TokenKind::String(bytes)) if tokens.len() == 1 => Ok(bytes.clone()),
+            _ => Err((operand_column(tokens, column), "expected a string".to_string())),
+        }
+    }).collect()
+}
+
+// Where an operand starts, or the statement's column if it is empty
+pub(crate) fn operand_column(tokens: &[Token], column: usize) -> usize {
+    tokens.first().map_or(column, |token| token.column)
+}
@@ -0,0 +1,394 @@
+// Values wider than a byte, held little-endian in consecutive registers, and
+// the instruction chains that work on them a byte at a time.
+
+use std::convert::TryFrom;
+
+use {AsmError, Assembler, Constraint, Feature, Register, RegisterPair, UpperRegister, WordPair};
+
+/// From one to eight consecutive registers holding a value low byte first,
+/// such as R24 to R27 for a 32-bit value.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RegisterGroup {
+    low: Register,
+    bytes: u32,
+}
+
+impl RegisterGroup {
+    /// The `bytes` registers from `low` up.
+    pub fn new(low: Register, bytes: u32) -> Result<RegisterGroup, Constraint> {
+        if !(1..=8).contains(&bytes) {
+            Err(Constraint::Range { min: 1, max: 8 })
+        } else if low.0 + bytes > 32 {
+            Err(Constraint::RegisterRange { min: 0, max: 32 - bytes })
+        } else {
+            Ok(RegisterGroup { low, bytes })
+        }
+    }
+
+    pub fn bytes(self) -> u32 {
+        self.bytes
+    }
+
+    /// The register holding byte `index`, counting from the low byte.
+    pub fn byte(self, index: u32) -> Register {
+        assert!(index < self.bytes, "byte {} of a {}-byte group", index, self.bytes);
+        Register(self.low.0 + index)
+    }
+}
+
+impl TryFrom<RegisterPair> for RegisterGroup {
+    type Error = Constraint;
+
+    fn try_from(pair: RegisterPair) -> Result<RegisterGroup, Constraint> {
+        let RegisterPair(high, low) = pair;
+        if high.0 != low.0 + 1 {
+            Err(Constraint::ConsecutivePair)
+        } else {
+            Ok(RegisterGroup { low, bytes: 2 })
+        }
+    }
+}
+
+impl From<WordPair> for RegisterGroup {
+    fn from(pair: WordPair) -> RegisterGroup {
+        RegisterGroup { low: pair.pair().1, bytes: 2 }
+    }
+}
+
+// Fails unless the groups are the same width, naming the second one
+fn check_width(instruction: &'static str, d: RegisterGroup, r: RegisterGroup) -> Result<(), AsmError> {
+    if d.bytes == r.bytes {
+        Ok(())
+    } else {
+        Err(AsmError::invalid_operand(instruction, b'r', Constraint::Width { bytes: d.bytes }))
+    }
+}
+
+// A byte of the group as the register class that immediate instructions take
+fn upper(instruction: &'static str, register: Register) -> Result<UpperRegister, AsmError> {
+    UpperRegister::try_from(register).map_err(|constraint| AsmError::invalid_operand(instruction, b'd', constraint))
+}
+
+impl Assembler {
+    pub fn ldi_wide(&mut self, d: RegisterGroup, value: u64) {
+        self.try_ldi_wide(d, value).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Loads `value`, cut to the width of `d`, with an `ldi` per byte, so `d`
+    /// must be in R16..R31.
+    pub fn try_ldi_wide(&mut self, d: RegisterGroup, value: u64) -> Result<(), AsmError> {
+        self.check_not_skipped("ldi_wide", d.bytes)?;
+        for index in 0..d.bytes {
+            self.try_ldi(d.byte(index), (value >> (8 * index)) as u32 & 0xff)?;
+        }
+        Ok(())
+    }
+
+    pub fn clr_wide(&mut self, d: RegisterGroup) {
+        self.try_clr_wide(d).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Clears every byte of `d` with `clr`, which also clears the carry flag.
+    pub fn try_clr_wide(&mut self, d: RegisterGroup) -> Result<(), AsmError> {
+        self.check_not_skipped("clr_wide", d.bytes)?;
+        for index in 0..d.bytes {
+            self.try_clr(d.byte(index))?;
+        }
+        Ok(())
+    }
+
+    pub fn mov_wide(&mut self, d: RegisterGroup, r: RegisterGroup) {
+        self.try_mov_wide(d, r).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Copies `r` into `d`, a pair at a time with `movw` where both are at
+    /// even registers and the core has it, and a byte at a time with `mov`
+    /// otherwise. Overlapping groups are copied in the order that reads each
+    /// byte before overwriting it.
+    pub fn try_mov_wide(&mut self, d: RegisterGroup, r: RegisterGroup) -> Result<(), AsmError> {
+        check_width("mov_wide", d, r)?;
+        let movw = self.core().has(Feature::Movw);
+        let mut moves = Vec::new();
+        let mut index = 0;
+        while index < d.bytes {
+            let (d_low, r_low) = (d.byte(index), r.byte(index));
+            if movw && index + 1 < d.bytes && d_low.0 % 2 == 0 && r_low.0 % 2 == 0 {
+                moves.push((index, 2));
+                index += 2;
+            } else {
+                moves.push((index, 1));
+                index += 1;
+            }
+        }
+        self.check_not_skipped("mov_wide", moves.len() as u32)?;
+        if d.low.0 > r.low.0 {
+            moves.reverse();
+        }
+        for (index, bytes) in moves {
+            if bytes == 2 {
+                let pair = |group: RegisterGroup| RegisterPair(group.byte(index + 1), group.byte(index));
+                self.try_movw(pair(d), pair(r))?;
+            } else {
+                self.try_mov(d.byte(index), r.byte(index))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_wide(&mut self, d: RegisterGroup, r: RegisterGroup) {
+        self.try_add_wide(d, r).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Adds `r` to `d` with `add` and then `adc` up the bytes.
+    pub fn try_add_wide(&mut self, d: RegisterGroup, r: RegisterGroup) -> Result<(), AsmError> {
+        check_width("add_wide", d, r)?;
+        self.check_not_skipped("add_wide", d.bytes)?;
+        self.try_add(d.byte(0), r.byte(0))?;
+        for index in 1..d.bytes {
+            self.try_adc(d.byte(index), r.byte(index))?;
+        }
+        Ok(())
+    }
+
+    pub fn sub_wide(&mut self, d: RegisterGroup, r: RegisterGroup) {
+        self.try_sub_wide(d, r).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Subtracts `r` from `d` with `sub` and then `sbc` up the bytes.
+    pub fn try_sub_wide(&mut self, d: RegisterGroup, r: RegisterGroup) -> Result<(), AsmError> {
+        check_width("sub_wide", d, r)?;
+        self.check_not_skipped("sub_wide", d.bytes)?;
+        self.try_sub(d.byte(0), r.byte(0))?;
+        for index in 1..d.bytes {
+            self.try_sbc(d.byte(index), r.byte(index))?;
+        }
+        Ok(())
+    }
+
+    pub fn subi_wide(&mut self, d: RegisterGroup, value: u64) {
+        self.try_subi_wide(d, value).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Subtracts `value` from `d` with `subi` and then `sbci` up the bytes, so
+    /// `d` must be in R16..R31. Subtracting the negated value adds it.
+    pub fn try_subi_wide(&mut self, d: RegisterGroup, value: u64) -> Result<(), AsmError> {
+        self.check_not_skipped("subi_wide", d.bytes)?;
+        self.try_subi(d.byte(0), value as u32 & 0xff)?;
+        for index in 1..d.bytes {
+            self.try_sbci(d.byte(index), (value >> (8 * index)) as u32 & 0xff)?;
+        }
+        Ok(())
+    }
+
+    pub fn cp_wide(&mut self, d: RegisterGroup, r: RegisterGroup) {
+        self.try_cp_wide(d, r).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Compares `d` with `r` with `cp` and then `cpc` up the bytes, leaving
+    /// the flags for the branches as a single compare of the whole values would.
+    pub fn try_cp_wide(&mut self, d: RegisterGroup, r: RegisterGroup) -> Result<(), AsmError> {
+        check_width("cp_wide", d, r)?;
+        self.check_not_skipped("cp_wide", d.bytes)?;
+        self.try_cp(d.byte(0), r.byte(0))?;
+        for index in 1..d.bytes {
+            self.try_cpc(d.byte(index), r.byte(index))?;
+        }
+        Ok(())
+    }
+
+    pub fn cpi_wide(&mut self, d: RegisterGroup, value: u64, scratch: UpperRegister) {
+        self.try_cpi_wide(d, value, scratch).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Compares `d` with `value`: the low byte with `cpi` if it is in
+    /// R16..R31, and every other byte by loading it into `scratch` with `ldi`,
+    /// which leaves the flags alone, for a `cp` or `cpc`.
+    pub fn try_cpi_wide(&mut self, d: RegisterGroup, value: u64, scratch: UpperRegister) -> Result<(), AsmError> {
+        let byte = |index: u32| (value >> (8 * index)) as u32 & 0xff;
+        let low = UpperRegister::try_from(d.byte(0));
+        self.check_not_skipped("cpi_wide", 2 * d.bytes - low.is_ok() as u32)?;
+        match low {
+            Ok(low) => self.try_cpi(low, byte(0))?,
+            Err(_) => {
+                self.try_ldi(scratch, byte(0))?;
+                self.try_cp(d.byte(0), scratch.register())?;
+            }
+        }
+        for index in 1..d.bytes {
+            self.try_ldi(scratch, byte(index))?;
+            self.try_cpc(d.byte(index), scratch.register())?;
+        }
+        Ok(())
+    }
+
+    pub fn neg_wide(&mut self, d: RegisterGroup) {
+        self.try_neg_wide(d).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Negates `d`: `com` on the bytes above the lowest, `neg` on the lowest,
+    /// and `sbci` of 0xff up the others to add one to them unless the lowest
+    /// borrowed, so every byte but the lowest must be in R16..R31.
+    pub fn try_neg_wide(&mut self, d: RegisterGroup) -> Result<(), AsmError> {
+        self.check_not_skipped("neg_wide", 2 * d.bytes - 1)?;
+        let high: Vec<UpperRegister> = (1..d.bytes).map(|index| upper("sbci", d.byte(index))).collect::<Result<_, _>>()?;
+        for &register in high.iter().rev() {
+            self.try_com(register.register())?;
+        }
+        self.try_neg(d.byte(0))?;
+        for &register in high.iter() {
+            self.try_sbci(register, 0xff)?;
+        }
+        Ok(())
+    }
+
+    pub fn lsl_wide(&mut self, d: RegisterGroup) {
+        self.try_lsl_wide(d).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Shifts `d` left by one bit with `lsl` and then `rol` up the bytes.
+    pub fn try_lsl_wide(&mut self, d: RegisterGroup) -> Result<(), AsmError> {
+        self.check_not_skipped("lsl_wide", d.bytes)?;
+        self.try_lsl(d.byte(0))?;
+        for index in 1..d.bytes {
+            self.try_rol(d.byte(index))?;
+        }
+        Ok(())
+    }
+
+    pub fn lsr_wide(&mut self, d: RegisterGroup) {
+        self.try_lsr_wide(d).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Shifts `d` right by one bit, unsigned, with `lsr` and then `ror` down the bytes.
+    pub fn try_lsr_wide(&mut self, d: RegisterGroup) -> Result<(), AsmError> {
+        self.check_not_skipped("lsr_wide", d.bytes)?;
+        self.try_lsr(d.byte(d.bytes - 1))?;
+        for index in (0..d.bytes - 1).rev() {
+            self.try_ror(d.byte(index))?;
+        }
+        Ok(())
+    }
+
+    pub fn asr_wide(&mut self, d: RegisterGroup) {
+        self.try_asr_wide(d).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Shifts `d` right by one bit, signed, with `asr` and then `ror` down the bytes.
+    pub fn try_asr_wide(&mut self, d: RegisterGroup) -> Result<(), AsmError> {
+        self.check_not_skipped("asr_wide", d.bytes)?;
+        self.try_asr(d.byte(d.bytes - 1))?;
+        for index in (0..d.bytes - 1).rev() {
+            self.try_ror(d.byte(index))?;
+        }
+        Ok(())
+    }
+
+    pub fn rol_wide(&mut self, d: RegisterGroup) {
+        self.try_rol_wide(d).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Rotates `d` left through the carry flag with `rol` up the bytes.
+    pub fn try_rol_wide(&mut self, d: RegisterGroup) -> Result<(), AsmError> {
+        self.check_not_skipped("rol_wide", d.bytes)?;
+        for index in 0..d.bytes {
+            self.try_rol(d.byte(index))?;
+        }
+        Ok(())
+    }
+
+    pub fn ror_wide(&mut self, d: RegisterGroup) {
+        self.try_ror_wide(d).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Rotates `d` right through the carry flag with `ror` down the bytes.
+    pub fn try_ror_wide(&mut self, d: RegisterGroup) -> Result<(), AsmError> {
+        self.check_not_skipped("ror_wide", d.bytes)?;
+        for index in (0..d.bytes).rev() {
+            self.try_ror(d.byte(index))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {upper, Bit, Core, R0, R1, R14, R16, R2, R20, R21, R22, R24, R25, R26, R3, R30, R4, R5, R6, R7};
+
+    #[test]
+    fn a_skip_may_come_before_a_sequence_of_one_instruction() {
+        let mut asm = Assembler::new(Core::Avr5);
+        asm.sbrs(R5, Bit::B0);
+        asm.ldi_wide(RegisterGroup::new(R16, 1).unwrap(), 0x12);
+        asm.sbrs(R5, Bit::B0);
+        asm.mov_wide(RegisterGroup::new(R2, 2).unwrap(), RegisterGroup::new(R4, 2).unwrap());
+        asm.sbrs(R5, Bit::B0);
+        match asm.try_ldi_wide(RegisterGroup::new(R16, 2).unwrap(), 0x1234) {
+            Err(AsmError::SkippedSequence { skip: "sbrs", sequence: "ldi_wide" }) => {}
+            result => panic!("{:?}", result),
+        }
+    }
+
+    fn group(low: Register, bytes: u32) -> RegisterGroup {
+        RegisterGroup::new(low, bytes).unwrap()
+    }
+
+    #[test]
+    fn groups_must_fit_the_register_file() {
+        assert_eq!(RegisterGroup::new(R30, 4), Err(Constraint::RegisterRange { min: 0, max: 28 }));
+        assert_eq!(RegisterGroup::new(R16, 9), Err(Constraint::Range { min: 1, max: 8 }));
+        assert_eq!(RegisterGroup::try_from(RegisterPair(R25, R24)), Ok(group(R24, 2)));
+    }
+
+    #[test]
+    fn wide_operations_chain_through_the_bytes() {
+        let mut wide = Assembler::new(Core::Avr5);
+        wide.add_wide(group(R24, 3), group(R20, 3));
+        wide.cpi_wide(group(R2, 2), 0x1234, upper::R16);
+        wide.neg_wide(group(R24, 3));
+        wide.lsr_wide(group(R24, 2));
+
+        let mut bytes = Assembler::new(Core::Avr5);
+        bytes.add(R24, R20);
+        bytes.adc(R25, R21);
+        bytes.adc(R26, R22);
+        bytes.ldi(upper::R16, 0x34);
+        bytes.cp(R2, R16);
+        bytes.ldi(upper::R16, 0x12);
+        bytes.cpc(R3, R16);
+        bytes.com(R26);
+        bytes.com(R25);
+        bytes.neg(R24);
+        bytes.sbci(upper::R25, 0xff);
+        bytes.sbci(upper::R26, 0xff);
+        bytes.lsr(R25);
+        bytes.ror(R24);
+        assert_eq!(wide.buf, bytes.buf);
+    }
+
+    #[test]
+    fn mov_wide_uses_movw_and_copies_overlapping_groups_in_order() {
+        let mut wide = Assembler::new(Core::Avr5);
+        wide.mov_wide(group(R2, 4), group(R4, 4));
+        wide.mov_wide(group(R1, 3), group(R0, 3));
+
+        let mut bytes = Assembler::new(Core::Avr5);
+        bytes.movw(RegisterPair(R3, R2), RegisterPair(R5, R4));
+        bytes.movw(RegisterPair(R5, R4), RegisterPair(R7, R6));
+        bytes.mov(R3, R2);
+        bytes.mov(R2, R1);
+        bytes.mov(R1, R0);
+        assert_eq!(wide.buf, bytes.buf);
+    }
+
+    #[test]
+    fn groups_of_different_widths_are_rejected() {
+        let mut asm = Assembler::new(Core::Avr5);
+        match asm.try_add_wide(group(R24, 2), group(R20, 4)) {
+            Err(AsmError::InvalidOperand { instruction: "add_wide", constraint: Constraint::Width { bytes: 2 }, .. }) => {}
+            result => panic!("{:?}", result),
+        }
+        assert!(asm.try_subi_wide(group(R14, 2), 1).is_err());
+        assert!(asm.buf.is_empty());
+    }
+}
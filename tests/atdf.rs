@@ -0,0 +1,38 @@
+// The constants that `AtdfDevice::write_rust` generates, compiled against the
+// instructions they are for. `atdf/ATmega328P.rs` is the output for
+// `atdf/ATmega328P.atdf`, which the first test keeps it in step with.
+
+extern crate rassembler_avr;
+
+use rassembler_avr::{read_atdf, upper, Assembler, Core, R16};
+
+#[allow(dead_code)]
+mod atmega328p {
+    include!("atdf/ATmega328P.rs");
+}
+
+#[test]
+fn generated_constants_are_up_to_date() {
+    let device = read_atdf(&include_bytes!("atdf/ATmega328P.atdf")[..]).unwrap();
+    let mut out = Vec::new();
+    device.write_rust(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), include_str!("atdf/ATmega328P.rs"));
+}
+
+#[test]
+fn generated_constants_go_with_the_instructions_for_them() {
+    use atmega328p::*;
+
+    let mut asm = Assembler::new(Core::Avr5);
+    asm.sbi(DDRB, DDB5);
+    asm.sbi(PORTB, PORTB5);
+    asm.sbic(PINB, PINB0);
+    asm.cbi(PORTB, PORTB5);
+    asm.lds(R16, TCCR1B);
+    asm.ori(upper::R16, CS11_MASK | CS10_MASK);
+    asm.sts(TCCR1B, R16);
+    assert_eq!(asm.buf, [
+        0x25, 0x9a, 0x2d, 0x9a, 0x18, 0x99, 0x2d, 0x98,
+        0x00, 0x91, 0x81, 0x00, 0x03, 0x60, 0x00, 0x93, 0x81, 0x00,
+    ]);
+}
@@ -0,0 +1,29 @@
+// Generated from the ATDF file for the ATmega328P
+pub const FLASH_START: u32 = 0x0;
+pub const FLASH_SIZE: u32 = 0x8000;
+pub const IRAM_START: u32 = 0x100;
+pub const IRAM_SIZE: u32 = 0x800;
+pub const PORTB: ::rassembler_avr::IoAddress = ::rassembler_avr::IoAddress(0x5);
+pub const PORTB5: ::rassembler_avr::Bit = ::rassembler_avr::Bit::B5;
+pub const PORTB5_MASK: u32 = 0x20;
+pub const DDRB: ::rassembler_avr::IoAddress = ::rassembler_avr::IoAddress(0x4);
+pub const DDB5: ::rassembler_avr::Bit = ::rassembler_avr::Bit::B5;
+pub const DDB5_MASK: u32 = 0x20;
+pub const PINB: ::rassembler_avr::IoAddress = ::rassembler_avr::IoAddress(0x3);
+pub const PINB0: ::rassembler_avr::Bit = ::rassembler_avr::Bit::B0;
+pub const PINB0_MASK: u32 = 0x1;
+pub const TCCR1B: ::rassembler_avr::DataAddress = ::rassembler_avr::DataAddress(0x81);
+pub const ICNC1: ::rassembler_avr::Bit = ::rassembler_avr::Bit::B7;
+pub const ICNC1_MASK: u32 = 0x80;
+pub const CS10: ::rassembler_avr::Bit = ::rassembler_avr::Bit::B0;
+pub const CS10_MASK: u32 = 0x1;
+pub const CS11: ::rassembler_avr::Bit = ::rassembler_avr::Bit::B1;
+pub const CS11_MASK: u32 = 0x2;
+pub const CS12: ::rassembler_avr::Bit = ::rassembler_avr::Bit::B2;
+pub const CS12_MASK: u32 = 0x4;
+pub const CS1_MASK: u32 = 0x7;
+pub const TCNT1: ::rassembler_avr::DataAddress = ::rassembler_avr::DataAddress(0x84);
+pub const TCNT1L: ::rassembler_avr::DataAddress = ::rassembler_avr::DataAddress(0x84);
+pub const TCNT1H: ::rassembler_avr::DataAddress = ::rassembler_avr::DataAddress(0x85);
+pub const RESET_VECTOR: u32 = 0x0;
+pub const TIMER1_OVF_VECTOR: u32 = 0x34;